@@ -0,0 +1,14 @@
+//! Decodes a datagram as the server and the client do on receiving it.
+
+#![no_main]
+
+use libfuzzer_sys::fuzz_target;
+use poor_mans_vpn::Message;
+
+fuzz_target!(|data: &[u8]| {
+    if let Ok(msg) = Message::decode(data) {
+        // whatever is decoded is encoded back to the same message
+        let bytes = bincode::serialize(&msg).expect("serialize");
+        assert_eq!(Message::decode(&bytes).ok(), Some(msg));
+    }
+});
@@ -0,0 +1,45 @@
+//! Opens the signed seed of a `Hello` and derives a session key from it, as the server does.
+
+#![no_main]
+
+use libfuzzer_sys::fuzz_target;
+use poor_mans_vpn::crypto::{self, PubSeed, SessionKey, Signed, StaticKeyPair};
+use serde::ser::{Serialize, SerializeTuple, Serializer};
+use std::sync::OnceLock;
+
+/// Bytes serialized as they are (without a length), to be signed as if they were a serialized value.
+struct Raw<'a>(&'a [u8]);
+
+impl Serialize for Raw<'_> {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        let mut tuple = serializer.serialize_tuple(self.0.len())?;
+        for byte in self.0 {
+            tuple.serialize_element(byte)?;
+        }
+        tuple.end()
+    }
+}
+
+fn key_pair() -> &'static StaticKeyPair {
+    static KEY_PAIR: OnceLock<StaticKeyPair> = OnceLock::new();
+    KEY_PAIR.get_or_init(|| {
+        StaticKeyPair::from_pkcs8_bytes(&StaticKeyPair::generate_pkcs8()).expect("key pair")
+    })
+}
+
+fuzz_target!(|data: &[u8]| {
+    let public_key = key_pair().public_key();
+
+    // as sent by anyone, which hardly has a valid signature
+    if let Ok(signed) = bincode::deserialize::<Signed<PubSeed>>(data) {
+        let _ = signed.open(&public_key);
+    }
+
+    // as signed by a known peer, to reach the seed behind the signature
+    let signed = bincode::serialize(&key_pair().sign(&Raw(data))).expect("serialize");
+    let signed: Signed<PubSeed> = bincode::deserialize(&signed).expect("deserialize");
+    if let Ok(seed) = signed.open(&public_key) {
+        let (priv_seed, _) = crypto::generate_seed_pair();
+        let _ = SessionKey::server_derive(priv_seed, seed);
+    }
+});
@@ -0,0 +1,60 @@
+//! Unseals the content of a `Packet` as the server and the client do,
+//! and the settings of a `HelloReply` as the client does.
+//!
+//! The first 8 bytes are the additional authenticated data (the addresses of the packet).
+
+#![no_main]
+
+use libfuzzer_sys::fuzz_target;
+use poor_mans_vpn::crypto::{self, SessionKey};
+use poor_mans_vpn::PeerSettings;
+use serde::ser::{Serialize, SerializeTuple, Serializer};
+use std::sync::{Mutex, OnceLock};
+
+/// Bytes serialized as they are (without a length), to be sealed as if they were a serialized value.
+struct Raw<'a>(&'a [u8]);
+
+impl Serialize for Raw<'_> {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        let mut tuple = serializer.serialize_tuple(self.0.len())?;
+        for byte in self.0 {
+            tuple.serialize_element(byte)?;
+        }
+        tuple.end()
+    }
+}
+
+/// The session keys of a client and the server, established once since the derivation is slow.
+struct Session {
+    client: SessionKey,
+    server: SessionKey,
+}
+
+fn session() -> &'static Mutex<Session> {
+    static SESSION: OnceLock<Mutex<Session>> = OnceLock::new();
+    SESSION.get_or_init(|| {
+        let (client_priv, client_pub) = crypto::generate_seed_pair();
+        let (server_priv, server_pub) = crypto::generate_seed_pair();
+        Mutex::new(Session {
+            client: SessionKey::client_derive(client_priv, server_pub).expect("derive"),
+            server: SessionKey::server_derive(server_priv, client_pub).expect("derive"),
+        })
+    })
+}
+
+fuzz_target!(|data: &[u8]| {
+    let (aad, ciphertext) = data.split_at(data.len().min(8));
+    let mut session = session().lock().expect("poisoned");
+
+    // as sent by anyone, which hardly has a valid tag
+    let _ = session
+        .server
+        .unseal::<_, Vec<u8>>(aad, &mut ciphertext.to_vec());
+    let _ = crypto::unseal_with_raw_key::<_, Vec<u8>>(&[0; 32], aad, &mut ciphertext.to_vec());
+
+    // as sealed by the other end, to reach the plaintext behind the tag
+    let mut sealed = session.client.seal(aad, Raw(ciphertext)).expect("seal");
+    let _ = session.server.unseal::<_, Vec<u8>>(aad, &mut sealed);
+    let mut sealed = session.server.seal(aad, Raw(ciphertext)).expect("seal");
+    let _ = session.client.unseal::<_, PeerSettings>(aad, &mut sealed);
+});
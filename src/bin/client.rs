@@ -0,0 +1,42 @@
+use poor_mans_vpn::client::{Client, Config};
+use poor_mans_vpn::{control, error, exit_on_signal, metrics, Teardown};
+use std::sync::{Arc, Mutex};
+
+use error::Result;
+
+const CONFIG_FILE: &str = "client-config.toml";
+
+fn main() -> Result<()> {
+    env_logger::init();
+
+    let config = match Config::load(CONFIG_FILE) {
+        Ok(conf) => conf,
+        Err(err) => {
+            log::error!("failed to load {}: {}", CONFIG_FILE, err);
+            return Ok(());
+        }
+    };
+    log::debug!("config: {:#?}", config);
+    let control_socket = config.peer.control_socket.clone();
+    let metrics_address = config.peer.metrics_address;
+
+    let teardown = Arc::new(Mutex::new(Teardown::new()));
+    exit_on_signal(teardown.clone())?;
+
+    let client = Client::builder(config).teardown(teardown).start()?;
+    let client = Arc::new(client);
+
+    control::serve(&control_socket, {
+        let client = client.clone();
+        move |request| client.handle_request(request)
+    })?;
+
+    if let Some(addr) = metrics_address {
+        metrics::serve(addr, {
+            let client = client.clone();
+            move || client.metrics()
+        })?;
+    }
+
+    client.wait()
+}
@@ -0,0 +1,511 @@
+//! Decodes the outer UDP traffic of the VPN in a capture file (pcap or pcapng),
+//! and decrypts packets with the session keys in a key log if given.
+
+use etherparse::{InternetSlice, Ipv4Header, SlicedPacket, TransportSlice};
+use poor_mans_vpn::cert;
+use poor_mans_vpn::crypto::{self, Fingerprint};
+use poor_mans_vpn::keylog::{self, KeyLogEntry};
+use poor_mans_vpn::{Message, PeerSettings};
+use std::net::SocketAddr;
+
+const USAGE: &str = "\
+Usage: vpn-dissect [--port PORT] [--keylog FILE] CAPTURE
+
+Options:
+    -p, --port PORT                 the UDP port of the server (default: 31415)
+    -k, --keylog FILE               decrypt packets with the session keys in FILE
+
+CAPTURE is a pcap or pcapng file of the outer traffic (e.g. by tcpdump).";
+
+const DEFAULT_PORT: u16 = 31415;
+
+const LINKTYPE_NULL: u32 = 0;
+const LINKTYPE_ETHERNET: u32 = 1;
+const LINKTYPE_RAW: u32 = 101;
+const LINKTYPE_LINUX_SLL: u32 = 113;
+const LINKTYPE_IPV4: u32 = 228;
+const LINKTYPE_LINUX_SLL2: u32 = 276;
+
+/// A captured link-layer frame.
+struct Frame {
+    /// Seconds since the UNIX epoch.
+    time: f64,
+    linktype: u32,
+    data: Vec<u8>,
+}
+
+/// Reads a field of a capture file in its byte order.
+struct Reader<'a> {
+    data: &'a [u8],
+    big_endian: bool,
+}
+
+impl<'a> Reader<'a> {
+    fn bytes(&self, offset: usize, len: usize) -> Result<&'a [u8], String> {
+        self.data
+            .get(offset..offset + len)
+            .ok_or_else(|| "truncated capture file".to_owned())
+    }
+
+    fn u16(&self, offset: usize) -> Result<u16, String> {
+        let bytes = self.bytes(offset, 2)?.try_into().expect("len");
+        Ok(match self.big_endian {
+            true => u16::from_be_bytes(bytes),
+            false => u16::from_le_bytes(bytes),
+        })
+    }
+
+    fn u32(&self, offset: usize) -> Result<u32, String> {
+        let bytes = self.bytes(offset, 4)?.try_into().expect("len");
+        Ok(match self.big_endian {
+            true => u32::from_be_bytes(bytes),
+            false => u32::from_le_bytes(bytes),
+        })
+    }
+}
+
+fn read_capture(data: &[u8]) -> Result<Vec<Frame>, String> {
+    match data.get(..4) {
+        Some([0x0a, 0x0d, 0x0d, 0x0a]) => read_pcapng(data),
+        Some(_) => read_pcap(data),
+        None => Err("not a capture file".to_owned()),
+    }
+}
+
+fn read_pcap(data: &[u8]) -> Result<Vec<Frame>, String> {
+    let magic = Reader {
+        data,
+        big_endian: false,
+    }
+    .u32(0)?;
+    let (big_endian, nanos) = match magic {
+        0xa1b2_c3d4 => (false, false),
+        0xa1b2_3c4d => (false, true),
+        0xd4c3_b2a1 => (true, false),
+        0x4d3c_b2a1 => (true, true),
+        _ => return Err("not a capture file".to_owned()),
+    };
+    let reader = Reader { data, big_endian };
+    let linktype = reader.u32(20)?;
+
+    let mut frames = Vec::new();
+    let mut offset = 24;
+    while offset < data.len() {
+        let secs = reader.u32(offset)? as f64;
+        let frac = reader.u32(offset + 4)? as f64;
+        let caplen = reader.u32(offset + 8)? as usize;
+        let time = secs + frac / if nanos { 1e9 } else { 1e6 };
+        let data = reader.bytes(offset + 16, caplen)?.to_vec();
+        frames.push(Frame {
+            time,
+            linktype,
+            data,
+        });
+        offset += 16 + caplen;
+    }
+    Ok(frames)
+}
+
+fn read_pcapng(data: &[u8]) -> Result<Vec<Frame>, String> {
+    // (linktype, ticks per second) of each interface in the current section
+    let mut interfaces: Vec<(u32, f64)> = Vec::new();
+    let mut big_endian = false;
+    let mut frames = Vec::new();
+    let mut offset = 0;
+    while offset < data.len() {
+        let mut reader = Reader { data, big_endian };
+        let block_type = reader.u32(offset)?;
+        if block_type == 0x0a0d_0d0a {
+            // a section header block, which tells the byte order
+            big_endian = reader.bytes(offset + 8, 4)? == [0x1a, 0x2b, 0x3c, 0x4d];
+            reader.big_endian = big_endian;
+            interfaces.clear();
+        }
+        let block_len = reader.u32(offset + 4)? as usize;
+        if block_len < 12 {
+            return Err("broken pcapng block".to_owned());
+        }
+        if data.len() - offset < block_len {
+            return Err("truncated capture file".to_owned());
+        }
+        let body = offset + 8;
+
+        match block_type {
+            // interface description block
+            1 => {
+                let linktype = reader.u16(body)? as u32;
+                let mut resolution = 1e6;
+                let mut opt = body + 8;
+                while opt + 4 <= offset + block_len - 4 {
+                    let (code, len) = (reader.u16(opt)?, reader.u16(opt + 2)? as usize);
+                    if code == 0 {
+                        break;
+                    }
+                    if code == 9 && len == 1 {
+                        // if_tsresol: a power of 10, or of 2 if the MSB is set
+                        let value = reader.bytes(opt + 4, 1)?[0];
+                        resolution = match value & 0x80 {
+                            0 => 10f64.powi(value as i32),
+                            _ => 2f64.powi((value & 0x7f) as i32),
+                        };
+                    }
+                    opt += 4 + len.div_ceil(4) * 4;
+                }
+                interfaces.push((linktype, resolution));
+            }
+            // enhanced packet block
+            6 => {
+                let interface = reader.u32(body)? as usize;
+                let &(linktype, resolution) = interfaces
+                    .get(interface)
+                    .ok_or_else(|| "unknown interface".to_owned())?;
+                let ticks = ((reader.u32(body + 4)? as u64) << 32) | reader.u32(body + 8)? as u64;
+                let caplen = reader.u32(body + 12)? as usize;
+                let data = reader.bytes(body + 20, caplen)?.to_vec();
+                frames.push(Frame {
+                    time: ticks as f64 / resolution,
+                    linktype,
+                    data,
+                });
+            }
+            // simple packet block
+            3 => {
+                let &(linktype, _) = interfaces
+                    .first()
+                    .ok_or_else(|| "unknown interface".to_owned())?;
+                if block_len < 16 {
+                    return Err("broken pcapng block".to_owned());
+                }
+                let caplen = (block_len - 16).min(reader.u32(body)? as usize);
+                let data = reader.bytes(body + 4, caplen)?.to_vec();
+                frames.push(Frame {
+                    time: 0.0,
+                    linktype,
+                    data,
+                });
+            }
+            _ => {}
+        }
+        offset += block_len;
+    }
+    Ok(frames)
+}
+
+/// Extracts the endpoints and the payload of a UDP datagram in a frame.
+fn udp_datagram(frame: &Frame) -> Option<(SocketAddr, SocketAddr, &[u8])> {
+    let data = &frame.data[..];
+    let sliced = match frame.linktype {
+        LINKTYPE_ETHERNET => SlicedPacket::from_ethernet(data),
+        LINKTYPE_RAW | LINKTYPE_IPV4 => SlicedPacket::from_ip(data),
+        LINKTYPE_NULL => SlicedPacket::from_ip(data.get(4..)?),
+        LINKTYPE_LINUX_SLL => SlicedPacket::from_ip(data.get(16..)?),
+        LINKTYPE_LINUX_SLL2 => SlicedPacket::from_ip(data.get(20..)?),
+        _ => return None,
+    }
+    .ok()?;
+
+    match (sliced.ip, sliced.transport) {
+        (Some(InternetSlice::Ipv4(ip, _)), Some(TransportSlice::Udp(udp))) => {
+            let src = SocketAddr::from((ip.source_addr(), udp.source_port()));
+            let dst = SocketAddr::from((ip.destination_addr(), udp.destination_port()));
+            Some((src, dst, sliced.payload))
+        }
+        _ => None,
+    }
+}
+
+/// Tries the keys of all sessions. Returns the plaintext and which key opened it.
+fn decrypt<T: serde::de::DeserializeOwned>(
+    keys: &[KeyLogEntry],
+    aad: &[u8],
+    ciphertext: &[u8],
+) -> Option<(T, String)> {
+    for entry in keys {
+        let directions = [
+            (&entry.client_key, "client->server"),
+            (&entry.server_key, "server->client"),
+        ];
+        for (key, direction) in directions {
+            let mut buf = ciphertext.to_vec();
+            if let Ok(plaintext) = crypto::unseal_with_raw_key(key, aad, &mut buf) {
+                let label = format!(
+                    "session {}#{} {}",
+                    entry.fingerprint, entry.index, direction
+                );
+                return Some((plaintext, label));
+            }
+        }
+    }
+    None
+}
+
+fn print_message(msg: &Message, keys: &[KeyLogEntry]) {
+    match msg {
+        Message::Hello {
+            key_hint,
+            seed,
+            certificate,
+        } => {
+            println!("Hello");
+            println!("    key_hint: {}", key_hint);
+            println!("    seed: {} bytes", seed.data().len());
+            println!("    signature: {}", crypto::to_hex(seed.signature()));
+            match certificate
+                .as_ref()
+                .map(|certificate| certificate.content())
+            {
+                None => {}
+                Some(Ok(content)) => println!(
+                    "    certificate: {} address {:?} expires {}",
+                    Fingerprint::of(&content.public_key),
+                    content.address,
+                    cert::format_date(content.expires_at)
+                ),
+                Some(Err(err)) => println!("    certificate: {}", err),
+            }
+        }
+        Message::HelloReply { seed, settings } => {
+            println!("HelloReply");
+            println!("    seed: {} bytes", seed.data().len());
+            println!("    signature: {}", crypto::to_hex(seed.signature()));
+            println!("    settings: {} bytes (sealed)", settings.len());
+            let opened = decrypt::<PeerSettings>(keys, PeerSettings::AAD, settings);
+            if let Some((settings, label)) = opened {
+                println!("    settings ({}): {:?}", label, settings);
+            }
+        }
+        Message::HeartBeat => println!("HeartBeat"),
+        Message::Packet(sealed) => {
+            println!("Packet");
+            println!("    source: {}", sealed.source);
+            println!("    destination: {}", sealed.destination);
+            println!("    content: {} bytes (sealed)", sealed.content.len());
+            let aad = sealed.addresses_as_bytes();
+            let opened = decrypt::<Vec<u8>>(keys, &aad, &sealed.content);
+            if let Some((packet, label)) = opened {
+                match Ipv4Header::from_slice(&packet) {
+                    Ok((hdr, _)) => println!(
+                        "    inner ({}): {} -> {} protocol {} length {}",
+                        label,
+                        std::net::Ipv4Addr::from(hdr.source),
+                        std::net::Ipv4Addr::from(hdr.destination),
+                        hdr.protocol,
+                        packet.len(),
+                    ),
+                    Err(_) => println!("    inner ({}): {} bytes", label, packet.len()),
+                }
+            }
+        }
+    }
+}
+
+fn run(port: u16, keylog: Option<String>, capture: String) -> Result<(), String> {
+    let keys = match keylog {
+        Some(path) => keylog::read(&path).map_err(|err| format!("{}: {}", path, err))?,
+        None => Vec::new(),
+    };
+    let data = std::fs::read(&capture).map_err(|err| format!("{}: {}", capture, err))?;
+    let frames = read_capture(&data).map_err(|err| format!("{}: {}", capture, err))?;
+
+    let start = frames.first().map(|frame| frame.time).unwrap_or(0.0);
+    for (i, frame) in frames.iter().enumerate() {
+        let (src, dst, payload) = match udp_datagram(frame) {
+            Some(datagram) => datagram,
+            None => continue,
+        };
+        if src.port() != port && dst.port() != port {
+            continue;
+        }
+
+        print!("#{} {:.6} {} -> {} ", i + 1, frame.time - start, src, dst);
+        match Message::decode(payload) {
+            Ok(msg) => print_message(&msg, &keys),
+            Err(_) => println!("undecodable ({} bytes)", payload.len()),
+        }
+    }
+    Ok(())
+}
+
+fn main() {
+    let mut port = DEFAULT_PORT;
+    let mut keylog = None;
+    let mut capture = None;
+
+    let mut args = std::env::args().skip(1);
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "-p" | "--port" => match args.next().and_then(|p| p.parse().ok()) {
+                Some(p) => port = p,
+                None => {
+                    eprintln!("{}", USAGE);
+                    std::process::exit(2);
+                }
+            },
+            "-k" | "--keylog" => keylog = args.next(),
+            _ if capture.is_none() && !arg.starts_with('-') => capture = Some(arg),
+            _ => {
+                eprintln!("{}", USAGE);
+                std::process::exit(2);
+            }
+        }
+    }
+    let capture = match capture {
+        Some(capture) => capture,
+        None => {
+            eprintln!("{}", USAGE);
+            std::process::exit(2);
+        }
+    };
+
+    if let Err(msg) = run(port, keylog, capture) {
+        eprintln!("error: {}", msg);
+        std::process::exit(1);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use etherparse::PacketBuilder;
+    use poor_mans_vpn::capture::{Capture, Direction};
+
+    fn pcap_header(linktype: u32) -> Vec<u8> {
+        let mut data = Vec::new();
+        data.extend_from_slice(&0xa1b2_c3d4u32.to_le_bytes());
+        data.extend_from_slice(&2u16.to_le_bytes()); // major version
+        data.extend_from_slice(&4u16.to_le_bytes()); // minor version
+        data.extend_from_slice(&[0; 8]); // time zone and accuracy
+        data.extend_from_slice(&65535u32.to_le_bytes()); // snap length
+        data.extend_from_slice(&linktype.to_le_bytes());
+        data
+    }
+
+    fn pcap_record(data: &mut Vec<u8>, frame: &[u8]) {
+        data.extend_from_slice(&1_650_000_000u32.to_le_bytes());
+        data.extend_from_slice(&0u32.to_le_bytes());
+        data.extend_from_slice(&(frame.len() as u32).to_le_bytes());
+        data.extend_from_slice(&(frame.len() as u32).to_le_bytes());
+        data.extend_from_slice(frame);
+    }
+
+    fn udp_packet(payload: &[u8]) -> Vec<u8> {
+        let mut packet = Vec::new();
+        PacketBuilder::ipv4([10, 20, 30, 2], [10, 20, 30, 1], 64)
+            .udp(40000, DEFAULT_PORT)
+            .write(&mut packet, payload)
+            .unwrap();
+        packet
+    }
+
+    /// A section header block followed by an interface description block of raw IPv4.
+    fn pcapng_header() -> Vec<u8> {
+        let mut data = Vec::new();
+        for word in [
+            0x0a0d_0d0a,
+            28,
+            0x1a2b_3c4d,
+            1,
+            0xffff_ffff,
+            0xffff_ffff,
+            28,
+        ] {
+            data.extend_from_slice(&u32::to_le_bytes(word));
+        }
+        for word in [1, 20, LINKTYPE_IPV4, 0, 20] {
+            data.extend_from_slice(&u32::to_le_bytes(word));
+        }
+        data
+    }
+
+    #[test]
+    fn pcap_is_read() {
+        let mut data = pcap_header(LINKTYPE_RAW);
+        pcap_record(&mut data, &udp_packet(b"hello"));
+        let frames = read_capture(&data).unwrap();
+        assert_eq!(frames.len(), 1);
+        assert_eq!(frames[0].time, 1_650_000_000.0);
+        let (src, dst, payload) = udp_datagram(&frames[0]).unwrap();
+        assert_eq!(src, "10.20.30.2:40000".parse().unwrap());
+        assert_eq!(dst.port(), DEFAULT_PORT);
+        assert_eq!(payload, b"hello");
+    }
+
+    #[test]
+    fn truncated_pcap_is_rejected() {
+        assert!(read_pcap(&[]).is_err());
+        assert!(read_pcap(&[0xd4, 0xc3, 0xb2]).is_err());
+        let header = pcap_header(LINKTYPE_RAW);
+        assert!(read_pcap(&header[..20]).is_err());
+
+        let mut data = header.clone();
+        pcap_record(&mut data, &udp_packet(b"hello"));
+        for len in header.len() + 1..data.len() {
+            assert!(read_pcap(&data[..len]).is_err(), "{} bytes", len);
+        }
+    }
+
+    #[test]
+    fn unknown_linktype_is_skipped() {
+        let mut data = pcap_header(0xffff);
+        pcap_record(&mut data, &udp_packet(b"hello"));
+        let frames = read_capture(&data).unwrap();
+        assert_eq!(frames.len(), 1);
+        assert!(udp_datagram(&frames[0]).is_none());
+    }
+
+    #[test]
+    fn truncated_pcapng_is_rejected() {
+        assert!(read_pcapng(&[]).is_ok());
+        let header = pcapng_header();
+        // the section header block alone (28 bytes) is a valid capture without interfaces
+        for len in (1..header.len()).filter(|&len| len != 28) {
+            assert!(read_pcapng(&header[..len]).is_err(), "{} bytes", len);
+        }
+    }
+
+    #[test]
+    fn pcapng_block_shorter_than_its_header_is_rejected() {
+        for block_type in [1, 3, 6, 0x0bad] {
+            for block_len in 0..16u32 {
+                let mut data = pcapng_header();
+                data.extend_from_slice(&u32::to_le_bytes(block_type));
+                data.extend_from_slice(&block_len.to_le_bytes());
+                data.extend_from_slice(&[0; 16]);
+                // must not panic; a block of 12 bytes without content may be accepted
+                let _ = read_pcapng(&data);
+                if block_len < 12 {
+                    assert!(read_pcapng(&data).is_err(), "{} bytes", block_len);
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn capture_is_read_back() {
+        let path = std::env::temp_dir().join(format!(
+            "poor-mans-vpn-dissect-{}.pcapng",
+            std::process::id()
+        ));
+        let packets = [udp_packet(b"first"), udp_packet(b"second packet")];
+        let mut capture = Capture::create(&path, "vpn0").unwrap();
+        capture
+            .record(Direction::TunToPeer, "10.20.30.2", &packets[0])
+            .unwrap();
+        capture
+            .record(Direction::PeerToTun, "10.20.30.2", &packets[1])
+            .unwrap();
+        drop(capture);
+        let data = std::fs::read(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+
+        let frames = read_capture(&data).unwrap();
+        assert_eq!(frames.len(), 2);
+        for (frame, packet) in frames.iter().zip(packets.iter()) {
+            assert_eq!(frame.linktype, LINKTYPE_IPV4);
+            assert_eq!(&frame.data, packet);
+            assert!(frame.time > 1_600_000_000.0);
+        }
+        let (_, _, payload) = udp_datagram(&frames[1]).unwrap();
+        assert_eq!(payload, b"second packet");
+    }
+}
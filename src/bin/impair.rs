@@ -0,0 +1,83 @@
+//! Relays the UDP traffic of the VPN through a simulated poor network
+//! (e.g. between a client and a server in containers).
+
+use poor_mans_vpn::impair::{Direction, Impairment, Proxy};
+use std::net::SocketAddr;
+use std::time::Duration;
+
+const USAGE: &str = "\
+Usage: vpn-impair [OPTIONS] LISTEN SERVER
+
+Options:
+    --loss P                        lose datagrams with the probability P (0.0 to 1.0)
+    --latency MS                    delay datagrams by MS milliseconds
+    --jitter MS                     delay datagrams by up to MS milliseconds more at random
+    --reorder P                     hold datagrams back with the probability P, to be overtaken
+    --reorder-delay MS              hold them back by MS milliseconds (default: 20)
+    --duplicate P                   deliver datagrams twice with the probability P
+    --bandwidth BYTES               limit the bandwidth to BYTES per second
+    --seed N                        seed the random decisions (default: 0)
+
+The conditions apply to both directions. Clients send to LISTEN (e.g. 0.0.0.0:31416)
+in place of SERVER (e.g. 10.0.0.1:31415).";
+
+/// How often the counters are logged.
+const STATS_INTERVAL: Duration = Duration::from_secs(10);
+
+fn usage() -> ! {
+    eprintln!("{}", USAGE);
+    std::process::exit(2);
+}
+
+fn main() {
+    env_logger::init();
+
+    let mut impairment = Impairment::default();
+    let mut seed = 0;
+    let mut addrs: Vec<SocketAddr> = Vec::new();
+
+    let mut args = std::env::args().skip(1);
+    while let Some(arg) = args.next() {
+        let mut value = || args.next().unwrap_or_else(|| usage());
+        let millis = |value: String| value.parse().map(Duration::from_millis).ok();
+        let parsed = match arg.as_str() {
+            "--loss" => value().parse().map(|p| impairment.loss = p).ok(),
+            "--latency" => millis(value()).map(|d| impairment.latency = d),
+            "--jitter" => millis(value()).map(|d| impairment.jitter = d),
+            "--reorder" => value().parse().map(|p| impairment.reorder = p).ok(),
+            "--reorder-delay" => millis(value()).map(|d| impairment.reorder_delay = d),
+            "--duplicate" => value().parse().map(|p| impairment.duplicate = p).ok(),
+            "--bandwidth" => value().parse().map(|b| impairment.bandwidth = Some(b)).ok(),
+            "--seed" => value().parse().map(|n| seed = n).ok(),
+            _ if !arg.starts_with('-') => arg.parse().map(|addr| addrs.push(addr)).ok(),
+            _ => None,
+        };
+        if parsed.is_none() {
+            usage();
+        }
+    }
+    let (listen, server) = match addrs[..] {
+        [listen, server] => (listen, server),
+        _ => usage(),
+    };
+
+    let proxy = Proxy::builder(server)
+        .bind(listen)
+        .impairment(Direction::Upstream, impairment.clone())
+        .impairment(Direction::Downstream, impairment)
+        .seed(seed)
+        .start();
+    let proxy = match proxy {
+        Ok(proxy) => proxy,
+        Err(err) => {
+            eprintln!("error: {}", err);
+            std::process::exit(1);
+        }
+    };
+
+    loop {
+        std::thread::sleep(STATS_INTERVAL);
+        log::info!("upstream: {:?}", proxy.stats(Direction::Upstream));
+        log::info!("downstream: {:?}", proxy.stats(Direction::Downstream));
+    }
+}
@@ -0,0 +1,195 @@
+//! Generates and inspects the static keys of the server and the peers, without OpenSSL.
+
+use poor_mans_vpn::cert::{self, Certificate, CertificateContent};
+use poor_mans_vpn::crypto::{self, Fingerprint, PublicKeySource, StaticKeyPair, PUBLIC_KEY_LEN};
+use std::io::Write;
+use std::net::Ipv4Addr;
+use std::path::Path;
+use std::time::{Duration, SystemTime};
+
+const USAGE: &str = "\
+Usage: vpn-keygen generate PRIVATE_KEY [PUBLIC_KEY]
+       vpn-keygen public PRIVATE_KEY [PUBLIC_KEY]
+       vpn-keygen show KEY
+       vpn-keygen cert issue CA_PRIVATE_KEY PUBLIC_KEY DAYS [ADDRESS]
+       vpn-keygen cert show CERTIFICATE [CA_PUBLIC_KEY]
+
+Commands:
+    generate    generate a private key (PKCS#8 Ed25519, readable only by the owner)
+                and write its public key to PUBLIC_KEY if given
+    public      write the public key of a private key to PUBLIC_KEY, or print it in base64
+    show        print a public key (or the public key of a private key) and its fingerprint
+    cert issue  print a certificate of PUBLIC_KEY signed by the CA, valid for DAYS days,
+                assigning ADDRESS to the peer (or an address from the pool if omitted)
+    cert show   print the content of a certificate, and verify it if CA_PUBLIC_KEY is given
+
+Public keys are given in base64 or as files. A private key is never overwritten.";
+
+/// Writes a new file readable and writable only by the owner. Fails if it exists.
+fn write_private(path: &str, content: &[u8]) -> Result<(), String> {
+    use std::os::unix::fs::OpenOptionsExt;
+    let mut file = std::fs::OpenOptions::new()
+        .write(true)
+        .create_new(true)
+        .mode(0o600)
+        .open(path)
+        .map_err(|err| format!("{}: {}", path, err))?;
+    file.write_all(content)
+        .map_err(|err| format!("{}: {}", path, err))
+}
+
+fn write_public(path: &str, public_key: &[u8]) -> Result<(), String> {
+    std::fs::write(path, public_key).map_err(|err| format!("{}: {}", path, err))
+}
+
+fn read_private(path: &str) -> Result<StaticKeyPair, String> {
+    StaticKeyPair::from_pkcs8(path).map_err(|err| format!("{}: {}", path, err))
+}
+
+/// Reads a public key in base64 or from a file, or derives it from a private key.
+fn read_public(path: &str) -> Result<Vec<u8>, String> {
+    if let PublicKeySource::Inline(key) = PublicKeySource::from(path.to_owned()) {
+        return Ok(key);
+    }
+    let content = match std::fs::read(Path::new(path)) {
+        Ok(content) => content,
+        // tells whether it looks like a mistyped inline key
+        Err(err) if err.kind() == std::io::ErrorKind::NotFound => {
+            let source = PublicKeySource::File(path.into());
+            return source.load().map_err(|err| format!("{}: {}", path, err));
+        }
+        Err(err) => return Err(format!("{}: {}", path, err)),
+    };
+    if let Ok(key_pair) = StaticKeyPair::from_pkcs8_bytes(&content) {
+        return Ok(key_pair.public_key());
+    }
+    if content.len() == PUBLIC_KEY_LEN {
+        return Ok(content);
+    }
+    Err(format!(
+        "{}: neither a PKCS#8 Ed25519 private key nor a {}-byte public key",
+        path, PUBLIC_KEY_LEN
+    ))
+}
+
+fn generate(private_key: &str, public_key: Option<&str>) -> Result<(), String> {
+    let pkcs8 = StaticKeyPair::generate_pkcs8();
+    let key_pair = StaticKeyPair::from_pkcs8_bytes(&pkcs8).map_err(|err| err.to_string())?;
+    write_private(private_key, &pkcs8)?;
+    if let Some(path) = public_key {
+        write_public(path, &key_pair.public_key())?;
+    }
+    println!("fingerprint: {}", Fingerprint::of(&key_pair.public_key()));
+    Ok(())
+}
+
+fn public(private_key: &str, public_key: Option<&str>) -> Result<(), String> {
+    let key_pair = read_private(private_key)?;
+    match public_key {
+        Some(path) => write_public(path, &key_pair.public_key()),
+        None => {
+            println!("{}", crypto::to_base64(&key_pair.public_key()));
+            Ok(())
+        }
+    }
+}
+
+fn show(key: &str) -> Result<(), String> {
+    let public_key = read_public(key)?;
+    let fingerprint = Fingerprint::of(&public_key);
+    println!("public key: {}", crypto::to_base64(&public_key));
+    println!("public key (hex): {}", crypto::to_hex(&public_key));
+    println!("fingerprint: {}", fingerprint);
+    println!(
+        "fingerprint (base64): {}",
+        crypto::to_base64(fingerprint.as_bytes())
+    );
+    Ok(())
+}
+
+fn issue_certificate(
+    ca_private_key: &str,
+    public_key: &str,
+    days: &str,
+    address: Option<&str>,
+) -> Result<(), String> {
+    let ca_key_pair = read_private(ca_private_key)?;
+    let public_key = read_public(public_key)?;
+    let expires_at = days
+        .parse::<u64>()
+        .ok()
+        .and_then(|days| days.checked_mul(24 * 60 * 60))
+        .and_then(|secs| SystemTime::now().checked_add(Duration::from_secs(secs)))
+        .ok_or_else(|| format!("{}: not a number of days", days))?;
+    let address = match address {
+        Some(address) => {
+            let address: Ipv4Addr = address
+                .parse()
+                .map_err(|_| format!("{}: not an IPv4 address", address))?;
+            Some(address)
+        }
+        None => None,
+    };
+    let content = CertificateContent {
+        public_key: public_key.try_into().expect("public key len"),
+        address,
+        expires_at: poor_mans_vpn::unix_time(expires_at),
+    };
+    println!("{}", Certificate::issue(&ca_key_pair, &content));
+    Ok(())
+}
+
+fn show_certificate(certificate: &str, ca_public_key: Option<&str>) -> Result<(), String> {
+    let certificate =
+        Certificate::load(certificate).map_err(|err| format!("{}: {}", certificate, err))?;
+    let content = match ca_public_key {
+        Some(ca_public_key) => {
+            let ca_public_key = read_public(ca_public_key)?;
+            certificate
+                .verify(&ca_public_key, SystemTime::now())
+                .map_err(|err| err.to_string())?
+        }
+        None => certificate.content().map_err(|err| err.to_string())?,
+    };
+    println!("public key: {}", crypto::to_base64(&content.public_key));
+    println!("fingerprint: {}", Fingerprint::of(&content.public_key));
+    match content.address {
+        Some(address) => println!("address: {}", address),
+        None => println!("address: (leased from the pool)"),
+    }
+    println!("expires: {}", cert::format_date(content.expires_at));
+    if ca_public_key.is_some() {
+        println!("verified: signed by the CA and not expired");
+    }
+    Ok(())
+}
+
+fn main() {
+    let args: Vec<String> = std::env::args().skip(1).collect();
+    let args: Vec<&str> = args.iter().map(String::as_str).collect();
+    let result = match args[..] {
+        ["generate", private_key] => generate(private_key, None),
+        ["generate", private_key, public_key] => generate(private_key, Some(public_key)),
+        ["public", private_key] => public(private_key, None),
+        ["public", private_key, public_key] => public(private_key, Some(public_key)),
+        ["show", key] => show(key),
+        ["cert", "issue", ca_private_key, public_key, days] => {
+            issue_certificate(ca_private_key, public_key, days, None)
+        }
+        ["cert", "issue", ca_private_key, public_key, days, address] => {
+            issue_certificate(ca_private_key, public_key, days, Some(address))
+        }
+        ["cert", "show", certificate] => show_certificate(certificate, None),
+        ["cert", "show", certificate, ca_public_key] => {
+            show_certificate(certificate, Some(ca_public_key))
+        }
+        _ => {
+            eprintln!("{}", USAGE);
+            std::process::exit(2);
+        }
+    };
+    if let Err(msg) = result {
+        eprintln!("error: {}", msg);
+        std::process::exit(1);
+    }
+}
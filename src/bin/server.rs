@@ -0,0 +1,59 @@
+use poor_mans_vpn::server::{Config, Server};
+use poor_mans_vpn::{control, error, exit_on_signal, metrics, Teardown};
+use std::sync::{Arc, Mutex};
+
+use error::Result;
+
+const CONFIG_FILE: &str = "server-config.toml";
+
+fn main() -> Result<()> {
+    env_logger::init();
+
+    let config = match Config::load(CONFIG_FILE) {
+        Ok(conf) => conf,
+        Err(err) => {
+            log::error!("failed to load {}: {}", CONFIG_FILE, err);
+            return Ok(());
+        }
+    };
+    log::debug!("config: {:#?}", config);
+    let control_socket = config.server.control_socket.clone();
+    let metrics_address = config.server.metrics_address;
+
+    let teardown = Arc::new(Mutex::new(Teardown::new()));
+    exit_on_signal(teardown.clone())?;
+
+    let server = Server::builder(config)
+        .config_file(CONFIG_FILE)
+        .teardown(teardown)
+        .start()?;
+    let server = Arc::new(server);
+
+    control::serve(&control_socket, {
+        let server = server.clone();
+        move |request| server.handle_request(request)
+    })?;
+
+    if let Some(addr) = metrics_address {
+        metrics::serve(addr, {
+            let server = server.clone();
+            move || server.metrics()
+        })?;
+    }
+
+    // Reload the configuration on SIGHUP
+    let mut signals = signal_hook::iterator::Signals::new([signal_hook::consts::SIGHUP])?;
+    std::thread::spawn({
+        let server = server.clone();
+        move || {
+            for _ in signals.forever() {
+                log::info!("SIGHUP received, reloading {}", CONFIG_FILE);
+                if let Err(err) = server.reload() {
+                    log::error!("reload: {}", err);
+                }
+            }
+        }
+    });
+
+    server.wait()
+}
@@ -0,0 +1,132 @@
+use poor_mans_vpn::control::{self, Request, Response};
+use poor_mans_vpn::crypto::PublicKeySource;
+use poor_mans_vpn::route::Ipv4Cidr;
+use std::path::PathBuf;
+
+const USAGE: &str = "\
+Usage: vpnctl [--socket PATH | --client] COMMAND
+
+Options:
+    -s, --socket PATH               connect to the control socket at PATH
+    -c, --client                    connect to the control socket of the client
+
+Server commands:
+    peers list                      list the registered peers
+    peer show <PEER>                show the details of a peer
+    peer kick <PEER>                drop the session with a peer
+    peer add <PUBKEY> [--address ADDR] [--allowed-ip PREFIX]...
+                                    register a peer until the server exits
+    peer remove <PEER>              unregister a peer
+    stats                           show the statistics
+    reload                          reload the configuration file
+
+Server and client commands:
+    capture start <FILE>            capture decrypted packets to a pcapng file
+    capture stop                    stop capturing
+
+Client commands:
+    status                          show the status of the connection
+    reconnect                       establish a new session
+    shutdown                        disconnect and make the client exit
+
+<PEER> is either an address or a fingerprint of the public key of a peer.
+<PUBKEY> is either a public key in base64 or a path to a public key file.
+Results are printed in JSON.";
+
+fn parse_request(args: &[String]) -> Result<Request, String> {
+    let args: Vec<&str> = args.iter().map(|a| a.as_str()).collect();
+    let peer_id = |s: &str| s.parse().map_err(|err| format!("{}", err));
+    let request = match args[..] {
+        ["peers", "list"] => Request::ListPeers,
+        ["peer", "show", peer] => Request::ShowPeer {
+            peer: peer_id(peer)?,
+        },
+        ["peer", "kick", peer] => Request::KickPeer {
+            peer: peer_id(peer)?,
+        },
+        ["peer", "remove", peer] => Request::RemovePeer {
+            peer: peer_id(peer)?,
+        },
+        ["peer", "add", pubkey, ref options @ ..] => {
+            let pubkey = PublicKeySource::from(pubkey.to_owned())
+                .load()
+                .map_err(|err| format!("{}: {}", pubkey, err))?;
+            let mut address = None;
+            let mut allowed_ips = Vec::new();
+            let mut options = options.iter();
+            while let Some(&option) = options.next() {
+                let value = options
+                    .next()
+                    .ok_or(format!("{} requires a value", option))?;
+                match option {
+                    "--address" => address = Some(value.parse().map_err(|_| "invalid address")?),
+                    "--allowed-ip" => {
+                        let prefix: Ipv4Cidr = value.parse().map_err(|err| format!("{}", err))?;
+                        allowed_ips.push(prefix);
+                    }
+                    _ => return Err(format!("unknown option: {}", option)),
+                }
+            }
+            Request::AddPeer {
+                public_key: poor_mans_vpn::crypto::to_hex(&pubkey),
+                address,
+                allowed_ips,
+            }
+        }
+        ["stats"] => Request::Stats,
+        ["reload"] => Request::Reload,
+        ["status"] => Request::Status,
+        ["reconnect"] => Request::Reconnect,
+        ["shutdown"] => Request::Shutdown,
+        ["capture", "start", path] => {
+            // the path is opened by the daemon, whose working directory differs
+            let cwd = std::env::current_dir().map_err(|err| format!("{}", err))?;
+            Request::CaptureStart {
+                path: cwd.join(path),
+            }
+        }
+        ["capture", "stop"] => Request::CaptureStop,
+        _ => return Err(USAGE.to_owned()),
+    };
+    Ok(request)
+}
+
+fn main() {
+    let mut args: Vec<String> = std::env::args().skip(1).collect();
+
+    let mut socket = PathBuf::from(control::SERVER_SOCKET);
+    match args.first().map(|a| a.as_str()) {
+        Some("-s" | "--socket") if args.len() >= 2 => {
+            socket = PathBuf::from(args.remove(1));
+            args.remove(0);
+        }
+        Some("-c" | "--client") => {
+            socket = PathBuf::from(control::CLIENT_SOCKET);
+            args.remove(0);
+        }
+        _ => {}
+    }
+
+    let request = match parse_request(&args) {
+        Ok(request) => request,
+        Err(msg) => {
+            eprintln!("{}", msg);
+            std::process::exit(2);
+        }
+    };
+
+    match control::request(&socket, &request) {
+        Ok(Response::Ok { result }) => {
+            let json = serde_json::to_string_pretty(&result).expect("serialize");
+            println!("{}", json);
+        }
+        Ok(Response::Error { message }) => {
+            eprintln!("error: {}", message);
+            std::process::exit(1);
+        }
+        Err(err) => {
+            eprintln!("error: {:?}: {}", socket, err);
+            std::process::exit(1);
+        }
+    }
+}
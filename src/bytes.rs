@@ -0,0 +1,124 @@
+//! Serialization of byte fields in messages, so that decoding untrusted bytes stays cheap.
+//!
+//! bincode decodes a `Vec<u8>` element by element (pre-allocating at most a capped size),
+//! while it reads a byte buffer at once after checking its length against the limit.
+//! It is the limit set by `crate::decode` that makes a huge length prefix fail early;
+//! a byte buffer saves the work per element.
+//! Both are encoded in the same way: a u64 length followed by the bytes.
+
+/// (De)serializes a `Vec<u8>` as a byte buffer.
+pub mod vec {
+    use serde::de::{Deserializer, Visitor};
+    use serde::ser::Serializer;
+
+    pub fn serialize<S: Serializer>(bytes: &[u8], serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_bytes(bytes)
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Vec<u8>, D::Error> {
+        struct BytesVisitor;
+
+        impl<'de> Visitor<'de> for BytesVisitor {
+            type Value = Vec<u8>;
+
+            fn expecting(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+                write!(f, "bytes")
+            }
+
+            fn visit_bytes<E>(self, bytes: &[u8]) -> Result<Vec<u8>, E> {
+                Ok(bytes.to_vec())
+            }
+
+            fn visit_byte_buf<E>(self, bytes: Vec<u8>) -> Result<Vec<u8>, E> {
+                Ok(bytes)
+            }
+        }
+
+        deserializer.deserialize_byte_buf(BytesVisitor)
+    }
+}
+
+/// (De)serializes a byte array as a tuple, i.e. as the bytes without a length.
+/// serde implements arrays only up to 32 elements.
+pub mod array {
+    use serde::de::{Deserializer, Error, SeqAccess, Visitor};
+    use serde::ser::{SerializeTuple, Serializer};
+
+    pub fn serialize<S: Serializer, const N: usize>(
+        bytes: &[u8; N],
+        serializer: S,
+    ) -> Result<S::Ok, S::Error> {
+        let mut tuple = serializer.serialize_tuple(N)?;
+        for byte in bytes {
+            tuple.serialize_element(byte)?;
+        }
+        tuple.end()
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>, const N: usize>(
+        deserializer: D,
+    ) -> Result<[u8; N], D::Error> {
+        struct ArrayVisitor<const N: usize>;
+
+        impl<'de, const N: usize> Visitor<'de> for ArrayVisitor<N> {
+            type Value = [u8; N];
+
+            fn expecting(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+                write!(f, "{} bytes", N)
+            }
+
+            fn visit_seq<A: SeqAccess<'de>>(self, mut seq: A) -> Result<[u8; N], A::Error> {
+                let mut bytes = [0; N];
+                for (i, byte) in bytes.iter_mut().enumerate() {
+                    *byte = seq
+                        .next_element()?
+                        .ok_or_else(|| A::Error::invalid_length(i, &self))?;
+                }
+                Ok(bytes)
+            }
+        }
+
+        deserializer.deserialize_tuple(N, ArrayVisitor::<N>)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::crypto::{self, StaticKeyPair};
+    use crate::error::Error;
+    use crate::{Message, SealedPacket};
+    use std::net::Ipv4Addr;
+
+    /// Replaces the length prefix of the trailing (empty) byte field of an encoded message.
+    /// Allocating for the announced length would abort the test.
+    fn announce_huge_length(message: &Message) -> Vec<u8> {
+        let mut bytes = bincode::serialize(message).unwrap();
+        assert!(Message::decode(&bytes).is_ok());
+        let len = bytes.len();
+        bytes[len - 8..].copy_from_slice(&(1u64 << 60).to_le_bytes());
+        bytes
+    }
+
+    #[test]
+    fn huge_packet_is_rejected() {
+        let packet = Message::Packet(SealedPacket {
+            source: Ipv4Addr::new(10, 0, 0, 2),
+            destination: Ipv4Addr::new(10, 0, 0, 3),
+            content: Vec::new(),
+        });
+        let bytes = announce_huge_length(&packet);
+        assert!(matches!(Message::decode(&bytes), Err(Error::BrokenMessage)));
+    }
+
+    #[test]
+    fn huge_hello_reply_is_rejected() {
+        let key_pair = StaticKeyPair::from_pkcs8_bytes(&StaticKeyPair::generate_pkcs8()).unwrap();
+        let (_, pub_seed) = crypto::generate_seed_pair();
+        let reply = Message::HelloReply {
+            seed: key_pair.sign(&crypto::ReplySeed::new(pub_seed, 0, &[])),
+            settings: Vec::new(),
+        };
+        let bytes = announce_huge_length(&reply);
+        assert!(matches!(Message::decode(&bytes), Err(Error::BrokenMessage)));
+    }
+}
@@ -0,0 +1,180 @@
+//! Captures decrypted inner packets to a pcapng file for debugging (e.g. with Wireshark).
+//!
+//! Each packet is recorded with a comment telling the direction and the peer,
+//! such as `peer->tun 10.20.30.2`.
+
+use std::fs::File;
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use crate::error::Result;
+
+const SECTION_HEADER_BLOCK: u32 = 0x0A0D_0D0A;
+const INTERFACE_DESCRIPTION_BLOCK: u32 = 0x0000_0001;
+const ENHANCED_PACKET_BLOCK: u32 = 0x0000_0006;
+const BYTE_ORDER_MAGIC: u32 = 0x1A2B_3C4D;
+
+/// Raw IPv4 packets without link-layer headers.
+const LINKTYPE_IPV4: u16 = 228;
+
+const OPT_END: u16 = 0;
+const OPT_COMMENT: u16 = 1;
+const OPT_IF_NAME: u16 = 2;
+const OPT_EPB_FLAGS: u16 = 2;
+
+/// Which way a packet went through the tunnel.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Direction {
+    /// Read from the VPN interface and sent to a peer.
+    TunToPeer,
+    /// Received from a peer and written to the VPN interface.
+    PeerToTun,
+    /// Received from a peer and forwarded to another peer.
+    PeerToPeer,
+}
+
+impl Direction {
+    fn label(self) -> &'static str {
+        match self {
+            Direction::TunToPeer => "tun->peer",
+            Direction::PeerToTun => "peer->tun",
+            Direction::PeerToPeer => "peer->peer",
+        }
+    }
+
+    /// The direction bits of `epb_flags` seen from the VPN interface.
+    fn flags(self) -> u32 {
+        match self {
+            Direction::TunToPeer => 0b10, // outbound
+            Direction::PeerToTun => 0b01, // inbound
+            Direction::PeerToPeer => 0b00,
+        }
+    }
+}
+
+/// A pcapng file being written.
+#[derive(Debug)]
+pub struct Capture {
+    path: PathBuf,
+    file: File,
+}
+
+impl Capture {
+    /// Creates a capture file readable only by the owner. `ifname` is recorded as the name of
+    /// the interface. An existing regular file (e.g. from the previous run) is truncated,
+    /// but anything else at the path (e.g. a symlink) is refused.
+    pub fn create<P: AsRef<Path>>(path: P, ifname: &str) -> Result<Self> {
+        use std::os::unix::fs::{OpenOptionsExt, PermissionsExt};
+        let path = path.as_ref().to_owned();
+        match std::fs::symlink_metadata(&path) {
+            Ok(metadata) if !metadata.file_type().is_file() => {
+                let msg = format!("{:?} exists and is not a regular file", path);
+                return Err(std::io::Error::new(std::io::ErrorKind::AlreadyExists, msg).into());
+            }
+            Ok(_) => {}
+            Err(err) if err.kind() == std::io::ErrorKind::NotFound => {}
+            Err(err) => return Err(err.into()),
+        }
+        let mut file = std::fs::OpenOptions::new()
+            .write(true)
+            .create(true)
+            .truncate(true)
+            .mode(0o600)
+            .open(&path)?;
+        // `mode` applies only to a new file
+        file.set_permissions(std::fs::Permissions::from_mode(0o600))?;
+
+        let mut body = Vec::new();
+        body.extend_from_slice(&BYTE_ORDER_MAGIC.to_le_bytes());
+        body.extend_from_slice(&1u16.to_le_bytes()); // major version
+        body.extend_from_slice(&0u16.to_le_bytes()); // minor version
+        body.extend_from_slice(&(-1i64).to_le_bytes()); // section length: unknown
+        file.write_all(&block(SECTION_HEADER_BLOCK, body))?;
+
+        let mut body = Vec::new();
+        body.extend_from_slice(&LINKTYPE_IPV4.to_le_bytes());
+        body.extend_from_slice(&0u16.to_le_bytes()); // reserved
+        body.extend_from_slice(&0u32.to_le_bytes()); // snap length: unlimited
+        push_option(&mut body, OPT_IF_NAME, ifname.as_bytes());
+        push_option(&mut body, OPT_END, &[]);
+        file.write_all(&block(INTERFACE_DESCRIPTION_BLOCK, body))?;
+
+        log::info!("capture started: {:?}", path);
+        Ok(Self { path, file })
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// Records a decrypted packet exchanged with `peer`.
+    pub fn record<D: std::fmt::Display>(
+        &mut self,
+        direction: Direction,
+        peer: D,
+        packet: &[u8],
+    ) -> Result<()> {
+        let micros = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|d| d.as_micros() as u64)
+            .unwrap_or(0);
+
+        let mut body = Vec::new();
+        body.extend_from_slice(&0u32.to_le_bytes()); // interface ID
+        body.extend_from_slice(&((micros >> 32) as u32).to_le_bytes());
+        body.extend_from_slice(&(micros as u32).to_le_bytes());
+        body.extend_from_slice(&(packet.len() as u32).to_le_bytes()); // captured length
+        body.extend_from_slice(&(packet.len() as u32).to_le_bytes()); // original length
+        body.extend_from_slice(packet);
+        pad(&mut body);
+
+        let comment = format!("{} {}", direction.label(), peer);
+        push_option(&mut body, OPT_COMMENT, comment.as_bytes());
+        push_option(&mut body, OPT_EPB_FLAGS, &direction.flags().to_le_bytes());
+        push_option(&mut body, OPT_END, &[]);
+
+        self.file.write_all(&block(ENHANCED_PACKET_BLOCK, body))?;
+        Ok(())
+    }
+}
+
+/// Records a packet if `capture` is active. The capture is stopped if it fails.
+pub fn record<D: std::fmt::Display>(
+    capture: &mut Option<Capture>,
+    direction: Direction,
+    peer: D,
+    packet: &[u8],
+) {
+    if let Some(active) = capture.as_mut() {
+        if let Err(err) = active.record(direction, peer, packet) {
+            log::error!("capture: {:?}: {}", active.path(), err);
+            *capture = None;
+        }
+    }
+}
+
+/// Makes a block with the type, the total length at both ends, and the body padded to 32 bits.
+fn block(block_type: u32, mut body: Vec<u8>) -> Vec<u8> {
+    pad(&mut body);
+    let total_len = (body.len() + 12) as u32;
+    let mut block = Vec::with_capacity(total_len as usize);
+    block.extend_from_slice(&block_type.to_le_bytes());
+    block.extend_from_slice(&total_len.to_le_bytes());
+    block.extend_from_slice(&body);
+    block.extend_from_slice(&total_len.to_le_bytes());
+    block
+}
+
+fn push_option(body: &mut Vec<u8>, code: u16, value: &[u8]) {
+    body.extend_from_slice(&code.to_le_bytes());
+    body.extend_from_slice(&(value.len() as u16).to_le_bytes());
+    body.extend_from_slice(value);
+    pad(body);
+}
+
+fn pad(body: &mut Vec<u8>) {
+    while !body.len().is_multiple_of(4) {
+        body.push(0);
+    }
+}
@@ -0,0 +1,187 @@
+//! Certificates of peer keys signed by a CA key, so that the server accepts peers
+//! without registering each of their keys in `[[peers]]`.
+//!
+//! The CA key is an Ed25519 key like the static keys (e.g. made by `vpn-keygen generate`).
+//! A certificate is written as a line of base64, and a peer presents it in its `Hello`.
+
+use serde::{Deserialize, Serialize};
+use std::net::Ipv4Addr;
+use std::path::Path;
+use std::time::SystemTime;
+
+use crate::crypto::{self, Signed, StaticKeyPair, PUBLIC_KEY_LEN};
+use crate::error::{Error, Result};
+
+/// What a certificate tells about a peer.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct CertificateContent {
+    /// The public key of the peer.
+    #[serde(with = "crate::bytes::array")]
+    pub public_key: [u8; PUBLIC_KEY_LEN],
+
+    /// The address assigned to the peer. If `None`, an address is leased from the pool.
+    pub address: Option<Ipv4Addr>,
+
+    /// When the certificate expires (seconds since the UNIX epoch).
+    pub expires_at: u64,
+}
+
+/// A `CertificateContent` signed by the CA key.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Certificate(Signed<CertificateContent>);
+
+impl Certificate {
+    /// Signs the content with the CA key.
+    pub fn issue(ca_key_pair: &StaticKeyPair, content: &CertificateContent) -> Self {
+        Self(ca_key_pair.sign(content))
+    }
+
+    /// Reads a certificate from a file.
+    pub fn load<P: AsRef<Path>>(path: P) -> Result<Self> {
+        std::fs::read_to_string(path)?.parse()
+    }
+
+    /// Returns the content without verifying it (for inspection).
+    pub fn content(&self) -> Result<CertificateContent> {
+        crate::decode(self.0.data()).map_err(|_| invalid("malformed content"))
+    }
+
+    /// Verifies that the certificate was signed by the CA key and has not expired at `now`.
+    pub fn verify(&self, ca_public_key: &[u8], now: SystemTime) -> Result<CertificateContent> {
+        self.0
+            .verify(ca_public_key)
+            .map_err(|_| invalid("not signed by the CA"))?;
+        let content = self.content()?;
+        if content.expires_at <= crate::unix_time(now) {
+            return Err(invalid(format!(
+                "expired on {}",
+                format_date(content.expires_at)
+            )));
+        }
+        Ok(content)
+    }
+}
+
+impl std::fmt::Display for Certificate {
+    /// Writes the certificate in base64.
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        let bytes = bincode::serialize(self).expect("serialize");
+        write!(f, "{}", crypto::to_base64(&bytes))
+    }
+}
+
+impl std::str::FromStr for Certificate {
+    type Err = Error;
+    fn from_str(s: &str) -> Result<Self> {
+        let bytes = crypto::from_base64(s.trim()).ok_or_else(|| invalid("not in base64"))?;
+        crate::decode(&bytes).map_err(|_| invalid("malformed"))
+    }
+}
+
+fn invalid<S: Into<String>>(reason: S) -> Error {
+    Error::InvalidCertificate {
+        reason: reason.into(),
+    }
+}
+
+/// Formats seconds since the UNIX epoch as a date in UTC (`YYYY-MM-DD`).
+pub fn format_date(secs: u64) -> String {
+    // http://howardhinnant.github.io/date_algorithms.html#civil_from_days
+    let days = (secs / 86400) as i64 + 719468;
+    let era = days.div_euclid(146097);
+    let doe = days.rem_euclid(146097);
+    let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = doy - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = yoe + era * 400 + i64::from(month <= 2);
+    format!("{:04}-{:02}-{:02}", year, month, day)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+
+    fn key_pair() -> StaticKeyPair {
+        StaticKeyPair::from_pkcs8_bytes(&StaticKeyPair::generate_pkcs8()).unwrap()
+    }
+
+    fn issue(ca: &StaticKeyPair, expires_at: u64) -> Certificate {
+        let content = CertificateContent {
+            public_key: key_pair().public_key().try_into().unwrap(),
+            address: Some(Ipv4Addr::new(10, 0, 0, 5)),
+            expires_at,
+        };
+        Certificate::issue(ca, &content)
+    }
+
+    #[test]
+    fn format_date_epoch() {
+        assert_eq!(format_date(0), "1970-01-01");
+        assert_eq!(format_date(86399), "1970-01-01");
+        assert_eq!(format_date(86400), "1970-01-02");
+    }
+
+    #[test]
+    fn format_date_leap_day() {
+        assert_eq!(format_date(951782400), "2000-02-29");
+        assert_eq!(format_date(951868800), "2000-03-01");
+        assert_eq!(format_date(1709164800), "2024-02-29");
+        // 2100 is not a leap year
+        assert_eq!(format_date(4107542400), "2100-03-01");
+    }
+
+    #[test]
+    fn format_date_year_boundary() {
+        assert_eq!(format_date(1704067199), "2023-12-31");
+        assert_eq!(format_date(1704067200), "2024-01-01");
+    }
+
+    #[test]
+    fn round_trip() {
+        let certificate = issue(&key_pair(), 1704067200);
+        let parsed: Certificate = certificate.to_string().parse().unwrap();
+        assert_eq!(parsed, certificate);
+        // surrounding whitespace (e.g. a newline at the end of the file) is ignored
+        let parsed: Certificate = format!(" {}\n", certificate).parse().unwrap();
+        assert_eq!(parsed, certificate);
+    }
+
+    #[test]
+    fn from_str_rejects_garbage() {
+        assert!(matches!(
+            "not base64!".parse::<Certificate>(),
+            Err(Error::InvalidCertificate { .. })
+        ));
+        assert!(matches!(
+            "AAAA".parse::<Certificate>(),
+            Err(Error::InvalidCertificate { .. })
+        ));
+    }
+
+    #[test]
+    fn verify_expiry_boundary() {
+        let ca = key_pair();
+        let expires_at = 1704067200;
+        let certificate = issue(&ca, expires_at);
+        let at = |secs| SystemTime::UNIX_EPOCH + Duration::from_secs(secs);
+
+        let content = certificate.verify(&ca.public_key(), at(expires_at - 1));
+        assert_eq!(content.unwrap().expires_at, expires_at);
+        assert!(matches!(
+            certificate.verify(&ca.public_key(), at(expires_at)),
+            Err(Error::InvalidCertificate { reason }) if reason == "expired on 2024-01-01"
+        ));
+    }
+
+    #[test]
+    fn verify_rejects_another_ca() {
+        let certificate = issue(&key_pair(), u64::MAX);
+        assert!(matches!(
+            certificate.verify(&key_pair().public_key(), SystemTime::now()),
+            Err(Error::InvalidCertificate { .. })
+        ));
+    }
+}
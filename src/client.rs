@@ -1,14 +1,54 @@
+//! The client, which connects to the server and tunnels packets of the host through it.
+//!
+//! The `client` binary is a thin wrapper of `Client`, which can be embedded as well:
+//! ```no_run
+//! use poor_mans_vpn::client::{Client, Config};
+//!
+//! let config = Config::load("client-config.toml")?;
+//! let client = Client::builder(config)
+//!     .on_event(|event| println!("{:?}", event))
+//!     .start()?;
+//! client.wait()?;
+//! # Ok::<(), poor_mans_vpn::error::Error>(())
+//! ```
+
 use etherparse::Ipv4Header;
-use poor_mans_vpn::{crypto, error, setup_tun, Channel, Message, SealedPacket};
-use std::net::{Ipv4Addr, UdpSocket};
-use std::path::PathBuf;
+use std::net::{Ipv4Addr, SocketAddr, UdpSocket};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::mpsc::{self, Receiver, Sender};
 use std::sync::{Arc, Mutex};
+use std::thread::JoinHandle;
+use std::time::{Duration, Instant, SystemTime};
+
+use crate::capture::{self, Capture, Direction};
+use crate::cert::Certificate;
+use crate::control::{Request, Response};
+use crate::crypto::{self, Fingerprint, PublicKeySource, StaticKeyPair};
+use crate::error::{Error, Result};
+use crate::event::{Callback, Event, EventLog};
+use crate::hook::{self, Hooks};
+use crate::keylog::KeyLogWriter;
+use crate::metrics::{DropReason, Exposition, HandshakeFailure, Metrics};
+use crate::route::Ipv4Cidr;
+use crate::{
+    add_route, current_route, is_timeout, run_command, setup_tun, Channel, Message, PeerSettings,
+    SealedPacket, Teardown, Traffic, VirtualInterface,
+};
+
+/// The nftables table holding the kill switch rules.
+const KILL_SWITCH_TABLE: &str = "poor_mans_vpn_kill_switch";
+
+const RESOLV_CONF: &str = "/etc/resolv.conf";
 
-use error::{Error, Result};
+/// The interval (in seconds) of `Hello` messages until the server replies.
+const HANDSHAKE_RETRY_INTERVAL: u64 = 5;
 
-const CONFIG_FILE: &str = "client-config.toml";
+/// The session is regarded as lost after this number of heart beats without reply.
+const SESSION_TIMEOUT_BEATS: u32 = 3;
 
 mod default_config {
+    use crate::crypto::PublicKeySource;
     use std::net::Ipv4Addr;
     use std::path::PathBuf;
 
@@ -24,15 +64,11 @@ mod default_config {
         "vpn0".to_owned()
     }
 
-    pub fn max_transmission_unit() -> u16 {
-        1300
-    }
-
-    pub fn server_public_key() -> PathBuf {
+    pub fn server_public_key() -> PublicKeySource {
         let mut p = PathBuf::new();
         p.push("keys");
         p.push("server_pubkey.der");
-        p
+        PublicKeySource::File(p)
     }
 
     pub fn private_key() -> PathBuf {
@@ -41,192 +77,658 @@ mod default_config {
         p.push("privkey.der");
         p
     }
+
+    pub fn control_socket() -> PathBuf {
+        PathBuf::from(crate::control::CLIENT_SOCKET)
+    }
 }
 
-#[derive(Debug, serde::Deserialize)]
-struct Config {
-    peer: PeerConfig,
-    server: ServerConfig,
+/// The configuration of the client (`client-config.toml`).
+#[derive(Debug, Clone, serde::Deserialize)]
+pub struct Config {
+    pub peer: PeerConfig,
+    pub server: ServerConfig,
 }
 
-#[derive(Debug, serde::Deserialize)]
-struct PeerConfig {
+#[derive(Debug, Clone, serde::Deserialize)]
+pub struct PeerConfig {
     /// The name of a network interface to be used.
     #[serde(default = "default_config::ifname")]
-    ifname: String,
-
-    /// The address to be assigned to the VPN interface.
-    address: Ipv4Addr,
-
-    /// The MTU value of the VPN interface.
-    #[serde(default = "default_config::max_transmission_unit")]
-    mtu: u16,
+    pub ifname: String,
 
     /// A path to the public key of the server.
     #[serde(default = "default_config::private_key")]
-    private_key: PathBuf,
+    pub private_key: PathBuf,
+
+    /// A path to a certificate of the key issued by the CA of the server (e.g. by `vpn-keygen cert issue`).
+    /// It is needed if the server enrolls peers by certificates instead of registering their keys.
+    pub certificate: Option<PathBuf>,
 
     /// The binding address of the client UDP socket.
     #[serde(default = "default_config::ipv4_addr_unspecified")]
-    bind_address: Ipv4Addr,
+    pub bind_address: Ipv4Addr,
 
     /// The binding port of the client UDP socket.
     #[serde(default)] // 0
-    bind_port: u16,
+    pub bind_port: u16,
+
+    /// Remote prefixes to be reached through the VPN interface.
+    #[serde(default)]
+    pub routes: Vec<Ipv4Cidr>,
+
+    /// Whether all traffic is sent through the VPN interface.
+    #[serde(default)]
+    pub full_tunnel: bool,
+
+    /// Whether traffic other than the tunnel is blocked while the client runs.
+    #[serde(default)]
+    pub kill_switch: bool,
+
+    /// A path to the Unix domain socket to control the client (e.g. with `vpnctl`).
+    #[serde(default = "default_config::control_socket")]
+    pub control_socket: PathBuf,
+
+    /// An address to export metrics for Prometheus (e.g. `127.0.0.1:9587`). Not exported if omitted.
+    pub metrics_address: Option<SocketAddr>,
+
+    /// A path to the pcapng file where decrypted packets are captured from startup.
+    /// The capture of the previous run is overwritten.
+    pub capture_file: Option<PathBuf>,
+
+    /// A shell command run after the interface is set up.
+    pub on_up: Option<String>,
+
+    /// A shell command run on shutdown.
+    pub on_down: Option<String>,
+
+    /// A shell command run when a session with the server is established.
+    pub on_peer_connect: Option<String>,
+
+    /// A shell command run when the server stops replying.
+    pub on_peer_disconnect: Option<String>,
 }
 
-#[derive(Debug, serde::Deserialize)]
-struct ServerConfig {
+#[derive(Debug, Clone, serde::Deserialize)]
+pub struct ServerConfig {
     /// The binding address of the server.
-    bind_address: Ipv4Addr,
+    pub bind_address: Ipv4Addr,
 
     /// The binding port of the server.
     #[serde(default = "default_config::server_bind_port")]
-    port: u16,
+    pub port: u16,
 
-    /// A path to the public key of the server.
+    /// The public key of the server in base64, or a path to it.
     #[serde(default = "default_config::server_public_key")]
-    public_key: PathBuf,
+    pub public_key: PublicKeySource,
 }
 
 fn print_error<D: std::fmt::Display>(ctx: D, err: Error) {
     log::error!("{}: {}", ctx, err);
 }
 
-fn main() -> Result<()> {
-    env_logger::init();
+#[derive(Debug, Clone, Copy, PartialEq, serde::Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ConnectionState {
+    /// Preparing the connection.
+    Connecting,
+    /// Waiting for the first reply from the server.
+    Handshaking,
+    Connected,
+    /// Re-establishing the lost session.
+    Reconnecting,
+}
+
+/// The status of the client (reported to the control socket as well).
+#[derive(Debug, Clone, serde::Serialize)]
+pub struct Status {
+    /// The fingerprint of the public key of the client.
+    pub fingerprint: crypto::Fingerprint,
+    pub state: ConnectionState,
+    pub endpoint: SocketAddr,
+    pub address: Option<Ipv4Addr>,
+    /// When the last handshake completed (seconds since the UNIX epoch).
+    pub last_handshake: Option<u64>,
+    pub traffic: Traffic,
+    /// The round trip time of the last heart beat in milliseconds.
+    pub rtt_ms: Option<f64>,
+}
+
+/// The state of the client shared among threads.
+struct State {
+    status: Status,
+    session_key: Option<crypto::SessionKey>,
+
+    /// The private seed of the handshake in progress, and when its public part was generated.
+    pending: Option<(crypto::PrivSeed, u64)>,
 
-    let config: Config = {
-        let config_toml = std::fs::read(CONFIG_FILE)?;
-        match toml::from_slice(&config_toml) {
-            Ok(conf) => conf,
-            Err(_) => {
-                log::error!("failed to parse {:?}", CONFIG_FILE);
-                return Ok(());
+    /// When the server replied to a heart beat or a hello last time.
+    last_heard: Instant,
+    heartbeat_sent: Option<Instant>,
+
+    /// The active capture of decrypted packets.
+    capture: Option<Capture>,
+
+    keylog: KeyLogWriter,
+    events: EventLog,
+}
+
+/// Counts the result of a handshake.
+fn count_handshake<T>(metrics: &Metrics, result: &Result<T>) {
+    match result {
+        Ok(_) => metrics.handshake_completed(),
+        Err(err) => {
+            if let Some(reason) = HandshakeFailure::of(err) {
+                metrics.handshake_failed(reason);
             }
         }
+    }
+}
+
+/// Starts a handshake by sending a `Hello` message.
+/// The current session (if any) is used until the server replies.
+fn send_hello(channel: &mut Channel, shared: &Shared) -> Result<()> {
+    let static_key_pair = &shared.static_key_pair;
+    let (priv_seed, pub_seed) = crypto::generate_seed_pair();
+    let hello = Message::Hello {
+        key_hint: crypto::Fingerprint::of(&static_key_pair.public_key()),
+        seed: static_key_pair.sign(&pub_seed),
+        certificate: shared.certificate.as_deref().cloned(),
     };
-    log::debug!("config: {:#?}", config);
 
-    let static_key_pair = crypto::StaticKeyPair::from_pkcs8(&config.peer.private_key)?;
-    let server_pubkey = std::fs::read(&config.server.public_key)?;
+    let mut state = shared.state.lock().expect("poisoned");
+    state.pending = Some((priv_seed, pub_seed.created_at()));
+    if state.status.state == ConnectionState::Connecting {
+        state.status.state = ConnectionState::Handshaking;
+    } else if state.status.state == ConnectionState::Connected {
+        state.status.state = ConnectionState::Reconnecting;
+    }
+    drop(state);
 
-    let iface = setup_tun(
-        &config.peer.ifname,
-        config.peer.address,
-        24,
-        config.peer.mtu,
-    )?;
-    let iface = Arc::new(iface);
+    log::debug!("sending hello");
+    channel.send(&hello)
+}
 
-    let mut channel = {
-        let sock = UdpSocket::bind((config.peer.bind_address, config.peer.bind_port))?;
+/// Completes the handshake in progress with a `HelloReply` message.
+fn accept_hello_reply(
+    state: &Mutex<State>,
+    server_pubkey: &[u8],
+    seed: crypto::Signed<crypto::ReplySeed>,
+    mut settings: Vec<u8>,
+) -> Result<PeerSettings> {
+    let reply_seed = seed.open(server_pubkey)?;
 
-        // We focus on communicatating with the server
-        sock.connect((config.server.bind_address, config.server.port))?;
+    let mut state = state.lock().expect("poisoned");
+    // the private seed is spent by the derivation: a reply must belong to the pending hello first
+    let server_seed = match &state.pending {
+        Some((_, hello_created_at)) => reply_seed.bound_to(*hello_created_at, &settings)?,
+        None => {
+            log::debug!("ignored a reply to no hello");
+            return Err(Error::BrokenMessage);
+        }
+    };
+    let (priv_seed, _) = state.pending.take().expect("pending hello");
+    let mut key = crypto::SessionKey::client_derive(priv_seed, server_seed)?;
+    state.keylog.write(state.status.fingerprint, &key);
+    let settings: PeerSettings = key.unseal(PeerSettings::AAD, &mut settings)?;
+    log::debug!("settings: {:?}", settings);
 
-        Channel::new(sock)
+    let (fingerprint, endpoint, address) = (
+        Fingerprint::of(server_pubkey),
+        state.status.endpoint,
+        settings.address,
+    );
+    let event = match state.session_key.replace(key) {
+        Some(_) => Event::Rekey {
+            fingerprint,
+            endpoint,
+            address,
+        },
+        None => Event::HandshakeAccepted {
+            fingerprint,
+            endpoint,
+            address,
+        },
     };
+    state.events.emit(event);
+    state.last_heard = Instant::now();
+    state.status.state = ConnectionState::Connected;
+    state.status.address = Some(settings.address);
+    state.status.last_handshake = Some(crate::unix_time(SystemTime::now()));
+    log::info!("connection established!");
+    Ok(settings)
+}
 
-    // Establish a connection
-    let session_key = {
-        let (priv_seed, pub_seed) = crypto::generate_seed_pair();
-        let signed_seed = static_key_pair.sign(&pub_seed);
+/// Installs nftables rules which allow only the UDP flow to the server and traffic on the VPN
+/// interface. The rules are left in place if the client crashes, so nothing leaks outside the VPN.
+fn install_kill_switch(config: &Config, teardown: &mut Teardown) -> Result<()> {
+    let table = format!("inet {}", KILL_SWITCH_TABLE);
+    let ifname = &config.peer.ifname;
+    let server = config.server.bind_address;
+    let port = config.server.port;
 
-        let hello = Message::Hello {
-            addr: config.peer.address,
-            seed: signed_seed,
+    let chain = |hook: &str| {
+        let spec = format!("type filter hook {} priority 0 ; policy drop ;", hook);
+        format!("add chain {} {} {{ {} }}", table, hook, spec)
+    };
+    let commands = [
+        // replace the rules possibly left by the previous run
+        format!("add table {}", table),
+        format!("flush table {}", table),
+        chain("output"),
+        chain("input"),
+        format!("add rule {} output oif lo accept", table),
+        format!("add rule {} output oifname \"{}\" accept", table, ifname),
+        format!(
+            "add rule {} output ip daddr {} udp dport {} accept",
+            table, server, port
+        ),
+        format!("add rule {} input iif lo accept", table),
+        format!("add rule {} input iifname \"{}\" accept", table, ifname),
+        format!(
+            "add rule {} input ip saddr {} udp sport {} accept",
+            table, server, port
+        ),
+    ];
+    // `nft` concatenates its arguments, so a whole command can be passed as one.
+    for command in commands.iter() {
+        run_command("nft", &[command])?;
+    }
+
+    teardown.push("nft", &[&format!("delete table {}", table)]);
+    log::info!("kill switch enabled");
+    Ok(())
+}
+
+/// Replaces the system DNS servers while the client runs.
+///
+/// If `/etc/resolv.conf` is a symlink (e.g. to the stub of systemd-resolved), the link itself is
+/// backed up and restored, so the file it points to is never overwritten.
+fn apply_dns(dns: &[Ipv4Addr], teardown: &mut Teardown) -> Result<()> {
+    let backup = format!("{}.poor-mans-vpn", RESOLV_CONF);
+    // A backup left by a crashed run is the only copy of the original configuration.
+    if std::fs::symlink_metadata(&backup).is_ok() {
+        return Err(Error::Setup {
+            msg: format!(
+                "{} exists (left by a previous run?); restore it to {} or remove it",
+                backup, RESOLV_CONF
+            ),
+        });
+    }
+
+    let metadata = std::fs::symlink_metadata(RESOLV_CONF)?;
+    if metadata.file_type().is_symlink() {
+        let target = std::fs::read_link(RESOLV_CONF)?;
+        std::os::unix::fs::symlink(&target, &backup)?;
+        std::fs::remove_file(RESOLV_CONF)?;
+    } else {
+        std::fs::copy(RESOLV_CONF, &backup)?;
+    }
+    // `mv` renames the backup, so a symlink is restored as the link itself.
+    teardown.push("mv", &[&backup, RESOLV_CONF]);
+
+    let resolv_conf: String = dns
+        .iter()
+        .map(|addr| format!("nameserver {}\n", addr))
+        .collect();
+    std::fs::write(RESOLV_CONF, resolv_conf)?;
+    log::info!("DNS servers: {:?}", dns);
+    Ok(())
+}
+
+impl Config {
+    /// Reads the configuration from a TOML file.
+    pub fn load<P: AsRef<Path>>(path: P) -> Result<Self> {
+        let config_toml = std::fs::read(path)?;
+        toml::from_slice(&config_toml).map_err(|err| Error::InvalidConfig {
+            msg: err.to_string(),
+        })
+    }
+}
+
+/// Configures and starts a `Client`.
+pub struct ClientBuilder {
+    config: Config,
+    key_pair: Option<StaticKeyPair>,
+    server_public_key: Option<Vec<u8>>,
+    certificate: Option<Certificate>,
+    iface: Option<Arc<dyn VirtualInterface>>,
+    teardown: Option<Arc<Mutex<Teardown>>>,
+    callbacks: Vec<Callback>,
+}
+
+impl ClientBuilder {
+    /// Uses the key pair instead of reading `private_key` of the configuration.
+    pub fn key_pair(mut self, key_pair: StaticKeyPair) -> Self {
+        self.key_pair = Some(key_pair);
+        self
+    }
+
+    /// Uses the public key of the server instead of reading `public_key` of the configuration.
+    pub fn server_public_key(mut self, public_key: Vec<u8>) -> Self {
+        self.server_public_key = Some(public_key);
+        self
+    }
+
+    /// Uses the certificate instead of reading `certificate` of the configuration.
+    pub fn certificate(mut self, certificate: Certificate) -> Self {
+        self.certificate = Some(certificate);
+        self
+    }
+
+    /// Uses the interface instead of setting up a tun device with the settings pushed by the server.
+    /// The host is left untouched: neither routes, DNS servers nor the kill switch are configured.
+    pub fn interface(mut self, iface: Arc<dyn VirtualInterface>) -> Self {
+        self.iface = Some(iface);
+        self
+    }
+
+    /// Registers the commands reverting changes to the host to the given teardown
+    /// (e.g. one run on signals by `exit_on_signal`) instead of a new one.
+    pub fn teardown(mut self, teardown: Arc<Mutex<Teardown>>) -> Self {
+        self.teardown = Some(teardown);
+        self
+    }
+
+    /// Registers a function called with every event. It is called on the threads of the client,
+    /// possibly with its state locked, so it must not call back into the client.
+    pub fn on_event<F>(mut self, callback: F) -> Self
+    where
+        F: Fn(&Event) + Send + Sync + 'static,
+    {
+        self.callbacks.push(Box::new(callback));
+        self
+    }
+
+    /// Starts connecting to the server in background threads.
+    /// The interface is set up once the server replies.
+    /// Changes made to the host are reverted if it fails.
+    pub fn start(self) -> Result<Client> {
+        let teardown = self.teardown.clone().unwrap_or_default();
+        let result = self.start_with(teardown.clone());
+        if result.is_err() {
+            teardown.lock().expect("poisoned").run();
+        }
+        result
+    }
+
+    fn start_with(self, teardown: Arc<Mutex<Teardown>>) -> Result<Client> {
+        let config = Arc::new(self.config);
+        let static_key_pair = match self.key_pair {
+            Some(key_pair) => key_pair,
+            None => StaticKeyPair::from_pkcs8(&config.peer.private_key)?,
+        };
+        let server_pubkey = match self.server_public_key {
+            Some(public_key) => public_key,
+            None => config
+                .server
+                .public_key
+                .load()
+                .map_err(|err| Error::InvalidConfig {
+                    msg: format!(
+                        "public key of the server ({}): {}",
+                        config.server.public_key, err
+                    ),
+                })?,
+        };
+        let certificate = match (self.certificate, &config.peer.certificate) {
+            (Some(certificate), _) => Some(certificate),
+            (None, Some(path)) => {
+                let certificate = Certificate::load(path).map_err(|err| Error::InvalidConfig {
+                    msg: format!("certificate ({}): {}", path.display(), err),
+                })?;
+                Some(certificate)
+            }
+            (None, None) => None,
         };
-        channel.send(&hello).expect("send hello");
 
-        let msg = channel.recv().expect("recv or parse");
-        match msg {
-            Message::HelloReply { seed: server_seed } => {
-                let server_seed = server_seed.open(&server_pubkey).expect("signature invalid");
-                let key = crypto::SessionKey::client_derive(priv_seed, server_seed);
-                log::info!("connection established!");
-                key
+        if self.iface.is_none() {
+            let mut teardown = teardown.lock().expect("poisoned");
+            if config.peer.kill_switch {
+                install_kill_switch(&config, &mut teardown)?;
             }
-            _ => {
-                panic!("unexpected message");
+            if config.peer.full_tunnel {
+                route_endpoint(&config, &mut teardown)?;
             }
         }
-    };
-    let session_key = Arc::new(Mutex::new(session_key));
 
-    std::thread::spawn({
-        let mut channel = channel.clone();
-        move || loop {
-            // FIXME: make `freq` configuarable
-            let freq = std::time::Duration::from_secs(5);
-            std::thread::sleep(freq);
-            if let Err(err) = channel.send(&Message::HeartBeat) {
-                print_error("heart beat", err);
-            }
+        let channel = {
+            let sock = UdpSocket::bind((config.peer.bind_address, config.peer.bind_port))?;
+
+            // We focus on communicatating with the server
+            sock.connect((config.server.bind_address, config.server.port))?;
+
+            Channel::new(sock)
+        };
+        channel.set_read_timeout(Some(crate::POLL_INTERVAL))?;
+
+        let mut events = EventLog::default();
+        for callback in self.callbacks {
+            events.subscribe(callback);
         }
-    });
 
-    std::thread::spawn({
-        let iface = iface.clone();
-        let mut channel = channel.clone();
-        let session_key = session_key.clone();
-        move || -> std::io::Result<()> {
-            loop {
-                let msg = match channel.recv() {
-                    Err(err) => {
-                        print_error("channel.recv", err);
-                        continue;
-                    }
-                    Ok(msg) => msg,
-                };
+        let state = State {
+            status: Status {
+                fingerprint: Fingerprint::of(&static_key_pair.public_key()),
+                state: ConnectionState::Connecting,
+                endpoint: channel.peer_addr()?,
+                address: None,
+                last_handshake: None,
+                traffic: Traffic::default(),
+                rtt_ms: None,
+            },
+            session_key: None,
+            pending: None,
+            last_heard: Instant::now(),
+            heartbeat_sent: None,
+            capture: match &config.peer.capture_file {
+                Some(path) => Some(Capture::create(path, &config.peer.ifname)?),
+                None => None,
+            },
+            keylog: KeyLogWriter::from_env()?,
+            events,
+        };
 
-                match msg {
-                    Message::Packet(sealed_packet) => {
-                        let packet: Vec<u8> = {
-                            let key = session_key.lock().expect("poisoned");
-                            let aad = sealed_packet.addresses_as_bytes();
-                            let mut content = sealed_packet.content;
-                            match key.unseal(&aad, &mut content) {
-                                Ok(p) => p,
-                                Err(err) => {
-                                    print_error("unseal", err);
-                                    continue;
-                                }
-                            }
-                        };
-
-                        let (ip_hdr, _payload) = match Ipv4Header::from_slice(&packet) {
-                            Ok(hdr_payload) => hdr_payload,
-                            Err(err) => {
-                                log::debug!("ignored uninteresting packet: {}", err);
-                                continue;
-                            }
-                        };
-                        log::debug!(
-                            "receive {} bytes: {:?} --> {:?}",
-                            packet.len(),
-                            Ipv4Addr::from(ip_hdr.source),
-                            Ipv4Addr::from(ip_hdr.destination),
-                        );
-
-                        iface.send(&packet)?;
-                    }
+        let (done, finished) = mpsc::channel();
+        let shared = Shared {
+            config,
+            state: Arc::new(Mutex::new(state)),
+            static_key_pair: Arc::new(static_key_pair),
+            certificate: certificate.map(Arc::new),
+            server_pubkey: Arc::new(server_pubkey),
+            metrics: Arc::new(Metrics::new()),
+            stopped: Arc::new(AtomicBool::new(false)),
+            done,
+        };
+        shared.spawn_worker({
+            let shared = shared.clone();
+            let channel = channel.clone();
+            let iface = self.iface;
+            let teardown = teardown.clone();
+            move || connect(shared, channel, iface, teardown)
+        });
+        Ok(Client {
+            shared,
+            channel: Mutex::new(channel),
+            teardown,
+            finished: Mutex::new(finished),
+        })
+    }
+}
 
-                    Message::HeartBeat => {
-                        log::trace!("HeartBeat from the server");
-                    }
+/// Keeps the path to the server out of the tunnel before the default route is overridden.
+fn route_endpoint(config: &Config, teardown: &mut Teardown) -> Result<()> {
+    let endpoint = format!("{}/32", config.server.bind_address);
+    let route = current_route(config.server.bind_address)?;
+    let route: Vec<&str> = route.iter().map(|w| w.as_str()).collect();
+
+    let mut args = vec!["route", "add", &endpoint];
+    args.extend_from_slice(&route);
+    run_command("ip", &args)?;
+
+    let mut args = vec!["route", "del", &endpoint];
+    args.extend_from_slice(&route);
+    teardown.push("ip", &args);
+    Ok(())
+}
 
-                    _ => {
-                        log::error!("unexpected message");
+/// What the threads of a client share.
+#[derive(Clone)]
+struct Shared {
+    config: Arc<Config>,
+    state: Arc<Mutex<State>>,
+    static_key_pair: Arc<StaticKeyPair>,
+    certificate: Option<Arc<Certificate>>,
+    server_pubkey: Arc<Vec<u8>>,
+    metrics: Arc<Metrics>,
+    stopped: Arc<AtomicBool>,
+
+    /// Tells `Client::wait` that the client stopped, with the error which stopped it if any.
+    done: Sender<Result<()>>,
+}
+
+impl Shared {
+    fn is_stopped(&self) -> bool {
+        self.stopped.load(Ordering::SeqCst)
+    }
+
+    /// Spawns a thread which runs `f` until it fails, and then lets `Client::wait` return.
+    fn spawn_worker<F>(&self, f: F) -> JoinHandle<()>
+    where
+        F: FnOnce() -> Result<()> + Send + 'static,
+    {
+        let done = self.done.clone();
+        std::thread::spawn(move || {
+            if let Err(err) = f() {
+                log::error!("client: {}", err);
+                let _ = done.send(Err(err));
+            }
+        })
+    }
+}
+
+/// Sends `Hello` messages until the server replies. Returns `None` if the client is stopped.
+fn handshake(shared: &Shared, channel: &mut Channel) -> Option<PeerSettings> {
+    let interval = Duration::from_secs(HANDSHAKE_RETRY_INTERVAL);
+    loop {
+        if let Err(err) = send_hello(channel, shared) {
+            print_error("send hello", err);
+        }
+        let deadline = Instant::now() + interval;
+        while Instant::now() < deadline {
+            if shared.is_stopped() {
+                return None;
+            }
+            match channel.recv() {
+                Ok(Message::HelloReply { seed, settings }) => {
+                    let result =
+                        accept_hello_reply(&shared.state, &shared.server_pubkey, seed, settings);
+                    count_handshake(&shared.metrics, &result);
+                    match result {
+                        Ok(settings) => return Some(settings),
+                        Err(err) => print_error("handshake", err),
                     }
                 }
+                Ok(_) => log::debug!("ignored a message before the handshake"),
+                Err(Error::Io(err)) if is_timeout(&err) => continue,
+                // not a reply yet: anyone can send a datagram
+                Err(Error::BrokenMessage) => {
+                    shared.metrics.malformed_datagram();
+                    print_error("channel.recv", Error::BrokenMessage);
+                }
+                Err(err) => print_error("channel.recv", err),
             }
         }
+        log::warn!("no reply from the server, retrying");
+    }
+}
+
+/// Establishes a connection, sets up the interface, and sends packets from it to the server.
+fn connect(
+    shared: Shared,
+    mut channel: Channel,
+    iface: Option<Arc<dyn VirtualInterface>>,
+    teardown: Arc<Mutex<Teardown>>,
+) -> Result<()> {
+    let config = shared.config.clone();
+    let settings = match handshake(&shared, &mut channel) {
+        Some(settings) => settings,
+        None => return Ok(()),
+    };
+
+    // Apply the settings pushed by the server
+    let iface = match iface {
+        Some(iface) => iface,
+        None => {
+            let iface = setup_tun(
+                &config.peer.ifname,
+                settings.address,
+                settings.prefix_len,
+                settings.mtu,
+            )?;
+            for &prefix in config.peer.routes.iter().chain(settings.routes.iter()) {
+                add_route(&config.peer.ifname, prefix)?;
+            }
+            if config.peer.full_tunnel {
+                // Two halves are more specific than the original default route, which is left untouched.
+                for half in ["0.0.0.0/1", "128.0.0.0/1"] {
+                    add_route(&config.peer.ifname, half.parse()?)?;
+                    let ifname = config.peer.ifname.as_str();
+                    teardown
+                        .lock()
+                        .expect("poisoned")
+                        .push("ip", &["route", "del", half, "dev", ifname]);
+                }
+            }
+            if !settings.dns.is_empty() {
+                apply_dns(&settings.dns, &mut teardown.lock().expect("poisoned"))?;
+            }
+            Arc::new(iface)
+        }
+    };
+
+    let interface_env: hook::Env = vec![
+        ("VPN_INTERFACE", config.peer.ifname.clone()),
+        ("VPN_ADDRESS", settings.address.to_string()),
+    ];
+    if let Some(script) = &config.peer.on_up {
+        hook::run_hook(script, &interface_env)?;
+    }
+    if let Some(script) = &config.peer.on_down {
+        let envs: Vec<(&str, &str)> = interface_env
+            .iter()
+            .map(|(k, v)| (*k, v.as_str()))
+            .collect();
+        let mut teardown = teardown.lock().expect("poisoned");
+        teardown.push_with_env("sh", &["-c", script], &envs);
+    }
+    let mut peer_env = interface_env;
+    peer_env.push(("VPN_PEER_PUBLIC_KEY", crypto::to_hex(&shared.server_pubkey)));
+    peer_env.push(("VPN_PEER_ENDPOINT", channel.peer_addr()?.to_string()));
+    let hooks = Hooks::new();
+    hooks.spawn(config.peer.on_peer_connect.as_deref(), peer_env.clone());
+
+    std::thread::spawn({
+        let shared = shared.clone();
+        let mut channel = channel.clone();
+        let hooks = hooks.clone();
+        let peer_env = peer_env.clone();
+        move || keepalive(&shared, &mut channel, settings.keepalive, &hooks, &peer_env)
+    });
+
+    shared.spawn_worker({
+        let shared = shared.clone();
+        let iface = iface.clone();
+        let channel = channel.clone();
+        move || receive(&shared, channel, iface, settings, &hooks, &peer_env)
     });
 
+    let metrics = &shared.metrics;
     let mut buf = [0; 4096];
     loop {
         let nb = iface.recv(&mut buf[..])?;
+        if shared.is_stopped() {
+            return Ok(());
+        }
         let packet = &buf[..nb];
 
         let (ip_hdr, _payload) = match Ipv4Header::from_slice(packet) {
@@ -251,12 +753,304 @@ fn main() -> Result<()> {
             destination,
             content: Vec::new(),
         };
-        let mut key = session_key.lock().expect("poisoned");
+        let mut state = shared.state.lock().expect("poisoned");
+        let key = match state.session_key.as_mut() {
+            Some(key) => key,
+            None => {
+                log::debug!("no session, dropped a packet");
+                metrics.dropped(DropReason::NoSession);
+                continue;
+            }
+        };
         let aad = sealed_packet.addresses_as_bytes();
-        sealed_packet.content = key.seal(&aad, packet.to_vec()).expect("Failed to encrypt");
+        sealed_packet.content = key.seal(aad, packet.to_vec()).expect("Failed to encrypt");
+
+        let endpoint = state.status.endpoint;
+        capture::record(&mut state.capture, Direction::TunToPeer, endpoint, packet);
 
         if let Err(err) = channel.send(&Message::Packet(sealed_packet)) {
             print_error("channel.send", err);
+            continue;
+        }
+        state.status.traffic.tx(packet.len());
+    }
+}
+
+/// Sends heart beats to keep the connection, and re-establishes it if the server stops replying.
+fn keepalive(
+    shared: &Shared,
+    channel: &mut Channel,
+    interval: u64,
+    hooks: &Hooks,
+    peer_env: &hook::Env,
+) {
+    // a broken server may push 0, which would spin the loop and drop the session every beat
+    let freq = Duration::from_secs(interval.max(1));
+    loop {
+        std::thread::sleep(freq);
+        if shared.is_stopped() {
+            return;
+        }
+
+        let mut locked = shared.state.lock().expect("poisoned");
+        let timeout = freq * SESSION_TIMEOUT_BEATS;
+        if locked.session_key.is_some() && locked.last_heard.elapsed() > timeout {
+            log::warn!("no heart beat from the server, reconnecting");
+            // the server has probably dropped the session as well
+            locked.session_key = None;
+            locked.status.state = ConnectionState::Reconnecting;
+            locked.events.emit(Event::SessionExpired {
+                fingerprint: Fingerprint::of(&shared.server_pubkey),
+                endpoint: locked.status.endpoint,
+                address: locked.status.address.unwrap_or(Ipv4Addr::UNSPECIFIED),
+                idle_secs: locked.last_heard.elapsed().as_secs(),
+            });
+            let hook = shared.config.peer.on_peer_disconnect.as_deref();
+            hooks.spawn(hook, peer_env.clone());
+        }
+
+        if locked.status.state == ConnectionState::Reconnecting {
+            drop(locked);
+            if let Err(err) = send_hello(channel, shared) {
+                print_error("send hello", err);
+            }
+        } else {
+            locked.heartbeat_sent = Some(Instant::now());
+            drop(locked);
+            if let Err(err) = channel.send(&Message::HeartBeat) {
+                print_error("heart beat", err);
+            }
+        }
+    }
+}
+
+/// Handles messages from the server.
+fn receive(
+    shared: &Shared,
+    mut channel: Channel,
+    iface: Arc<dyn VirtualInterface>,
+    settings: PeerSettings,
+    hooks: &Hooks,
+    peer_env: &hook::Env,
+) -> Result<()> {
+    loop {
+        if shared.is_stopped() {
+            return Ok(());
+        }
+        let msg = match channel.recv() {
+            Err(Error::Io(err)) if is_timeout(&err) => continue,
+            Err(err) => {
+                if let Error::BrokenMessage = err {
+                    shared.metrics.malformed_datagram();
+                }
+                print_error("channel.recv", err);
+                continue;
+            }
+            Ok(msg) => msg,
+        };
+
+        match msg {
+            Message::Packet(sealed_packet) => {
+                let mut state = shared.state.lock().expect("poisoned");
+                let packet: Vec<u8> = {
+                    let key = match state.session_key.as_mut() {
+                        Some(key) => key,
+                        None => {
+                            log::debug!("no session, dropped a packet");
+                            shared.metrics.dropped(DropReason::NoSession);
+                            continue;
+                        }
+                    };
+                    let aad = sealed_packet.addresses_as_bytes();
+                    let mut content = sealed_packet.content;
+                    match key.unseal(aad, &mut content) {
+                        Ok(p) => p,
+                        Err(Error::Replayed) => {
+                            log::debug!("dropped a replayed packet");
+                            shared.metrics.dropped(DropReason::Replayed);
+                            continue;
+                        }
+                        Err(err) => {
+                            print_error("unseal", err);
+                            shared.metrics.dropped(DropReason::Unseal);
+                            continue;
+                        }
+                    }
+                };
+
+                let (ip_hdr, _payload) = match Ipv4Header::from_slice(&packet) {
+                    Ok(hdr_payload) => hdr_payload,
+                    Err(err) => {
+                        log::debug!("ignored uninteresting packet: {}", err);
+                        continue;
+                    }
+                };
+                log::debug!(
+                    "receive {} bytes: {:?} --> {:?}",
+                    packet.len(),
+                    Ipv4Addr::from(ip_hdr.source),
+                    Ipv4Addr::from(ip_hdr.destination),
+                );
+
+                let endpoint = state.status.endpoint;
+                capture::record(&mut state.capture, Direction::PeerToTun, endpoint, &packet);
+                iface.send(&packet)?;
+                state.status.traffic.rx(packet.len());
+            }
+
+            Message::HeartBeat => {
+                log::trace!("HeartBeat from the server");
+                let mut state = shared.state.lock().expect("poisoned");
+                state.last_heard = Instant::now();
+                if let Some(sent) = state.heartbeat_sent.take() {
+                    state.status.rtt_ms = Some(sent.elapsed().as_secs_f64() * 1000.0);
+                }
+            }
+
+            Message::HelloReply {
+                seed,
+                settings: sealed_settings,
+            } => {
+                let lost = shared.state.lock().expect("poisoned").session_key.is_none();
+                let result =
+                    accept_hello_reply(&shared.state, &shared.server_pubkey, seed, sealed_settings);
+                count_handshake(&shared.metrics, &result);
+                match result {
+                    Ok(new_settings) => {
+                        if lost {
+                            hooks.spawn(
+                                shared.config.peer.on_peer_connect.as_deref(),
+                                peer_env.clone(),
+                            );
+                        }
+                        if new_settings != settings {
+                            log::warn!("settings changed, restart to apply them");
+                        }
+                    }
+                    Err(err) => print_error("handshake", err),
+                }
+            }
+
+            _ => {
+                log::error!("unexpected message");
+            }
+        }
+    }
+}
+
+/// A running client.
+///
+/// It is stopped by `stop` or when dropped. The tun device is closed when the process exits.
+pub struct Client {
+    shared: Shared,
+    /// Used to send `Hello` messages on request.
+    channel: Mutex<Channel>,
+    teardown: Arc<Mutex<Teardown>>,
+    finished: Mutex<Receiver<Result<()>>>,
+}
+
+impl Client {
+    /// Makes a builder of a client with the configuration.
+    pub fn builder(config: Config) -> ClientBuilder {
+        ClientBuilder {
+            config,
+            key_pair: None,
+            server_public_key: None,
+            certificate: None,
+            iface: None,
+            teardown: None,
+            callbacks: Vec::new(),
         }
     }
+
+    /// Returns the current status of the connection.
+    pub fn status(&self) -> Status {
+        self.shared.state.lock().expect("poisoned").status.clone()
+    }
+
+    /// Starts a new handshake. The current session is used until the server replies.
+    pub fn reconnect(&self) -> Result<()> {
+        let mut channel = self.channel.lock().expect("poisoned");
+        send_hello(&mut channel, &self.shared)
+    }
+
+    /// Serves a request from the control socket.
+    pub fn handle_request(&self, request: Request) -> Response {
+        let state = &self.shared.state;
+        match request {
+            Request::Status => Response::ok(self.status()),
+            Request::Reconnect => {
+                log::info!("control: reconnect");
+                self.reconnect().map_or_else(Response::error, Response::ok)
+            }
+            Request::Shutdown => {
+                log::info!("control: shutdown");
+                self.stop();
+                Response::ok(())
+            }
+            Request::CaptureStart { path } => {
+                match Capture::create(&path, &self.shared.config.peer.ifname) {
+                    Ok(capture) => {
+                        state.lock().expect("poisoned").capture = Some(capture);
+                        Response::ok(path)
+                    }
+                    Err(err) => Response::error(err),
+                }
+            }
+            Request::CaptureStop => match state.lock().expect("poisoned").capture.take() {
+                Some(capture) => {
+                    log::info!("capture stopped: {:?}", capture.path());
+                    Response::ok(capture.path())
+                }
+                None => Response::error("not capturing"),
+            },
+            _ => Response::error("unsupported command"),
+        }
+    }
+
+    /// Renders the metrics in the Prometheus text format.
+    pub fn metrics(&self) -> String {
+        let mut out = Exposition::new();
+        self.shared.metrics.write_to(&mut out);
+
+        let state = self.shared.state.lock().expect("poisoned");
+        let connected = state.status.state == ConnectionState::Connected;
+        out.family(
+            "active_sessions",
+            "gauge",
+            "Whether the session is established.",
+        );
+        out.sample("active_sessions", &[], connected as u8);
+        let peers = [(state.status.endpoint.to_string(), state.status.traffic)];
+        out.peer_traffic(&peers);
+        out.into_string()
+    }
+
+    /// Stops the connection and reverts changes made to the host.
+    pub fn stop(&self) {
+        if self.shared.stopped.swap(true, Ordering::SeqCst) {
+            return;
+        }
+        self.teardown.lock().expect("poisoned").run();
+        let _ = self.shared.done.send(Ok(()));
+        log::info!("client stopped");
+    }
+
+    /// Blocks until the client is stopped, either by `stop` or by an error of the interface.
+    pub fn wait(&self) -> Result<()> {
+        let result = self
+            .finished
+            .lock()
+            .expect("poisoned")
+            .recv()
+            .unwrap_or(Ok(()));
+        self.stop();
+        result
+    }
+}
+
+impl Drop for Client {
+    fn drop(&mut self) {
+        self.stop();
+    }
 }
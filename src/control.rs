@@ -0,0 +1,202 @@
+//! A local control interface over a Unix domain socket.
+//!
+//! A client connects to the socket, writes a `Request` as a line of JSON,
+//! and reads a `Response` as a line of JSON. The connection is closed after the response.
+
+use serde::{Deserialize, Serialize};
+use std::io::{BufRead, BufReader, Read, Write};
+use std::net::Ipv4Addr;
+use std::os::unix::fs::{FileTypeExt, PermissionsExt};
+use std::os::unix::net::{UnixListener, UnixStream};
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+use crate::crypto::Fingerprint;
+use crate::error::{Error, Result};
+use crate::route::Ipv4Cidr;
+
+/// The default path of the control socket of the server.
+pub const SERVER_SOCKET: &str = "/run/poor-mans-vpn-server.sock";
+
+/// The default path of the control socket of the client.
+pub const CLIENT_SOCKET: &str = "/run/poor-mans-vpn-client.sock";
+
+/// Requests are served one by one, so a silent client is disconnected after this period.
+const READ_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// The maximum length of a request line.
+const MAX_REQUEST_LEN: u64 = 64 * 1024;
+
+/// Identifies a peer by its address or the fingerprint of its public key.
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum PeerId {
+    Address(Ipv4Addr),
+    Fingerprint(Fingerprint),
+}
+
+impl std::str::FromStr for PeerId {
+    type Err = Error;
+    fn from_str(s: &str) -> Result<Self> {
+        match s.parse() {
+            Ok(addr) => Ok(PeerId::Address(addr)),
+            Err(_) => Ok(PeerId::Fingerprint(s.parse()?)),
+        }
+    }
+}
+
+/// A command sent to the control socket.
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(tag = "command", rename_all = "snake_case")]
+pub enum Request {
+    /// Lists the registered peers.
+    ListPeers,
+
+    /// Shows the details of a peer.
+    ShowPeer { peer: PeerId },
+
+    /// Drops the session with a peer. The peer can connect again.
+    KickPeer { peer: PeerId },
+
+    /// Registers a peer until the server exits.
+    AddPeer {
+        /// The hex-encoded public key.
+        public_key: String,
+        address: Option<Ipv4Addr>,
+        #[serde(default)]
+        allowed_ips: Vec<Ipv4Cidr>,
+    },
+
+    /// Unregisters a peer and drops its session.
+    RemovePeer { peer: PeerId },
+
+    /// Shows the statistics.
+    Stats,
+
+    /// Reloads the configuration file.
+    Reload,
+
+    /// Shows the status of the client.
+    Status,
+
+    /// Makes the client establish a new session.
+    Reconnect,
+
+    /// Makes the client exit.
+    Shutdown,
+
+    /// Starts capturing decrypted packets to a pcapng file.
+    CaptureStart { path: PathBuf },
+
+    /// Stops capturing.
+    CaptureStop,
+}
+
+/// A reply from the control socket.
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(tag = "status", rename_all = "snake_case")]
+pub enum Response {
+    Ok { result: serde_json::Value },
+    Error { message: String },
+}
+
+impl Response {
+    /// Makes a successful response with a serializable result.
+    pub fn ok<T: Serialize>(result: T) -> Self {
+        let result = serde_json::to_value(result).expect("serialize");
+        Response::Ok { result }
+    }
+
+    pub fn error<D: std::fmt::Display>(message: D) -> Self {
+        Response::Error {
+            message: message.to_string(),
+        }
+    }
+}
+
+/// Listens on the socket at the given path, and spawns a thread which serves requests with `handler`.
+/// The socket is accessible only by the owner.
+pub fn serve<P, F>(path: P, handler: F) -> Result<()>
+where
+    P: AsRef<Path>,
+    F: Fn(Request) -> Response + Send + 'static,
+{
+    let path = path.as_ref();
+    // remove the socket left by the previous run, but nothing else
+    match std::fs::symlink_metadata(path) {
+        Ok(metadata) if metadata.file_type().is_socket() => std::fs::remove_file(path)?,
+        Ok(_) => {
+            let msg = format!("{:?} exists and is not a socket", path);
+            return Err(std::io::Error::new(std::io::ErrorKind::AlreadyExists, msg).into());
+        }
+        Err(err) if err.kind() == std::io::ErrorKind::NotFound => {}
+        Err(err) => return Err(err.into()),
+    }
+    let listener = bind_private(path)?;
+    log::info!("control socket: {:?}", path);
+
+    std::thread::spawn(move || {
+        for stream in listener.incoming() {
+            let result = stream
+                .map_err(Error::from)
+                .and_then(|stream| serve_one(stream, &handler));
+            if let Err(err) = result {
+                log::error!("control: {}", err);
+            }
+        }
+    });
+    Ok(())
+}
+
+/// Binds a socket accessible only by the owner.
+/// The socket is bound in a private directory and then moved to `path`,
+/// so no other user can connect before its permissions are restricted.
+fn bind_private(path: &Path) -> Result<UnixListener> {
+    use std::os::unix::fs::DirBuilderExt;
+    let mut dir = path.as_os_str().to_owned();
+    dir.push(format!(".{}", std::process::id()));
+    let dir = PathBuf::from(dir);
+    std::fs::DirBuilder::new().mode(0o700).create(&dir)?;
+
+    let tmp_path = dir.join("sock");
+    let result = UnixListener::bind(&tmp_path).and_then(|listener| {
+        std::fs::set_permissions(&tmp_path, std::fs::Permissions::from_mode(0o600))?;
+        std::fs::rename(&tmp_path, path)?;
+        Ok(listener)
+    });
+    let _ = std::fs::remove_file(&tmp_path);
+    std::fs::remove_dir(&dir)?;
+    Ok(result?)
+}
+
+fn serve_one<F: Fn(Request) -> Response>(mut stream: UnixStream, handler: &F) -> Result<()> {
+    stream.set_read_timeout(Some(READ_TIMEOUT))?;
+    stream.set_write_timeout(Some(READ_TIMEOUT))?;
+    let mut line = String::new();
+    BufReader::new((&stream).take(MAX_REQUEST_LEN)).read_line(&mut line)?;
+
+    let response = match serde_json::from_str(&line) {
+        Ok(request) => {
+            log::debug!("control: {:?}", request);
+            handler(request)
+        }
+        Err(err) => Response::error(format!("invalid request: {}", err)),
+    };
+
+    let mut json = serde_json::to_string(&response).expect("serialize");
+    json.push('\n');
+    stream.write_all(json.as_bytes())?;
+    Ok(())
+}
+
+/// Sends a request to the socket at the given path and waits for the response.
+pub fn request<P: AsRef<Path>>(path: P, request: &Request) -> Result<Response> {
+    let mut stream = UnixStream::connect(path)?;
+    let mut json = serde_json::to_string(request).expect("serialize");
+    json.push('\n');
+    stream.write_all(json.as_bytes())?;
+
+    let mut line = String::new();
+    BufReader::new(&stream).read_line(&mut line)?;
+    serde_json::from_str(&line).map_err(|_| Error::BrokenMessage)
+}
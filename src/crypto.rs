@@ -1,19 +1,224 @@
 use ring::error::Unspecified;
-use ring::{aead, agreement, pbkdf2, rand, signature};
+use ring::{aead, agreement, digest, pbkdf2, rand, signature};
 use serde::{de::DeserializeOwned, Deserialize, Serialize};
 
 use crate::error::{Error, Result};
 
+/// Returns a hex representation of bytes (e.g. a public key).
+pub fn to_hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+/// Decodes a hex representation of bytes. Returns `None` if it is malformed.
+pub fn from_hex(s: &str) -> Option<Vec<u8>> {
+    if !s.len().is_multiple_of(2) || !s.is_ascii() {
+        return None;
+    }
+    (0..s.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(&s[i..i + 2], 16).ok())
+        .collect()
+}
+
+const BASE64_ALPHABET: &[u8; 64] =
+    b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789+/";
+
+/// Returns a base64 representation of bytes (the standard alphabet with padding).
+pub fn to_base64(bytes: &[u8]) -> String {
+    let mut s = String::with_capacity(bytes.len().div_ceil(3) * 4);
+    for chunk in bytes.chunks(3) {
+        let n = chunk
+            .iter()
+            .enumerate()
+            .fold(0u32, |n, (i, &b)| n | (b as u32) << (16 - 8 * i));
+        for i in 0..4 {
+            if i <= chunk.len() {
+                s.push(BASE64_ALPHABET[(n >> (18 - 6 * i) & 0x3f) as usize] as char);
+            } else {
+                s.push('=');
+            }
+        }
+    }
+    s
+}
+
+/// Decodes a base64 representation of bytes (the standard alphabet with padding).
+/// Returns `None` if it is malformed.
+pub fn from_base64(s: &str) -> Option<Vec<u8>> {
+    let s = s.as_bytes();
+    if !s.len().is_multiple_of(4) {
+        return None;
+    }
+    let mut bytes = Vec::with_capacity(s.len() / 4 * 3);
+    for (i, chunk) in s.chunks(4).enumerate() {
+        let last = i == s.len() / 4 - 1;
+        let padding = chunk.iter().rev().take_while(|&&c| c == b'=').count();
+        if padding > 2 || (padding > 0 && !last) {
+            return None;
+        }
+        let mut n = 0u32;
+        for &c in &chunk[..4 - padding] {
+            let value = BASE64_ALPHABET.iter().position(|&a| a == c)? as u32;
+            n = n << 6 | value;
+        }
+        n <<= 6 * padding as u32;
+        // the bits beyond the last byte must be zero, so that bytes have only one representation
+        if n & ((1 << (8 * padding)) - 1) != 0 {
+            return None;
+        }
+        bytes.extend_from_slice(&n.to_be_bytes()[1..4 - padding]);
+    }
+    Some(bytes)
+}
+
+/// Returns how many bytes `s` would decode to if it looks like base64,
+/// tolerating a wrong length (e.g. a character lost in copying).
+fn base64_decoded_len(s: &str) -> Option<usize> {
+    let data = s.trim_end_matches('=');
+    if data.is_empty() || !data.bytes().all(|c| BASE64_ALPHABET.contains(&c)) {
+        return None;
+    }
+    Some(data.len() * 6 / 8)
+}
+
+/// The length of an Ed25519 public key.
+pub const PUBLIC_KEY_LEN: usize = 32;
+
+/// The length of an Ed25519 signature.
+pub const SIGNATURE_LEN: usize = 64;
+
+/// The length of an uncompressed P-384 public key of a session seed.
+pub const SEED_PUBLIC_KEY_LEN: usize = 97;
+
+/// A fingerprint (SHA-256 digest) of a public key.
+/// It identifies a peer without revealing its public key.
+///
+/// It is serialized as a hex string in human-readable formats (e.g. JSON), or as raw bytes otherwise.
+#[derive(Clone, Copy, PartialEq, Eq, Hash)]
+pub struct Fingerprint([u8; 32]);
+
+impl Fingerprint {
+    /// Calculates the fingerprint of the given public key.
+    pub fn of(pubkey: &[u8]) -> Self {
+        let digest = digest::digest(&digest::SHA256, pubkey);
+        Self(digest.as_ref().try_into().expect("digest len"))
+    }
+
+    pub fn as_bytes(&self) -> &[u8] {
+        &self.0
+    }
+}
+
+impl std::fmt::Display for Fingerprint {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(f, "{}", to_hex(&self.0))
+    }
+}
+
+impl std::str::FromStr for Fingerprint {
+    type Err = Error;
+    fn from_str(s: &str) -> Result<Self> {
+        let bytes = from_hex(s).and_then(|bytes| bytes.try_into().ok());
+        bytes.map(Self).ok_or_else(|| Error::InvalidFingerprint {
+            fingerprint: s.to_owned(),
+        })
+    }
+}
+
+impl Serialize for Fingerprint {
+    fn serialize<S: serde::Serializer>(
+        &self,
+        serializer: S,
+    ) -> std::result::Result<S::Ok, S::Error> {
+        if serializer.is_human_readable() {
+            serializer.serialize_str(&self.to_string())
+        } else {
+            self.0.serialize(serializer)
+        }
+    }
+}
+
+impl<'de> Deserialize<'de> for Fingerprint {
+    fn deserialize<D: serde::Deserializer<'de>>(
+        deserializer: D,
+    ) -> std::result::Result<Self, D::Error> {
+        if deserializer.is_human_readable() {
+            let s = String::deserialize(deserializer)?;
+            s.parse().map_err(serde::de::Error::custom)
+        } else {
+            <[u8; 32]>::deserialize(deserializer).map(Self)
+        }
+    }
+}
+
+impl std::fmt::Debug for Fingerprint {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(f, "Fingerprint({})", self)
+    }
+}
+
+/// A public key in a configuration: the key itself in base64 (as `vpn-keygen public` prints),
+/// or a path to a file of the raw 32 bytes.
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(from = "String")]
+pub enum PublicKeySource {
+    Inline(Vec<u8>),
+    File(std::path::PathBuf),
+}
+
+impl From<String> for PublicKeySource {
+    /// Takes a string as an inline key if it is 32 bytes in base64, or as a path otherwise.
+    fn from(s: String) -> Self {
+        match from_base64(&s) {
+            Some(key) if key.len() == PUBLIC_KEY_LEN => Self::Inline(key),
+            _ => Self::File(s.into()),
+        }
+    }
+}
+
+impl PublicKeySource {
+    /// Returns the key, reading the file if needed.
+    pub fn load(&self) -> Result<Vec<u8>> {
+        let key = match self {
+            Self::Inline(key) => key.clone(),
+            Self::File(path) => match std::fs::read(path) {
+                Ok(key) => key,
+                // probably a mistyped inline key rather than a missing file
+                Err(err) if err.kind() == std::io::ErrorKind::NotFound => {
+                    match path.to_str().and_then(base64_decoded_len) {
+                        Some(len) => return Err(Error::InvalidBase64Key { len }),
+                        None => return Err(err.into()),
+                    }
+                }
+                Err(err) => return Err(err.into()),
+            },
+        };
+        if key.len() != PUBLIC_KEY_LEN {
+            return Err(Error::InvalidPublicKey);
+        }
+        Ok(key)
+    }
+}
+
+impl std::fmt::Display for PublicKeySource {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            Self::Inline(key) => write!(f, "{}", to_base64(key)),
+            Self::File(path) => write!(f, "{}", path.display()),
+        }
+    }
+}
+
 /// A staticaly generated pair of (ED25519) keys.
 ///
 /// Each peer, belonging to the VPN, has to generate a pair of keys
 /// and share it with the server securely (in advance).
 ///
-/// To generate a private key, `genkey.sh` script can be used:
-/// $ ./genkey.sh > privkey.der
+/// To generate a private key and its public key, `vpn-keygen` can be used:
+/// $ vpn-keygen generate privkey.der pubkey.der
 ///
-/// To derive a public key from a private key, `pubkey.sh` script can be used:
-/// $ ./pubkey.sh < privkey.der > pubkey.der
+/// To derive a public key from a private key:
+/// $ vpn-keygen public privkey.der pubkey.der
 #[derive(Debug)]
 pub struct StaticKeyPair {
     key_pair: signature::Ed25519KeyPair,
@@ -22,15 +227,27 @@ pub struct StaticKeyPair {
 impl StaticKeyPair {
     /// Opens the given file and reads a private key from it.
     /// The content must be in PKCS#8 v1 (or v2) format.
-    /// To generate a private key, `genkey.sh` can be used.
-    /// $ ./genkey.sh > privkey.der
+    /// To generate a private key, `vpn-keygen` can be used.
+    /// $ vpn-keygen generate privkey.der
     pub fn from_pkcs8<P: AsRef<std::path::Path>>(path: P) -> Result<Self> {
         let keyfile = std::fs::read(path)?;
-        let key_pair = signature::Ed25519KeyPair::from_pkcs8_maybe_unchecked(&keyfile)
+        Self::from_pkcs8_bytes(&keyfile)
+    }
+
+    /// Reads a private key in PKCS#8 v1 (or v2) format.
+    pub fn from_pkcs8_bytes(pkcs8: &[u8]) -> Result<Self> {
+        let key_pair = signature::Ed25519KeyPair::from_pkcs8_maybe_unchecked(pkcs8)
             .map_err(|_| Error::InvalidPrivateKeyFormat)?;
         Ok(Self { key_pair })
     }
 
+    /// Generates a new private key in PKCS#8 v2 format.
+    pub fn generate_pkcs8() -> Vec<u8> {
+        let rng = rand::SystemRandom::new();
+        let pkcs8 = signature::Ed25519KeyPair::generate_pkcs8(&rng).expect("generate a key pair");
+        pkcs8.as_ref().to_vec()
+    }
+
     /// Returns a public key of the pair.
     pub fn public_key(&self) -> Vec<u8> {
         use signature::KeyPair;
@@ -40,7 +257,12 @@ impl StaticKeyPair {
     /// Signs a given data.
     pub fn sign<T: Serialize>(&self, val: &T) -> Signed<T> {
         let data = bincode::serialize(val).expect("serialize");
-        let signature = self.key_pair.sign(&data).as_ref().to_vec();
+        let signature = self
+            .key_pair
+            .sign(&data)
+            .as_ref()
+            .try_into()
+            .expect("signature len");
         Signed {
             data,
             signature,
@@ -50,14 +272,26 @@ impl StaticKeyPair {
 }
 
 /// A bytes with signature generated by `StaticKeyPair::sign`.
-#[derive(Debug, PartialEq, Serialize, Deserialize)]
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
 pub struct Signed<T> {
+    #[serde(with = "crate::bytes::vec")]
     data: Vec<u8>,
-    signature: Vec<u8>,
+    #[serde(with = "crate::bytes::array")]
+    signature: [u8; SIGNATURE_LEN],
     _phantom: std::marker::PhantomData<T>,
 }
 
 impl<T> Signed<T> {
+    /// Returns the signature (for inspection).
+    pub fn signature(&self) -> &[u8] {
+        &self.signature
+    }
+
+    /// Returns the signed data serialized with bincode (for inspection).
+    pub fn data(&self) -> &[u8] {
+        &self.data
+    }
+
     /// Verifies its content with the given public key.
     /// Returns Ok(()) if it was signed by the private one corresponding to the given key.
     pub fn verify(&self, pubkey: &[u8]) -> Result<()> {
@@ -72,8 +306,7 @@ impl<T: DeserializeOwned> Signed<T> {
     /// Verifies and deserialize its content.
     pub fn open(self, pubkey: &[u8]) -> Result<T> {
         self.verify(pubkey)?;
-        let val: T = bincode::deserialize(&self.data).map_err(|_| Error::BrokenMessage)?;
-        Ok(val)
+        crate::decode(&self.data)
     }
 }
 
@@ -89,8 +322,20 @@ pub struct PrivSeed {
 /// It is used to establish a session key between 2 peers.
 #[derive(Debug, PartialEq, Serialize, Deserialize)]
 pub struct PubSeed {
-    pubkey1: Vec<u8>,
-    pubkey2: Vec<u8>,
+    #[serde(with = "crate::bytes::array")]
+    pubkey1: [u8; SEED_PUBLIC_KEY_LEN],
+    #[serde(with = "crate::bytes::array")]
+    pubkey2: [u8; SEED_PUBLIC_KEY_LEN],
+
+    /// When the seed was generated (microseconds since the UNIX epoch).
+    /// It is signed with the seed, so that an old `Hello` cannot be replayed.
+    created_at: u64,
+}
+
+impl PubSeed {
+    pub fn created_at(&self) -> u64 {
+        self.created_at
+    }
 }
 
 /// Generates a pair of session seeds.
@@ -106,13 +351,55 @@ pub fn generate_seed_pair() -> (PrivSeed, PubSeed) {
 
     let privseed = PrivSeed { privkey1, privkey2 };
     let pubseed = PubSeed {
-        pubkey1: pubkey1.as_ref().to_vec(),
-        pubkey2: pubkey2.as_ref().to_vec(),
+        pubkey1: pubkey1.as_ref().try_into().expect("public key len"),
+        pubkey2: pubkey2.as_ref().try_into().expect("public key len"),
+        created_at: std::time::SystemTime::now()
+            .duration_since(std::time::UNIX_EPOCH)
+            .map(|d| d.as_micros() as u64)
+            .unwrap_or(0),
     };
 
     (privseed, pubseed)
 }
 
+/// The seed of a `HelloReply`, bound to the `Hello` it replies to and to the sealed settings.
+/// The client checks the binding before it spends its private seed on the reply,
+/// so a replayed or corrupted reply does not end the handshake in progress.
+#[derive(Debug, PartialEq, Serialize, Deserialize)]
+pub struct ReplySeed {
+    seed: PubSeed,
+    /// `created_at` of the seed of the `Hello`.
+    hello_created_at: u64,
+    /// A SHA-256 digest of the sealed settings.
+    #[serde(with = "crate::bytes::array")]
+    settings_digest: [u8; 32],
+}
+
+impl ReplySeed {
+    pub fn new(seed: PubSeed, hello_created_at: u64, sealed_settings: &[u8]) -> Self {
+        Self {
+            seed,
+            hello_created_at,
+            settings_digest: Self::digest(sealed_settings),
+        }
+    }
+
+    /// Returns the seed if the reply belongs to the `Hello` and the settings.
+    pub fn bound_to(self, hello_created_at: u64, sealed_settings: &[u8]) -> Result<PubSeed> {
+        if self.hello_created_at != hello_created_at
+            || self.settings_digest != Self::digest(sealed_settings)
+        {
+            return Err(Error::BrokenMessage);
+        }
+        Ok(self.seed)
+    }
+
+    fn digest(sealed_settings: &[u8]) -> [u8; 32] {
+        let digest = digest::digest(&digest::SHA256, sealed_settings);
+        digest.as_ref().try_into().expect("digest len")
+    }
+}
+
 /// A nonce generator implements `ring::aead::NonceSequence`.
 pub struct NonceSeq {
     id: u8,
@@ -143,61 +430,78 @@ pub struct SessionKey {
     opening: aead::LessSafeKey,
     sealing: aead::LessSafeKey,
     nonce_seq: NonceSeq,
+    replay_window: ReplayWindow,
+
+    /// The raw keys from the client and from the server, kept only to be written to a key log.
+    #[cfg(feature = "keylog")]
+    secrets: (Vec<u8>, Vec<u8>),
 }
 
 impl SessionKey {
     fn derive(
         privkey: agreement::EphemeralPrivateKey,
-        pubkey: agreement::UnparsedPublicKey<Vec<u8>>,
-    ) -> aead::UnboundKey {
-        agreement::agree_ephemeral(privkey, &pubkey, (), |material| {
+        pubkey: agreement::UnparsedPublicKey<[u8; SEED_PUBLIC_KEY_LEN]>,
+    ) -> Result<(aead::UnboundKey, Vec<u8>)> {
+        // the public key comes from the other peer, and may not be a valid point
+        agreement::agree_ephemeral(privkey, &pubkey, Error::BrokenMessage, |material| {
             let algo = &aead::CHACHA20_POLY1305;
             let mut key_bytes = vec![0; algo.key_len()];
             let pbkdf2 = pbkdf2::PBKDF2_HMAC_SHA256;
             let iter = std::num::NonZeroU32::new(100000).unwrap();
             pbkdf2::derive(pbkdf2, iter, &[], material, &mut key_bytes);
-            let key = aead::UnboundKey::new(algo, &key_bytes).map_err(|_| ())?;
-            Ok(key)
+            let key = aead::UnboundKey::new(algo, &key_bytes).map_err(|_| Error::BrokenMessage)?;
+            Ok((key, key_bytes))
         })
-        .expect("agreement")
     }
 
     /// Derives a session key for clients.
-    pub fn client_derive(privseed: PrivSeed, pubseed: PubSeed) -> Self {
+    pub fn client_derive(privseed: PrivSeed, pubseed: PubSeed) -> Result<Self> {
         let privkey = privseed.privkey1;
         let pubkey = agreement::UnparsedPublicKey::new(&agreement::ECDH_P384, pubseed.pubkey1);
-        let ubkey = Self::derive(privkey, pubkey);
+        let (ubkey, _client_secret) = Self::derive(privkey, pubkey)?;
         let sealing_key = aead::LessSafeKey::new(ubkey);
 
         let privkey = privseed.privkey2;
         let pubkey = agreement::UnparsedPublicKey::new(&agreement::ECDH_P384, pubseed.pubkey2);
-        let ubkey = Self::derive(privkey, pubkey);
+        let (ubkey, _server_secret) = Self::derive(privkey, pubkey)?;
         let opening_key = aead::LessSafeKey::new(ubkey);
 
-        Self {
+        Ok(Self {
             opening: opening_key,
             sealing: sealing_key,
             nonce_seq: NonceSeq::new(1),
-        }
+            replay_window: ReplayWindow::default(),
+            #[cfg(feature = "keylog")]
+            secrets: (_client_secret, _server_secret),
+        })
     }
 
     /// Derives a session key for the server.
-    pub fn server_derive(privseed: PrivSeed, pubseed: PubSeed) -> Self {
+    pub fn server_derive(privseed: PrivSeed, pubseed: PubSeed) -> Result<Self> {
         let privkey = privseed.privkey1;
         let pubkey = agreement::UnparsedPublicKey::new(&agreement::ECDH_P384, pubseed.pubkey1);
-        let ubkey = Self::derive(privkey, pubkey);
+        let (ubkey, _client_secret) = Self::derive(privkey, pubkey)?;
         let opening_key = aead::LessSafeKey::new(ubkey);
 
         let privkey = privseed.privkey2;
         let pubkey = agreement::UnparsedPublicKey::new(&agreement::ECDH_P384, pubseed.pubkey2);
-        let ubkey = Self::derive(privkey, pubkey);
+        let (ubkey, _server_secret) = Self::derive(privkey, pubkey)?;
         let sealing_key = aead::LessSafeKey::new(ubkey);
 
-        Self {
+        Ok(Self {
             opening: opening_key,
             sealing: sealing_key,
             nonce_seq: NonceSeq::new(2),
-        }
+            replay_window: ReplayWindow::default(),
+            #[cfg(feature = "keylog")]
+            secrets: (_client_secret, _server_secret),
+        })
+    }
+
+    /// Returns the raw keys sealing packets from the client and from the server.
+    #[cfg(feature = "keylog")]
+    pub fn secrets(&self) -> (&[u8], &[u8]) {
+        (&self.secrets.0, &self.secrets.1)
     }
 
     /// Encrypts any data.
@@ -220,23 +524,173 @@ impl SessionKey {
     }
 
     /// Decrypts a ciphertext.
+    /// Fails with `Error::Replayed` if a ciphertext with the same nonce was already decrypted.
     pub fn unseal<A: AsRef<[u8]>, T: DeserializeOwned>(
-        &self,
+        &mut self,
         aad: A,
         ciphertext: &mut [u8],
     ) -> Result<T> {
-        let (ciphertext, nonce_bytes) = ciphertext.split_at_mut(ciphertext.len() - aead::NONCE_LEN);
+        let plaintext = open(&self.opening, aad, ciphertext)?;
+
+        // the nonce is authentic now, so it can move the window
+        let nonce_bytes = &ciphertext[ciphertext.len() - aead::NONCE_LEN..];
+        let mut counter_bytes = [0; 16];
+        counter_bytes[..11].copy_from_slice(&nonce_bytes[..11]);
+        if !self
+            .replay_window
+            .accept(u128::from_le_bytes(counter_bytes))
+        {
+            return Err(Error::Replayed);
+        }
+        Ok(plaintext)
+    }
+}
 
-        let nonce_bytes: [u8; aead::NONCE_LEN] = nonce_bytes[..].try_into().expect("nonce len");
-        let nonce = aead::Nonce::assume_unique_for_key(nonce_bytes);
+/// Counters of nonces already accepted, in the manner of the IPsec anti-replay window.
+/// UDP may reorder datagrams, so a counter below the highest one is accepted
+/// as long as it is within the window and has not been seen.
+#[derive(Debug, Default)]
+struct ReplayWindow {
+    /// One past the highest counter accepted.
+    next: u128,
+    /// The bit `i` tells whether the counter `next - 1 - i` has been accepted.
+    seen: u64,
+}
 
-        let aad = aead::Aad::from([aad.as_ref(), &nonce_bytes].concat());
+impl ReplayWindow {
+    /// Records a counter. Returns false if it was seen or is too old to tell.
+    fn accept(&mut self, counter: u128) -> bool {
+        if counter >= self.next {
+            let shift = counter - self.next + 1;
+            self.seen = if shift < 64 { self.seen << shift } else { 0 };
+            self.seen |= 1;
+            self.next = counter + 1;
+            return true;
+        }
+        let offset = self.next - 1 - counter;
+        if offset >= 64 || self.seen & (1 << offset) != 0 {
+            return false;
+        }
+        self.seen |= 1 << offset;
+        true
+    }
+}
+
+/// Decrypts a ciphertext made by `SessionKey::seal` with a raw key of one direction
+/// (e.g. read from a key log).
+pub fn unseal_with_raw_key<A: AsRef<[u8]>, T: DeserializeOwned>(
+    key: &[u8],
+    aad: A,
+    ciphertext: &mut [u8],
+) -> Result<T> {
+    let key = aead::UnboundKey::new(&aead::CHACHA20_POLY1305, key).map_err(|_| Error::Unseal)?;
+    open(&aead::LessSafeKey::new(key), aad, ciphertext)
+}
+
+fn open<A: AsRef<[u8]>, T: DeserializeOwned>(
+    key: &aead::LessSafeKey,
+    aad: A,
+    ciphertext: &mut [u8],
+) -> Result<T> {
+    // the length is up to the sender
+    if ciphertext.len() < aead::NONCE_LEN {
+        return Err(Error::BrokenMessage);
+    }
+    let (ciphertext, nonce_bytes) = ciphertext.split_at_mut(ciphertext.len() - aead::NONCE_LEN);
+
+    let nonce_bytes: [u8; aead::NONCE_LEN] = nonce_bytes[..].try_into().expect("nonce len");
+    let nonce = aead::Nonce::assume_unique_for_key(nonce_bytes);
 
-        let plaintext = self
-            .opening
-            .open_in_place(nonce, aad, ciphertext)
-            .map_err(|_| Error::BrokenMessage)?;
+    let aad = aead::Aad::from([aad.as_ref(), &nonce_bytes].concat());
+
+    let plaintext = key
+        .open_in_place(nonce, aad, ciphertext)
+        .map_err(|_| Error::Unseal)?;
+
+    crate::decode(plaintext)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // RFC 4648 section 10
+    const VECTORS: [(&str, &str); 7] = [
+        ("", ""),
+        ("f", "Zg=="),
+        ("fo", "Zm8="),
+        ("foo", "Zm9v"),
+        ("foob", "Zm9vYg=="),
+        ("fooba", "Zm9vYmE="),
+        ("foobar", "Zm9vYmFy"),
+    ];
+
+    #[test]
+    fn base64_test_vectors() {
+        for (bytes, encoded) in VECTORS {
+            assert_eq!(to_base64(bytes.as_bytes()), encoded);
+            assert_eq!(from_base64(encoded).unwrap(), bytes.as_bytes());
+        }
+    }
+
+    #[test]
+    fn base64_round_trip() {
+        let bytes: Vec<u8> = (0..=255).collect();
+        for len in 0..bytes.len() {
+            assert_eq!(
+                from_base64(&to_base64(&bytes[..len])).unwrap(),
+                &bytes[..len]
+            );
+        }
+    }
+
+    #[test]
+    fn base64_rejects_malformed() {
+        for encoded in ["Zg", "Zg=", "Z===", "Zg==Zg==", "Zm9v!A==", "Zm 9v"] {
+            assert_eq!(from_base64(encoded), None, "{:?}", encoded);
+        }
+    }
+
+    #[test]
+    fn base64_rejects_non_zero_padding_bits() {
+        // "Zh==" and "Zm9=" have the same bytes as "Zg==" and "Zm8=" if the padding bits are ignored
+        assert_eq!(from_base64("Zh=="), None);
+        assert_eq!(from_base64("Zm9="), None);
+    }
+
+    #[test]
+    fn public_key_source_reports_short_base64() {
+        let key = to_base64(&[7; PUBLIC_KEY_LEN]);
+        assert_eq!(
+            PublicKeySource::from(key.clone()),
+            PublicKeySource::Inline(vec![7; PUBLIC_KEY_LEN])
+        );
+        // a character lost in copying
+        let source = PublicKeySource::from(key[1..].to_owned());
+        assert!(matches!(source, PublicKeySource::File(_)));
+        assert!(matches!(
+            source.load(),
+            Err(Error::InvalidBase64Key { len: 31 })
+        ));
+        assert!(matches!(
+            PublicKeySource::from("/nonexistent/key.der".to_owned()).load(),
+            Err(Error::Io(_))
+        ));
+    }
 
-        bincode::deserialize(plaintext).map_err(|_| Error::BrokenMessage)
+    #[test]
+    fn reply_seed_is_bound_to_the_hello_and_the_settings() {
+        let reply_seed = || ReplySeed::new(generate_seed_pair().1, 42, b"settings");
+        assert!(reply_seed().bound_to(42, b"settings").is_ok());
+        // a reply to an older hello
+        assert!(matches!(
+            reply_seed().bound_to(43, b"settings"),
+            Err(Error::BrokenMessage)
+        ));
+        // corrupted settings
+        assert!(matches!(
+            reply_seed().bound_to(42, b"sett1ngs"),
+            Err(Error::BrokenMessage)
+        ));
     }
 }
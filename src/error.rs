@@ -5,6 +5,33 @@ pub enum Error {
     #[error("Failed to setup tun device: {}", .msg)]
     Setup { msg: String },
 
+    #[error("Failed to parse the configuration: {}", .msg)]
+    InvalidConfig { msg: String },
+
+    #[error("Invalid network prefix: {}", .prefix)]
+    InvalidPrefix { prefix: String },
+
+    #[error("Invalid fingerprint: {}", .fingerprint)]
+    InvalidFingerprint { fingerprint: String },
+
+    #[error("Public key must be 32 bytes of Ed25519 key")]
+    InvalidPublicKey,
+
+    #[error("No such file, and as a base64 key it decodes to {} bytes, expected 32", .len)]
+    InvalidBase64Key { len: usize },
+
+    #[error("Conflicting peer: {}", .reason)]
+    ConflictingPeer { reason: String },
+
+    #[error("No such peer")]
+    UnknownPeer,
+
+    #[error("Key was revoked on {}: {}", .date, .reason)]
+    Revoked { date: String, reason: String },
+
+    #[error("Invalid certificate: {}", .reason)]
+    InvalidCertificate { reason: String },
+
     #[error("Only PKCS8 Ed25519 private key is supported.")]
     InvalidPrivateKeyFormat,
 
@@ -14,6 +41,21 @@ pub enum Error {
     #[error("MAC tag is invalid")]
     Unseal,
 
+    #[error("Message was replayed")]
+    Replayed,
+
+    #[error("Failed to parse the lease file")]
+    InvalidLeaseFile,
+
+    #[error("No address is available in the pool")]
+    PoolExhausted,
+
+    #[error("Invalid key log line: {}", .line)]
+    InvalidKeyLog { line: String },
+
+    #[error("Invalid revocation list line: {}", .line)]
+    InvalidRevocationList { line: String },
+
     #[error("Received message was broken")]
     BrokenMessage,
 
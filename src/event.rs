@@ -0,0 +1,139 @@
+//! A structured log of events for auditing, written as JSON lines.
+//!
+//! Unlike the free-form log, each line is an `Event` tagged by its kind:
+//! ```json
+//! {"time":1650000000,"event":"handshake_accepted","fingerprint":"3f2a...","endpoint":"192.0.2.1:41641","address":"10.20.30.2"}
+//! ```
+//!
+//! The client emits `handshake_accepted`, `rekey` and `session_expired` about its session with the server.
+
+use serde::Serialize;
+use std::fs::{File, OpenOptions};
+use std::io::Write;
+use std::net::{Ipv4Addr, SocketAddr};
+use std::path::Path;
+use std::sync::Mutex;
+use std::time::SystemTime;
+
+use crate::crypto::Fingerprint;
+use crate::error::Result;
+
+#[derive(Debug, Clone, Serialize)]
+#[serde(tag = "event", rename_all = "snake_case")]
+pub enum Event {
+    /// A peer established a new session.
+    HandshakeAccepted {
+        fingerprint: Fingerprint,
+        endpoint: SocketAddr,
+        address: Ipv4Addr,
+    },
+
+    /// A handshake was refused. `fingerprint` is the key hint, which the peer may have forged.
+    HandshakeRejected {
+        fingerprint: Fingerprint,
+        endpoint: SocketAddr,
+        reason: String,
+    },
+
+    /// A peer with a session made a new one.
+    Rekey {
+        fingerprint: Fingerprint,
+        endpoint: SocketAddr,
+        address: Ipv4Addr,
+    },
+
+    /// A session was dropped because the peer was silent too long.
+    SessionExpired {
+        fingerprint: Fingerprint,
+        endpoint: SocketAddr,
+        address: Ipv4Addr,
+        idle_secs: u64,
+    },
+
+    /// A session was dropped because the key of the peer was revoked.
+    SessionRevoked {
+        fingerprint: Fingerprint,
+        endpoint: SocketAddr,
+        address: Ipv4Addr,
+        reason: String,
+    },
+
+    /// A peer started sending from another endpoint.
+    EndpointRoamed {
+        fingerprint: Fingerprint,
+        address: Ipv4Addr,
+        from: SocketAddr,
+        to: SocketAddr,
+    },
+
+    /// The configuration was reloaded.
+    ConfigReloaded {
+        peers: usize,
+        added: usize,
+        removed: usize,
+        modified: usize,
+    },
+}
+
+#[derive(Serialize)]
+struct Record<'a> {
+    /// Seconds since the UNIX epoch.
+    time: u64,
+    #[serde(flatten)]
+    event: &'a Event,
+}
+
+/// A function called with every event (e.g. by an application embedding the VPN).
+pub type Callback = Box<dyn Fn(&Event) + Send + Sync>;
+
+/// A sink of events. Events are written to the file if given, and passed to the callbacks.
+#[derive(Default)]
+pub struct EventLog {
+    file: Option<Mutex<File>>,
+    callbacks: Vec<Callback>,
+}
+
+impl EventLog {
+    /// Opens the file to append events to.
+    pub fn open<P: AsRef<Path>>(path: Option<P>) -> Result<Self> {
+        let file = match path {
+            Some(path) => {
+                let file = OpenOptions::new()
+                    .create(true)
+                    .append(true)
+                    .open(path.as_ref())?;
+                Some(Mutex::new(file))
+            }
+            None => None,
+        };
+        Ok(Self {
+            file,
+            callbacks: Vec::new(),
+        })
+    }
+
+    /// Registers a function called with every event.
+    pub fn subscribe(&mut self, callback: Callback) {
+        self.callbacks.push(callback);
+    }
+
+    /// Appends an event. Failures are logged but otherwise ignored.
+    pub fn emit(&self, event: Event) {
+        for callback in self.callbacks.iter() {
+            callback(&event);
+        }
+        let file = match &self.file {
+            Some(file) => file,
+            None => return,
+        };
+        let record = Record {
+            time: crate::unix_time(SystemTime::now()),
+            event: &event,
+        };
+        let mut line = serde_json::to_string(&record).expect("serialize");
+        line.push('\n');
+        if let Err(err) = file.lock().expect("poisoned").write_all(line.as_bytes()) {
+            log::error!("event log: {}", err);
+        }
+    }
+}
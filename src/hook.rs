@@ -0,0 +1,56 @@
+//! Shell commands configured to run when the interface goes up or down and when peers come and go.
+//!
+//! A hook is run by `sh -c` with environment variables describing the event:
+//!
+//! - `VPN_INTERFACE`: the name of the VPN interface
+//! - `VPN_ADDRESS`: the address of the VPN interface
+//! - `VPN_PEER_ADDRESS`: the address of the peer (server only)
+//! - `VPN_PEER_PUBLIC_KEY`: the hex-encoded public key of the peer
+//! - `VPN_PEER_ENDPOINT`: the socket address of the peer
+
+use std::sync::mpsc::{self, Sender};
+
+use crate::error::Result;
+
+/// Environment variables passed to a hook.
+pub type Env = Vec<(&'static str, String)>;
+
+/// Runs a hook and waits for it to finish successfully.
+pub fn run_hook(script: &str, env: &Env) -> Result<()> {
+    let envs: Vec<(&str, &str)> = env.iter().map(|(k, v)| (*k, v.as_str())).collect();
+    crate::run_command_with_env("sh", &["-c", script], &envs)
+}
+
+/// Runs hooks one by one in a background thread so that packets are not blocked by them.
+#[derive(Debug, Clone)]
+pub struct Hooks {
+    sender: Sender<(String, Env)>,
+}
+
+impl Hooks {
+    pub fn new() -> Self {
+        let (sender, receiver) = mpsc::channel::<(String, Env)>();
+        std::thread::spawn(move || {
+            for (script, env) in receiver {
+                log::debug!("hook: {}", script);
+                if let Err(err) = run_hook(&script, &env) {
+                    log::error!("hook: {}", err);
+                }
+            }
+        });
+        Self { sender }
+    }
+
+    /// Queues a hook if it is configured.
+    pub fn spawn(&self, hook: Option<&str>, env: Env) {
+        if let Some(script) = hook {
+            let _ = self.sender.send((script.to_owned(), env));
+        }
+    }
+}
+
+impl Default for Hooks {
+    fn default() -> Self {
+        Self::new()
+    }
+}
@@ -0,0 +1,500 @@
+//! A UDP proxy simulating a poor network between clients and the server, for protocol testing.
+//!
+//! `Proxy` relays datagrams between clients and the server, and applies an `Impairment`
+//! to each direction: datagrams are lost, delayed, reordered, duplicated,
+//! and queued behind a bandwidth limit. Every client gets its own socket towards the server,
+//! so the server sees the clients at distinct endpoints as usual.
+//!
+//! The random decisions are made by a generator seeded by `ProxyBuilder::seed`,
+//! so the decisions are the same as long as the order of datagrams is.
+
+use std::cmp::{Ordering as CmpOrdering, Reverse};
+use std::collections::{BinaryHeap, HashMap};
+use std::net::{SocketAddr, UdpSocket};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::mpsc::{self, Receiver, RecvTimeoutError, Sender};
+use std::sync::{Arc, Mutex};
+use std::thread::JoinHandle;
+use std::time::{Duration, Instant};
+
+use crate::error::Result;
+use crate::{is_timeout, POLL_INTERVAL};
+
+/// The size of the buffers receiving datagrams, enough for any UDP payload.
+const BUF_SIZE: usize = 65536;
+
+/// Conditions applied to the datagrams in one direction.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Impairment {
+    /// The probability of a datagram being lost.
+    pub loss: f64,
+
+    /// The delay added to every datagram.
+    pub latency: Duration,
+
+    /// The upper bound of a random delay added on top of `latency`.
+    pub jitter: Duration,
+
+    /// The probability of a datagram being held back by `reorder_delay`,
+    /// so that the datagrams following it overtake it.
+    pub reorder: f64,
+    pub reorder_delay: Duration,
+
+    /// The probability of a datagram being delivered twice.
+    pub duplicate: f64,
+
+    /// The bandwidth in bytes per second. Datagrams wait for the link to be free.
+    pub bandwidth: Option<u64>,
+}
+
+impl Default for Impairment {
+    /// Passes every datagram through as is.
+    fn default() -> Self {
+        Self {
+            loss: 0.0,
+            latency: Duration::ZERO,
+            jitter: Duration::ZERO,
+            reorder: 0.0,
+            reorder_delay: Duration::from_millis(20),
+            duplicate: 0.0,
+            bandwidth: None,
+        }
+    }
+}
+
+/// The direction of datagrams through the proxy.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Direction {
+    /// From clients to the server.
+    Upstream,
+    /// From the server to clients.
+    Downstream,
+}
+
+/// Counters of datagrams in one direction.
+#[derive(Debug, Default, Clone, Copy, PartialEq)]
+pub struct LinkStats {
+    /// Datagrams received from the senders.
+    pub received: u64,
+    pub lost: u64,
+    pub reordered: u64,
+    pub duplicated: u64,
+    /// Datagrams sent to the receivers, including the duplicates.
+    pub delivered: u64,
+}
+
+/// A random number generator (SplitMix64), good enough to make decisions on datagrams.
+#[derive(Debug)]
+struct Rng(u64);
+
+impl Rng {
+    fn next_u64(&mut self) -> u64 {
+        self.0 = self.0.wrapping_add(0x9e37_79b9_7f4a_7c15);
+        let mut z = self.0;
+        z = (z ^ (z >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+        z ^ (z >> 31)
+    }
+
+    /// Returns a number in [0, 1).
+    fn next_f64(&mut self) -> f64 {
+        (self.next_u64() >> 11) as f64 / (1u64 << 53) as f64
+    }
+
+    /// Returns true with the given probability.
+    fn chance(&mut self, probability: f64) -> bool {
+        probability > 0.0 && self.next_f64() < probability
+    }
+}
+
+/// One direction of the simulated network.
+#[derive(Debug)]
+struct Link {
+    impairment: Impairment,
+    stats: LinkStats,
+    rng: Rng,
+
+    /// When the link finishes sending the datagrams queued for the bandwidth.
+    busy_until: Instant,
+}
+
+impl Link {
+    fn new(impairment: Impairment, seed: u64) -> Self {
+        Self {
+            impairment,
+            stats: LinkStats::default(),
+            rng: Rng(seed),
+            busy_until: Instant::now(),
+        }
+    }
+
+    /// Decides when the copies of a datagram of <len> bytes arrive. Returns nothing if it is lost.
+    fn schedule(&mut self, len: usize, now: Instant) -> Vec<Instant> {
+        self.stats.received += 1;
+        let impairment = &self.impairment;
+        if self.rng.chance(impairment.loss) {
+            self.stats.lost += 1;
+            return Vec::new();
+        }
+        let copies = if self.rng.chance(impairment.duplicate) {
+            self.stats.duplicated += 1;
+            2
+        } else {
+            1
+        };
+
+        let mut arrivals = Vec::with_capacity(copies);
+        for _ in 0..copies {
+            let mut departure = now;
+            if let Some(bandwidth) = impairment.bandwidth {
+                let transmission = Duration::from_secs_f64(len as f64 / bandwidth.max(1) as f64);
+                departure = self.busy_until.max(now) + transmission;
+                self.busy_until = departure;
+            }
+            let mut delay = impairment.latency + impairment.jitter.mul_f64(self.rng.next_f64());
+            if self.rng.chance(impairment.reorder) {
+                self.stats.reordered += 1;
+                delay += impairment.reorder_delay;
+            }
+            arrivals.push(departure + delay);
+        }
+        arrivals
+    }
+}
+
+/// A datagram to be sent when it arrives at the other end of the link.
+struct Delivery {
+    at: Instant,
+    direction: Direction,
+    sock: Arc<UdpSocket>,
+    /// The destination, or `None` for a connected socket.
+    to: Option<SocketAddr>,
+    data: Arc<Vec<u8>>,
+}
+
+/// A delivery waiting in the queue, ordered by the arrival and then by the order of scheduling.
+struct Queued {
+    seq: u64,
+    delivery: Delivery,
+}
+
+impl PartialEq for Queued {
+    fn eq(&self, other: &Self) -> bool {
+        self.cmp(other) == CmpOrdering::Equal
+    }
+}
+
+impl Eq for Queued {}
+
+impl PartialOrd for Queued {
+    fn partial_cmp(&self, other: &Self) -> Option<CmpOrdering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl Ord for Queued {
+    fn cmp(&self, other: &Self) -> CmpOrdering {
+        (self.delivery.at, self.seq).cmp(&(other.delivery.at, other.seq))
+    }
+}
+
+/// The state of the proxy shared among threads.
+struct Shared {
+    upstream: Mutex<Link>,
+    downstream: Mutex<Link>,
+    stopped: AtomicBool,
+    workers: Mutex<Vec<JoinHandle<()>>>,
+}
+
+impl Shared {
+    fn link(&self, direction: Direction) -> &Mutex<Link> {
+        match direction {
+            Direction::Upstream => &self.upstream,
+            Direction::Downstream => &self.downstream,
+        }
+    }
+
+    fn is_stopped(&self) -> bool {
+        self.stopped.load(Ordering::SeqCst)
+    }
+
+    fn spawn_worker<F: FnOnce() + Send + 'static>(&self, f: F) {
+        self.workers
+            .lock()
+            .expect("poisoned")
+            .push(std::thread::spawn(f));
+    }
+
+    /// Passes a datagram to the link of the direction, which decides when (or whether) it arrives.
+    fn forward(
+        &self,
+        direction: Direction,
+        data: &[u8],
+        sock: &Arc<UdpSocket>,
+        to: Option<SocketAddr>,
+        scheduler: &Sender<Delivery>,
+    ) {
+        let arrivals = self
+            .link(direction)
+            .lock()
+            .expect("poisoned")
+            .schedule(data.len(), Instant::now());
+        let data = Arc::new(data.to_vec());
+        for at in arrivals {
+            let delivery = Delivery {
+                at,
+                direction,
+                sock: sock.clone(),
+                to,
+                data: data.clone(),
+            };
+            if scheduler.send(delivery).is_err() {
+                return;
+            }
+        }
+    }
+}
+
+/// Configures a `Proxy`.
+pub struct ProxyBuilder {
+    upstream: SocketAddr,
+    bind: SocketAddr,
+    impairments: (Impairment, Impairment),
+    seed: u64,
+}
+
+impl ProxyBuilder {
+    /// Sets the address to listen on for clients (by default, an ephemeral port on 127.0.0.1).
+    pub fn bind(mut self, addr: SocketAddr) -> Self {
+        self.bind = addr;
+        self
+    }
+
+    /// Sets the conditions of the given direction.
+    pub fn impairment(mut self, direction: Direction, impairment: Impairment) -> Self {
+        match direction {
+            Direction::Upstream => self.impairments.0 = impairment,
+            Direction::Downstream => self.impairments.1 = impairment,
+        }
+        self
+    }
+
+    /// Seeds the random decisions.
+    pub fn seed(mut self, seed: u64) -> Self {
+        self.seed = seed;
+        self
+    }
+
+    /// Starts relaying datagrams in background threads.
+    pub fn start(self) -> Result<Proxy> {
+        let sock = UdpSocket::bind(self.bind)?;
+        sock.set_read_timeout(Some(POLL_INTERVAL))?;
+        let local_addr = sock.local_addr()?;
+        let (upstream, downstream) = self.impairments;
+        let shared = Arc::new(Shared {
+            upstream: Mutex::new(Link::new(upstream, self.seed)),
+            downstream: Mutex::new(Link::new(downstream, !self.seed)),
+            stopped: AtomicBool::new(false),
+            workers: Mutex::new(Vec::new()),
+        });
+
+        let (scheduler, queue) = mpsc::channel();
+        shared.spawn_worker({
+            let shared = shared.clone();
+            move || deliver(&shared, queue)
+        });
+        shared.spawn_worker({
+            let shared = shared.clone();
+            let upstream = self.upstream;
+            move || listen(&shared, Arc::new(sock), upstream, scheduler)
+        });
+        log::info!("relaying {} to {}", local_addr, self.upstream);
+
+        Ok(Proxy { shared, local_addr })
+    }
+}
+
+/// Relays datagrams from clients to the server, opening a socket towards the server for each new client.
+fn listen(
+    shared: &Arc<Shared>,
+    sock: Arc<UdpSocket>,
+    upstream: SocketAddr,
+    scheduler: Sender<Delivery>,
+) {
+    let mut clients: HashMap<SocketAddr, Arc<UdpSocket>> = HashMap::new();
+    let mut buf = vec![0; BUF_SIZE];
+    while !shared.is_stopped() {
+        let (nb, client) = match sock.recv_from(&mut buf) {
+            Err(err) if is_timeout(&err) => continue,
+            Err(err) => {
+                log::error!("receive from clients: {}", err);
+                continue;
+            }
+            Ok(pair) => pair,
+        };
+
+        let server_sock = match clients.get(&client) {
+            Some(server_sock) => server_sock.clone(),
+            None => match connect(upstream) {
+                Err(err) => {
+                    log::error!("connect to {}: {}", upstream, err);
+                    continue;
+                }
+                Ok(server_sock) => {
+                    log::debug!("new client: {}", client);
+                    let server_sock = Arc::new(server_sock);
+                    clients.insert(client, server_sock.clone());
+                    shared.spawn_worker({
+                        let shared = shared.clone();
+                        let server_sock = server_sock.clone();
+                        let sock = sock.clone();
+                        let scheduler = scheduler.clone();
+                        move || relay_replies(&shared, server_sock, sock, client, scheduler)
+                    });
+                    server_sock
+                }
+            },
+        };
+        shared.forward(
+            Direction::Upstream,
+            &buf[..nb],
+            &server_sock,
+            None,
+            &scheduler,
+        );
+    }
+}
+
+fn connect(upstream: SocketAddr) -> std::io::Result<UdpSocket> {
+    let any: SocketAddr = match upstream {
+        SocketAddr::V4(_) => ([0, 0, 0, 0], 0).into(),
+        SocketAddr::V6(_) => ([0; 16], 0).into(),
+    };
+    let sock = UdpSocket::bind(any)?;
+    sock.connect(upstream)?;
+    sock.set_read_timeout(Some(POLL_INTERVAL))?;
+    Ok(sock)
+}
+
+/// Relays datagrams from the server to a client.
+fn relay_replies(
+    shared: &Shared,
+    server_sock: Arc<UdpSocket>,
+    sock: Arc<UdpSocket>,
+    client: SocketAddr,
+    scheduler: Sender<Delivery>,
+) {
+    let mut buf = vec![0; BUF_SIZE];
+    while !shared.is_stopped() {
+        match server_sock.recv(&mut buf) {
+            Err(err) if is_timeout(&err) => continue,
+            // e.g. ECONNREFUSED while the server is down
+            Err(err) => log::debug!("receive from the server for {}: {}", client, err),
+            Ok(nb) => shared.forward(
+                Direction::Downstream,
+                &buf[..nb],
+                &sock,
+                Some(client),
+                &scheduler,
+            ),
+        }
+    }
+}
+
+/// Sends the datagrams when they arrive.
+fn deliver(shared: &Shared, queue: Receiver<Delivery>) {
+    let mut seq = 0;
+    let mut pending = BinaryHeap::new();
+    while !shared.is_stopped() {
+        let now = Instant::now();
+        while pending
+            .peek()
+            .is_some_and(|Reverse(queued): &Reverse<Queued>| queued.delivery.at <= now)
+        {
+            let Reverse(Queued { delivery, .. }) = pending.pop().expect("peeked");
+            let result = match delivery.to {
+                Some(to) => delivery.sock.send_to(&delivery.data, to),
+                None => delivery.sock.send(&delivery.data),
+            };
+            match result {
+                Err(err) => log::debug!("deliver: {}", err),
+                Ok(_) => {
+                    let mut link = shared.link(delivery.direction).lock().expect("poisoned");
+                    link.stats.delivered += 1;
+                }
+            }
+        }
+
+        let timeout = pending
+            .peek()
+            .map(|Reverse(queued)| queued.delivery.at.saturating_duration_since(now))
+            .map_or(POLL_INTERVAL, |wait| wait.min(POLL_INTERVAL));
+        match queue.recv_timeout(timeout) {
+            Ok(delivery) => {
+                pending.push(Reverse(Queued { seq, delivery }));
+                seq += 1;
+            }
+            Err(RecvTimeoutError::Timeout) => {}
+            Err(RecvTimeoutError::Disconnected) => return,
+        }
+    }
+}
+
+/// A running UDP proxy with impairments. It stops when dropped.
+pub struct Proxy {
+    shared: Arc<Shared>,
+    local_addr: SocketAddr,
+}
+
+impl Proxy {
+    /// Starts configuring a proxy relaying datagrams to the given address (e.g. the server).
+    pub fn builder(upstream: SocketAddr) -> ProxyBuilder {
+        ProxyBuilder {
+            upstream,
+            bind: ([127, 0, 0, 1], 0).into(),
+            impairments: Default::default(),
+            seed: 0,
+        }
+    }
+
+    /// Returns the address clients should send to in place of the server.
+    pub fn local_addr(&self) -> SocketAddr {
+        self.local_addr
+    }
+
+    /// Changes the conditions of the given direction, from the next datagram on.
+    pub fn set_impairment(&self, direction: Direction, impairment: Impairment) {
+        self.shared
+            .link(direction)
+            .lock()
+            .expect("poisoned")
+            .impairment = impairment;
+    }
+
+    /// Returns the counters of the given direction.
+    pub fn stats(&self, direction: Direction) -> LinkStats {
+        self.shared.link(direction).lock().expect("poisoned").stats
+    }
+
+    /// Stops relaying and waits for the threads to finish. Datagrams in flight are discarded.
+    pub fn stop(&self) {
+        self.shared.stopped.store(true, Ordering::SeqCst);
+        loop {
+            let workers = std::mem::take(&mut *self.shared.workers.lock().expect("poisoned"));
+            if workers.is_empty() {
+                break;
+            }
+            for worker in workers {
+                if worker.join().is_err() {
+                    log::error!("a proxy thread panicked");
+                }
+            }
+        }
+    }
+}
+
+impl Drop for Proxy {
+    fn drop(&mut self) {
+        self.stop();
+    }
+}
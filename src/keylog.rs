@@ -0,0 +1,163 @@
+//! A log of session keys for decrypting captured traffic in lab environments,
+//! in the spirit of `SSLKEYLOGFILE`.
+//!
+//! Each line describes a session:
+//! ```text
+//! SESSION <fingerprint> <index> <client-to-server key> <server-to-client key>
+//! ```
+//! `<fingerprint>` identifies the peer, and `<index>` counts the sessions with the peer.
+//! The keys are hex-encoded ChaCha20-Poly1305 keys. Empty lines and lines starting with `#` are ignored.
+//!
+//! The server and the client write the keys to the file at `$VPN_KEYLOG_FILE`
+//! only if they are built with the `keylog` feature.
+
+use std::path::Path;
+
+use crate::crypto::{self, Fingerprint, SessionKey};
+use crate::error::{Error, Result};
+
+const LABEL: &str = "SESSION";
+
+/// The environment variable telling the path of the key log to write.
+pub const ENV_VAR: &str = "VPN_KEYLOG_FILE";
+
+/// A session recorded in a key log.
+#[derive(Debug, Clone, PartialEq)]
+pub struct KeyLogEntry {
+    pub fingerprint: Fingerprint,
+    pub index: u64,
+    /// The key sealing packets from the client to the server.
+    pub client_key: Vec<u8>,
+    /// The key sealing packets from the server to the client.
+    pub server_key: Vec<u8>,
+}
+
+impl std::fmt::Display for KeyLogEntry {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(
+            f,
+            "{} {} {} {} {}",
+            LABEL,
+            self.fingerprint,
+            self.index,
+            crypto::to_hex(&self.client_key),
+            crypto::to_hex(&self.server_key)
+        )
+    }
+}
+
+impl std::str::FromStr for KeyLogEntry {
+    type Err = Error;
+    fn from_str(s: &str) -> Result<Self> {
+        let invalid = || Error::InvalidKeyLog { line: s.to_owned() };
+        match s.split_whitespace().collect::<Vec<_>>()[..] {
+            [LABEL, fingerprint, index, client_key, server_key] => Ok(Self {
+                fingerprint: fingerprint.parse()?,
+                index: index.parse().map_err(|_| invalid())?,
+                client_key: crypto::from_hex(client_key).ok_or_else(invalid)?,
+                server_key: crypto::from_hex(server_key).ok_or_else(invalid)?,
+            }),
+            _ => Err(invalid()),
+        }
+    }
+}
+
+/// Reads all sessions in a key log.
+pub fn read<P: AsRef<Path>>(path: P) -> Result<Vec<KeyLogEntry>> {
+    std::fs::read_to_string(path)?
+        .lines()
+        .map(str::trim)
+        .filter(|line| !line.is_empty() && !line.starts_with('#'))
+        .map(str::parse)
+        .collect()
+}
+
+/// Writes session keys to the key log at `$VPN_KEYLOG_FILE`.
+/// Without the `keylog` feature, it never writes anything.
+#[derive(Debug, Default)]
+pub struct KeyLogWriter {
+    #[cfg(feature = "keylog")]
+    inner: Option<std::sync::Mutex<Inner>>,
+}
+
+#[cfg(feature = "keylog")]
+#[derive(Debug)]
+struct Inner {
+    file: std::fs::File,
+    /// The number of sessions written for each peer.
+    sessions: std::collections::HashMap<Fingerprint, u64>,
+}
+
+impl KeyLogWriter {
+    /// Opens the key log at `$VPN_KEYLOG_FILE` to append to, with a loud warning.
+    #[cfg(feature = "keylog")]
+    pub fn from_env() -> Result<Self> {
+        let path = match std::env::var_os(ENV_VAR) {
+            Some(path) => path,
+            None => return Ok(Self::default()),
+        };
+        use std::os::unix::fs::{OpenOptionsExt, PermissionsExt};
+        let file = std::fs::OpenOptions::new()
+            .create(true)
+            .append(true)
+            .mode(0o600)
+            .open(&path)?;
+        // `mode` applies only to a new file
+        if file.metadata()?.permissions().mode() & 0o077 != 0 {
+            let msg = format!(
+                "{:?} is accessible by other users; chmod 600 it or remove it",
+                path
+            );
+            return Err(std::io::Error::new(std::io::ErrorKind::PermissionDenied, msg).into());
+        }
+        let warning = format!(
+            "WARNING: session keys are written to {:?}. Anyone with the file can decrypt the traffic. \
+             Use it only for debugging in a lab.",
+            path
+        );
+        eprintln!("{}", warning);
+        log::warn!("{}", warning);
+        let inner = Inner {
+            file,
+            sessions: Default::default(),
+        };
+        Ok(Self {
+            inner: Some(std::sync::Mutex::new(inner)),
+        })
+    }
+
+    /// Returns a writer which writes nothing, since the `keylog` feature is disabled.
+    #[cfg(not(feature = "keylog"))]
+    pub fn from_env() -> Result<Self> {
+        if std::env::var_os(ENV_VAR).is_some() {
+            log::warn!("{} is ignored (built without the keylog feature)", ENV_VAR);
+        }
+        Ok(Self::default())
+    }
+
+    /// Writes the keys of a new session with the peer of the given fingerprint.
+    #[cfg(feature = "keylog")]
+    pub fn write(&self, fingerprint: Fingerprint, key: &SessionKey) {
+        use std::io::Write;
+
+        let mut inner = match &self.inner {
+            Some(inner) => inner.lock().expect("poisoned"),
+            None => return,
+        };
+        let index = inner.sessions.entry(fingerprint).or_insert(0);
+        let (client_key, server_key) = key.secrets();
+        let entry = KeyLogEntry {
+            fingerprint,
+            index: *index,
+            client_key: client_key.to_vec(),
+            server_key: server_key.to_vec(),
+        };
+        *index += 1;
+        if let Err(err) = writeln!(inner.file, "{}", entry) {
+            log::error!("key log: {}", err);
+        }
+    }
+
+    #[cfg(not(feature = "keylog"))]
+    pub fn write(&self, _fingerprint: Fingerprint, _key: &SessionKey) {}
+}
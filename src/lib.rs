@@ -1,15 +1,43 @@
+mod bytes;
+pub mod capture;
+pub mod cert;
+pub mod client;
+pub mod control;
 pub mod crypto;
 pub mod error;
+pub mod event;
+pub mod hook;
+pub mod impair;
+pub mod keylog;
+pub mod memory;
+pub mod metrics;
+pub mod pool;
+pub mod revocation;
+pub mod route;
+pub mod server;
 
 use error::{Error, Result};
 
 use serde::{Deserialize, Serialize};
 use std::net::{Ipv4Addr, SocketAddr, UdpSocket};
-use std::sync::Arc;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
 
-fn run_command(cmd: &str, args: &[&str]) -> Result<()> {
+/// How often threads blocked on a socket check whether the server or the client is stopped.
+const POLL_INTERVAL: Duration = Duration::from_millis(200);
+
+/// Executes a command and waits for it to finish successfully.
+pub fn run_command(cmd: &str, args: &[&str]) -> Result<()> {
+    run_command_with_env(cmd, args, &[])
+}
+
+/// Executes a command with additional environment variables and waits for it to finish successfully.
+pub fn run_command_with_env(cmd: &str, args: &[&str], envs: &[(&str, &str)]) -> Result<()> {
     use std::process::Command;
-    let cmd_status = Command::new(cmd).args(args).status()?;
+    let cmd_status = Command::new(cmd)
+        .args(args)
+        .envs(envs.iter().copied())
+        .status()?;
     if cmd_status.success() {
         Ok(())
     } else {
@@ -19,6 +47,81 @@ fn run_command(cmd: &str, args: &[&str]) -> Result<()> {
     }
 }
 
+fn command_output(cmd: &str, args: &[&str]) -> Result<String> {
+    use std::process::Command;
+    let output = Command::new(cmd).args(args).output()?;
+    if output.status.success() {
+        Ok(String::from_utf8_lossy(&output.stdout).into_owned())
+    } else {
+        Err(Error::Setup {
+            msg: format!("command: {} args: {}", cmd, args.join(" ")),
+        })
+    }
+}
+
+/// A list of commands to revert changes made to the system.
+/// The commands are executed in the reverse order on shutdown.
+#[derive(Debug, Default)]
+pub struct Teardown {
+    commands: Vec<TeardownCommand>,
+}
+
+#[derive(Debug)]
+struct TeardownCommand {
+    cmd: String,
+    args: Vec<String>,
+    envs: Vec<(String, String)>,
+}
+
+impl Teardown {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Registers a command to be executed on shutdown.
+    pub fn push(&mut self, cmd: &str, args: &[&str]) {
+        self.push_with_env(cmd, args, &[]);
+    }
+
+    /// Registers a command to be executed with additional environment variables on shutdown.
+    pub fn push_with_env(&mut self, cmd: &str, args: &[&str], envs: &[(&str, &str)]) {
+        self.commands.push(TeardownCommand {
+            cmd: cmd.to_owned(),
+            args: args.iter().map(|a| a.to_string()).collect(),
+            envs: envs
+                .iter()
+                .map(|(k, v)| (k.to_string(), v.to_string()))
+                .collect(),
+        });
+    }
+
+    /// Executes all registered commands.
+    pub fn run(&mut self) {
+        while let Some(TeardownCommand { cmd, args, envs }) = self.commands.pop() {
+            let args: Vec<&str> = args.iter().map(|a| a.as_str()).collect();
+            let envs: Vec<(&str, &str)> =
+                envs.iter().map(|(k, v)| (k.as_str(), v.as_str())).collect();
+            if let Err(err) = run_command_with_env(&cmd, &args, &envs) {
+                log::error!("teardown: {}", err);
+            }
+        }
+    }
+}
+
+/// Spawns a thread which executes the teardown and exits the process on SIGINT or SIGTERM.
+pub fn exit_on_signal(teardown: Arc<Mutex<Teardown>>) -> Result<()> {
+    use signal_hook::consts::{SIGINT, SIGTERM};
+    let mut signals = signal_hook::iterator::Signals::new([SIGINT, SIGTERM])?;
+    std::thread::spawn(move || {
+        if let Some(sig) = signals.forever().next() {
+            log::info!("signal {} received, shutting down", sig);
+            teardown.lock().expect("poisoned").run();
+            std::process::exit(0);
+        }
+    });
+    Ok(())
+}
+
 /// Opens a tun device named <ifname>, and configures it with the "ip" utility.
 pub fn setup_tun(
     ifname: &str,
@@ -39,18 +142,96 @@ pub fn setup_tun(
     Ok(iface)
 }
 
+/// A network interface which IP packets go through to and from the host.
+///
+/// It is a tun device (`tun_tap::Iface`) in production,
+/// and `memory::MemoryInterface` for driving the forwarding logic in-process.
+pub trait VirtualInterface: Send + Sync {
+    fn name(&self) -> &str;
+
+    /// Writes a packet to the host.
+    fn send(&self, packet: &[u8]) -> std::io::Result<usize>;
+
+    /// Reads a packet from the host. Blocks until a packet arrives.
+    fn recv(&self, buf: &mut [u8]) -> std::io::Result<usize>;
+}
+
+impl VirtualInterface for tun_tap::Iface {
+    fn name(&self) -> &str {
+        tun_tap::Iface::name(self)
+    }
+
+    fn send(&self, packet: &[u8]) -> std::io::Result<usize> {
+        tun_tap::Iface::send(self, packet)
+    }
+
+    fn recv(&self, buf: &mut [u8]) -> std::io::Result<usize> {
+        tun_tap::Iface::recv(self, buf)
+    }
+}
+
+/// Adds a route to the given prefix through the interface named <ifname>.
+pub fn add_route(ifname: &str, prefix: route::Ipv4Cidr) -> Result<()> {
+    let prefix = prefix.to_string();
+    run_command("ip", &["route", "add", &prefix, "dev", ifname])
+}
+
+/// Removes a route to the given prefix through the interface named <ifname>.
+pub fn del_route(ifname: &str, prefix: route::Ipv4Cidr) -> Result<()> {
+    let prefix = prefix.to_string();
+    run_command("ip", &["route", "del", &prefix, "dev", ifname])
+}
+
+/// Returns the arguments for "ip route" describing the current path to the given address
+/// (e.g. `["via", "192.168.0.1", "dev", "eth0"]`).
+pub fn current_route(addr: Ipv4Addr) -> Result<Vec<String>> {
+    let addr = addr.to_string();
+    let output = command_output("ip", &["-4", "route", "get", &addr])?;
+    let words: Vec<&str> = output
+        .lines()
+        .next()
+        .unwrap_or("")
+        .split_whitespace()
+        .collect();
+
+    let mut route = Vec::new();
+    for pair in words.windows(2) {
+        if pair[0] == "via" || pair[0] == "dev" {
+            route.push(pair[0].to_owned());
+            route.push(pair[1].to_owned());
+        }
+    }
+    if route.is_empty() {
+        return Err(Error::Setup {
+            msg: format!("no route to {}", addr),
+        });
+    }
+    Ok(route)
+}
+
+fn is_timeout(err: &std::io::Error) -> bool {
+    use std::io::ErrorKind;
+    matches!(err.kind(), ErrorKind::WouldBlock | ErrorKind::TimedOut)
+}
+
 /// A message of the protocol.
 #[derive(Debug, PartialEq, Serialize, Deserialize)]
 pub enum Message {
     /// The first message to establish a connection (from a peer to the server).
     Hello {
-        addr: Ipv4Addr,
+        /// The fingerprint of the static public key of the peer.
+        key_hint: crypto::Fingerprint,
         seed: crypto::Signed<crypto::PubSeed>,
+        /// A certificate of the key, for a server enrolling peers by certificates.
+        certificate: Option<cert::Certificate>,
     },
 
     /// The second message to establish a connection (from the server to a peer).
     HelloReply {
-        seed: crypto::Signed<crypto::PubSeed>,
+        seed: crypto::Signed<crypto::ReplySeed>,
+        /// `PeerSettings` sealed with the established session key.
+        #[serde(with = "bytes::vec")]
+        settings: Vec<u8>,
     },
 
     /// A message to keep the connection, primarily for preserving NAPT table.
@@ -60,6 +241,27 @@ pub enum Message {
     Packet(SealedPacket),
 }
 
+impl Message {
+    /// Decodes a message received from the network.
+    /// Anyone can send anything, so it fails on malformed bytes instead of panicking.
+    pub fn decode(bytes: &[u8]) -> Result<Self> {
+        decode(bytes)
+    }
+}
+
+/// Decodes a value serialized with `bincode::serialize` from untrusted bytes.
+///
+/// The decoder may not read more than the given bytes, so a length prefix announcing more
+/// than the rest fails before it is allocated. Trailing bytes are rejected as well.
+pub fn decode<T: serde::de::DeserializeOwned>(bytes: &[u8]) -> Result<T> {
+    use bincode::Options;
+    bincode::DefaultOptions::new()
+        .with_fixint_encoding()
+        .with_limit(bytes.len() as u64)
+        .deserialize(bytes)
+        .map_err(|_| Error::BrokenMessage)
+}
+
 /// A wrapper around `UdpSocket` for easily sending/receiving `Message`s through the socket.
 #[derive(Clone)]
 pub struct Channel {
@@ -76,16 +278,12 @@ impl Channel {
 
     pub fn recv(&mut self) -> Result<Message> {
         let nb = self.sock.recv(&mut self.buf[..])?;
-        let slice = &self.buf[..nb];
-        let msg: Message = bincode::deserialize(slice).map_err(|_| error::Error::BrokenMessage)?;
-        Ok(msg)
+        Message::decode(&self.buf[..nb])
     }
 
     pub fn recv_from(&mut self) -> Result<(Message, SocketAddr)> {
         let (nb, from) = self.sock.recv_from(&mut self.buf[..])?;
-        let slice = &self.buf[..nb];
-        let msg: Message = bincode::deserialize(slice).map_err(|_| error::Error::BrokenMessage)?;
-        Ok((msg, from))
+        Ok((Message::decode(&self.buf[..nb])?, from))
     }
 
     pub fn send(&mut self, msg: &Message) -> Result<()> {
@@ -94,6 +292,16 @@ impl Channel {
         Ok(())
     }
 
+    /// Returns the address of the remote peer of a connected socket.
+    pub fn peer_addr(&self) -> Result<SocketAddr> {
+        Ok(self.sock.peer_addr()?)
+    }
+
+    /// Sets the timeout of `recv` and `recv_from`. `None` means they block indefinitely.
+    pub fn set_read_timeout(&self, timeout: Option<Duration>) -> Result<()> {
+        Ok(self.sock.set_read_timeout(timeout)?)
+    }
+
     pub fn send_to(&mut self, msg: &Message, addr: SocketAddr) -> Result<()> {
         let msg = bincode::serialize(msg).expect("invalid msg"); // FIXME: reduce heap allocation
         self.sock.send_to(&msg[..], addr)?;
@@ -101,11 +309,69 @@ impl Channel {
     }
 }
 
+/// Counters of traffic through a tunnel.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub struct Traffic {
+    pub rx_bytes: u64,
+    pub rx_packets: u64,
+    pub tx_bytes: u64,
+    pub tx_packets: u64,
+}
+
+impl Traffic {
+    /// Counts a received packet.
+    pub fn rx(&mut self, bytes: usize) {
+        self.rx_bytes += bytes as u64;
+        self.rx_packets += 1;
+    }
+
+    /// Counts a transmitted packet.
+    pub fn tx(&mut self, bytes: usize) {
+        self.tx_bytes += bytes as u64;
+        self.tx_packets += 1;
+    }
+}
+
+/// Returns seconds elapsed since the UNIX epoch.
+pub fn unix_time(time: std::time::SystemTime) -> u64 {
+    time.duration_since(std::time::UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or(0)
+}
+
+/// Settings of a peer decided by the server, pushed to the peer on the handshake.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct PeerSettings {
+    /// The address to be assigned to the VPN interface.
+    pub address: Ipv4Addr,
+
+    /// The prefix length of the VPN subnet.
+    pub prefix_len: u8,
+
+    /// The MTU value of the VPN interface.
+    pub mtu: u16,
+
+    /// Prefixes to be reached through the VPN interface.
+    pub routes: Vec<route::Ipv4Cidr>,
+
+    /// DNS servers to be used while connected.
+    pub dns: Vec<Ipv4Addr>,
+
+    /// The interval of `HeartBeat` messages in seconds.
+    pub keepalive: u64,
+}
+
+impl PeerSettings {
+    /// The additional authenticated data used to seal the settings.
+    pub const AAD: &'static [u8] = b"settings";
+}
+
 /// An encrypted IP packet.
 #[derive(Debug, PartialEq, Serialize, Deserialize)]
 pub struct SealedPacket {
     pub source: Ipv4Addr,
     pub destination: Ipv4Addr,
+    #[serde(with = "bytes::vec")]
     pub content: Vec<u8>,
 }
 
@@ -0,0 +1,88 @@
+//! An in-memory `VirtualInterface`, which needs neither root nor a kernel interface.
+//!
+//! `MemoryInterface` is given to the VPN in place of a tun device,
+//! and the paired `MemoryHost` plays the host side: it injects packets as if
+//! applications sent them, and receives packets the VPN delivered.
+
+use std::io;
+use std::sync::mpsc::{self, Receiver, Sender};
+use std::sync::Mutex;
+use std::time::Duration;
+
+use crate::VirtualInterface;
+
+/// Makes a pair of an interface and its host side.
+pub fn memory_interface(name: &str) -> (MemoryInterface, MemoryHost) {
+    let (to_iface, from_host) = mpsc::channel();
+    let (to_host, from_iface) = mpsc::channel();
+    let iface = MemoryInterface {
+        name: name.to_owned(),
+        inbound: Mutex::new(from_host),
+        outbound: to_host,
+    };
+    let host = MemoryHost {
+        outbound: to_iface,
+        inbound: Mutex::new(from_iface),
+    };
+    (iface, host)
+}
+
+/// The VPN side of an in-memory interface.
+#[derive(Debug)]
+pub struct MemoryInterface {
+    name: String,
+    inbound: Mutex<Receiver<Vec<u8>>>,
+    outbound: Sender<Vec<u8>>,
+}
+
+impl VirtualInterface for MemoryInterface {
+    fn name(&self) -> &str {
+        &self.name
+    }
+
+    fn send(&self, packet: &[u8]) -> io::Result<usize> {
+        self.outbound
+            .send(packet.to_vec())
+            .map_err(|_| io::Error::from(io::ErrorKind::BrokenPipe))?;
+        Ok(packet.len())
+    }
+
+    /// Fails with `BrokenPipe` once the host is dropped.
+    fn recv(&self, buf: &mut [u8]) -> io::Result<usize> {
+        let packet = self
+            .inbound
+            .lock()
+            .expect("poisoned")
+            .recv()
+            .map_err(|_| io::Error::from(io::ErrorKind::BrokenPipe))?;
+        let len = packet.len().min(buf.len());
+        buf[..len].copy_from_slice(&packet[..len]);
+        Ok(len)
+    }
+}
+
+/// The host side of an in-memory interface.
+#[derive(Debug)]
+pub struct MemoryHost {
+    outbound: Sender<Vec<u8>>,
+    inbound: Mutex<Receiver<Vec<u8>>>,
+}
+
+impl MemoryHost {
+    /// Injects a packet, which the VPN reads from the interface.
+    pub fn inject(&self, packet: &[u8]) -> io::Result<()> {
+        self.outbound
+            .send(packet.to_vec())
+            .map_err(|_| io::Error::from(io::ErrorKind::BrokenPipe))
+    }
+
+    /// Waits for a packet written to the interface by the VPN.
+    /// Returns `None` on timeout or if the interface is dropped.
+    pub fn recv_timeout(&self, timeout: Duration) -> Option<Vec<u8>> {
+        self.inbound
+            .lock()
+            .expect("poisoned")
+            .recv_timeout(timeout)
+            .ok()
+    }
+}
@@ -0,0 +1,300 @@
+//! Counters exported in the Prometheus text format over HTTP.
+//!
+//! The exporter only answers `GET /metrics`. It is meant to listen on a local address.
+
+use std::fmt::{Display, Write as _};
+use std::io::{BufRead, BufReader, Read, Write};
+use std::net::{SocketAddr, TcpListener, TcpStream};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::Duration;
+
+use crate::error::{Error, Result};
+use crate::Traffic;
+
+/// The prefix of all metric names.
+const NAMESPACE: &str = "poor_mans_vpn";
+
+/// Why a handshake failed.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum HandshakeFailure {
+    UnknownPeer,
+    Revoked,
+    BadCertificate,
+    BadSignature,
+    Unseal,
+    BrokenMessage,
+}
+
+impl HandshakeFailure {
+    const ALL: [Self; 6] = [
+        Self::UnknownPeer,
+        Self::Revoked,
+        Self::BadCertificate,
+        Self::BadSignature,
+        Self::Unseal,
+        Self::BrokenMessage,
+    ];
+
+    /// Classifies an error during a handshake. Returns `None` for errors of other kinds (e.g. I/O).
+    pub fn of(err: &Error) -> Option<Self> {
+        match err {
+            Error::UnknownPeer => Some(Self::UnknownPeer),
+            Error::Revoked { .. } => Some(Self::Revoked),
+            Error::InvalidCertificate { .. } => Some(Self::BadCertificate),
+            Error::InvalidSignature => Some(Self::BadSignature),
+            Error::Unseal => Some(Self::Unseal),
+            Error::BrokenMessage => Some(Self::BrokenMessage),
+            _ => None,
+        }
+    }
+
+    fn label(self) -> &'static str {
+        match self {
+            Self::UnknownPeer => "unknown_peer",
+            Self::Revoked => "revoked",
+            Self::BadCertificate => "bad_certificate",
+            Self::BadSignature => "bad_signature",
+            Self::Unseal => "unseal",
+            Self::BrokenMessage => "broken_message",
+        }
+    }
+}
+
+/// Why a packet was dropped instead of being forwarded.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum DropReason {
+    /// No peer is routed to the destination (or from the source).
+    NoRoute,
+    /// The peer has no session.
+    NoSession,
+    /// The packet failed to be decrypted.
+    Unseal,
+    /// The packet was a copy of one already received.
+    Replayed,
+    /// The peer sent a packet from an address it is not allowed to use.
+    ForbiddenSource,
+}
+
+impl DropReason {
+    const ALL: [Self; 5] = [
+        Self::NoRoute,
+        Self::NoSession,
+        Self::Unseal,
+        Self::Replayed,
+        Self::ForbiddenSource,
+    ];
+
+    fn label(self) -> &'static str {
+        match self {
+            Self::NoRoute => "no_route",
+            Self::NoSession => "no_session",
+            Self::Unseal => "unseal",
+            Self::Replayed => "replayed",
+            Self::ForbiddenSource => "forbidden_source",
+        }
+    }
+}
+
+/// Event counters shared among threads.
+#[derive(Debug, Default)]
+pub struct Metrics {
+    handshakes: AtomicU64,
+    handshake_failures: [AtomicU64; HandshakeFailure::ALL.len()],
+    malformed_datagrams: AtomicU64,
+    drops: [AtomicU64; DropReason::ALL.len()],
+}
+
+impl Metrics {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn handshake_completed(&self) {
+        self.handshakes.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn handshake_failed(&self, reason: HandshakeFailure) {
+        self.handshake_failures[reason as usize].fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Counts a datagram which could not be decoded as a message (e.g. noise from anyone).
+    pub fn malformed_datagram(&self) {
+        self.malformed_datagrams.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn dropped(&self, reason: DropReason) {
+        self.drops[reason as usize].fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Writes the counters to the exposition.
+    pub fn write_to(&self, out: &mut Exposition) {
+        out.family("handshakes_total", "counter", "Completed handshakes.");
+        out.sample(
+            "handshakes_total",
+            &[],
+            self.handshakes.load(Ordering::Relaxed),
+        );
+
+        out.family(
+            "handshake_failures_total",
+            "counter",
+            "Failed handshakes by reason.",
+        );
+        for reason in HandshakeFailure::ALL {
+            let count = self.handshake_failures[reason as usize].load(Ordering::Relaxed);
+            out.sample(
+                "handshake_failures_total",
+                &[("reason", reason.label())],
+                count,
+            );
+        }
+
+        out.family(
+            "malformed_datagrams_total",
+            "counter",
+            "Datagrams which could not be decoded.",
+        );
+        out.sample(
+            "malformed_datagrams_total",
+            &[],
+            self.malformed_datagrams.load(Ordering::Relaxed),
+        );
+
+        out.family(
+            "dropped_packets_total",
+            "counter",
+            "Packets dropped instead of being forwarded by reason.",
+        );
+        for reason in DropReason::ALL {
+            let count = self.drops[reason as usize].load(Ordering::Relaxed);
+            out.sample(
+                "dropped_packets_total",
+                &[("reason", reason.label())],
+                count,
+            );
+        }
+    }
+}
+
+/// A text in the Prometheus exposition format.
+#[derive(Debug, Default)]
+pub struct Exposition {
+    text: String,
+}
+
+impl Exposition {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Starts a family of samples. `kind` is either `counter` or `gauge`.
+    pub fn family(&mut self, name: &str, kind: &str, help: &str) {
+        let _ = writeln!(self.text, "# HELP {}_{} {}", NAMESPACE, name, help);
+        let _ = writeln!(self.text, "# TYPE {}_{} {}", NAMESPACE, name, kind);
+    }
+
+    pub fn sample<V: Display>(&mut self, name: &str, labels: &[(&str, &str)], value: V) {
+        let _ = write!(self.text, "{}_{}", NAMESPACE, name);
+        if !labels.is_empty() {
+            let labels: Vec<String> = labels
+                .iter()
+                .map(|(key, value)| format!("{}=\"{}\"", key, escape(value)))
+                .collect();
+            let _ = write!(self.text, "{{{}}}", labels.join(","));
+        }
+        let _ = writeln!(self.text, " {}", value);
+    }
+
+    /// Writes the traffic of each peer, labeled with `peer`.
+    pub fn peer_traffic(&mut self, peers: &[(String, Traffic)]) {
+        let rx_bytes = "Bytes received from the peer.";
+        self.traffic_family("peer_rx_bytes_total", rx_bytes, peers, |t| t.rx_bytes);
+        let rx_packets = "Packets received from the peer.";
+        self.traffic_family("peer_rx_packets_total", rx_packets, peers, |t| t.rx_packets);
+        let tx_bytes = "Bytes sent to the peer.";
+        self.traffic_family("peer_tx_bytes_total", tx_bytes, peers, |t| t.tx_bytes);
+        let tx_packets = "Packets sent to the peer.";
+        self.traffic_family("peer_tx_packets_total", tx_packets, peers, |t| t.tx_packets);
+    }
+
+    fn traffic_family<F>(&mut self, name: &str, help: &str, peers: &[(String, Traffic)], value: F)
+    where
+        F: Fn(&Traffic) -> u64,
+    {
+        self.family(name, "counter", help);
+        for (peer, traffic) in peers {
+            self.sample(name, &[("peer", peer)], value(traffic));
+        }
+    }
+
+    pub fn into_string(self) -> String {
+        self.text
+    }
+}
+
+fn escape(value: &str) -> String {
+    value
+        .replace('\\', "\\\\")
+        .replace('"', "\\\"")
+        .replace('\n', "\\n")
+}
+
+/// Requests are served one by one, so a slow scraper is disconnected after this period.
+const READ_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// The maximum length of a request including the headers.
+const MAX_REQUEST_LEN: u64 = 16 * 1024;
+
+/// Listens on the given address, and spawns a thread which serves `GET /metrics`
+/// with the text made by `render`.
+pub fn serve<F>(addr: SocketAddr, render: F) -> Result<()>
+where
+    F: Fn() -> String + Send + 'static,
+{
+    let listener = TcpListener::bind(addr)?;
+    log::info!("metrics: http://{}/metrics", addr);
+
+    std::thread::spawn(move || {
+        for stream in listener.incoming() {
+            let result = stream
+                .map_err(Error::from)
+                .and_then(|stream| serve_one(stream, &render));
+            if let Err(err) = result {
+                log::debug!("metrics: {}", err);
+            }
+        }
+    });
+    Ok(())
+}
+
+fn serve_one<F: Fn() -> String>(mut stream: TcpStream, render: &F) -> Result<()> {
+    stream.set_read_timeout(Some(READ_TIMEOUT))?;
+    stream.set_write_timeout(Some(READ_TIMEOUT))?;
+    // reads end at the cap as if the connection were closed
+    let mut reader = BufReader::new((&stream).take(MAX_REQUEST_LEN));
+    let mut request_line = String::new();
+    reader.read_line(&mut request_line)?;
+    // skip the headers
+    let mut header = String::new();
+    while reader.read_line(&mut header)? > 2 {
+        header.clear();
+    }
+
+    let mut words = request_line.split_whitespace();
+    let response = match (words.next(), words.next()) {
+        (Some("GET"), Some("/metrics")) => {
+            let body = render();
+            format!(
+                "HTTP/1.1 200 OK\r\n\
+                 Content-Type: text/plain; version=0.0.4\r\n\
+                 Content-Length: {}\r\n\
+                 Connection: close\r\n\r\n{}",
+                body.len(),
+                body
+            )
+        }
+        _ => "HTTP/1.1 404 Not Found\r\nContent-Length: 0\r\nConnection: close\r\n\r\n".to_owned(),
+    };
+    stream.write_all(response.as_bytes())?;
+    Ok(())
+}
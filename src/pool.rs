@@ -0,0 +1,171 @@
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, HashSet};
+use std::net::Ipv4Addr;
+use std::path::PathBuf;
+
+use crate::error::{Error, Result};
+use crate::route::Ipv4Cidr;
+
+#[derive(Debug, Default, Serialize, Deserialize)]
+struct LeaseFile {
+    /// Leased addresses keyed by the hex-encoded public key of each peer.
+    leases: BTreeMap<String, Ipv4Addr>,
+}
+
+/// A pool of addresses leased to peers.
+/// A peer always gets the same address once leased, and the leases are persisted to a file.
+#[derive(Debug)]
+pub struct AddressPool {
+    prefix: Ipv4Cidr,
+    reserved: HashSet<Ipv4Addr>,
+    path: PathBuf,
+    leases: BTreeMap<String, Ipv4Addr>,
+}
+
+impl AddressPool {
+    /// Opens a pool of the given prefix, loading existing leases from the file at `path`.
+    /// The `reserved` addresses are never leased.
+    pub fn open<P: Into<PathBuf>>(
+        prefix: Ipv4Cidr,
+        reserved: HashSet<Ipv4Addr>,
+        path: P,
+    ) -> Result<Self> {
+        let path = path.into();
+        let leases = match std::fs::read(&path) {
+            Ok(toml) => {
+                let file: LeaseFile =
+                    toml::from_slice(&toml).map_err(|_| Error::InvalidLeaseFile)?;
+                file.leases
+            }
+            Err(err) if err.kind() == std::io::ErrorKind::NotFound => BTreeMap::new(),
+            Err(err) => return Err(err.into()),
+        };
+        let mut pool = Self {
+            prefix,
+            reserved,
+            path,
+            leases,
+        };
+        // addresses given to peers statically after they were leased
+        let before = pool.leases.len();
+        let reserved = &pool.reserved;
+        pool.leases.retain(|_, addr| !reserved.contains(addr));
+        if pool.leases.len() != before {
+            log::warn!("dropped leases of reserved addresses");
+            pool.save()?;
+        }
+        Ok(pool)
+    }
+
+    /// Returns the address leased to the peer of the given public key,
+    /// or leases a new one if the peer has none.
+    pub fn lease(&mut self, pubkey: &[u8]) -> Result<Ipv4Addr> {
+        let key = crate::crypto::to_hex(pubkey);
+        if let Some(&addr) = self.leases.get(&key) {
+            if self.prefix.contains(addr) && !self.reserved.contains(&addr) {
+                return Ok(addr);
+            }
+        }
+
+        self.leases.remove(&key);
+        let leased: HashSet<Ipv4Addr> = self.leases.values().copied().collect();
+        let first = u32::from(self.prefix.network());
+        let size = 1u64 << (32 - self.prefix.prefix_len());
+        // skip the network and broadcast addresses
+        let addr = (1..size.saturating_sub(1))
+            .map(|i| Ipv4Addr::from(first + i as u32))
+            .find(|addr| !leased.contains(addr) && !self.reserved.contains(addr))
+            .ok_or(Error::PoolExhausted)?;
+
+        self.leases.insert(key, addr);
+        self.save()?;
+        Ok(addr)
+    }
+
+    fn save(&self) -> Result<()> {
+        let file = LeaseFile {
+            leases: self.leases.clone(),
+        };
+        let toml = toml::to_string(&file).expect("serialize");
+        std::fs::write(&self.path, toml)?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A lease file in the temporary directory, removed on drop.
+    struct LeasePath(PathBuf);
+
+    impl LeasePath {
+        fn new(name: &str) -> Self {
+            let file = format!("poor-mans-vpn-leases-{}-{}", std::process::id(), name);
+            let path = std::env::temp_dir().join(file);
+            let _ = std::fs::remove_file(&path);
+            Self(path)
+        }
+    }
+
+    impl Drop for LeasePath {
+        fn drop(&mut self) {
+            let _ = std::fs::remove_file(&self.0);
+        }
+    }
+
+    fn open(prefix: &str, reserved: &[&str], path: &LeasePath) -> AddressPool {
+        let reserved = reserved.iter().map(|addr| addr.parse().unwrap()).collect();
+        AddressPool::open(prefix.parse().unwrap(), reserved, &path.0).unwrap()
+    }
+
+    fn addr(s: &str) -> Ipv4Addr {
+        s.parse().unwrap()
+    }
+
+    #[test]
+    fn lease_is_sticky_across_reopen() {
+        let path = LeasePath::new("sticky");
+        let mut pool = open("10.0.0.0/29", &[], &path);
+        let first = pool.lease(&[1; 32]).unwrap();
+        let second = pool.lease(&[2; 32]).unwrap();
+        assert_ne!(first, second);
+        assert_eq!(pool.lease(&[1; 32]).unwrap(), first);
+
+        let mut pool = open("10.0.0.0/29", &[], &path);
+        assert_eq!(pool.lease(&[2; 32]).unwrap(), second);
+        assert_eq!(pool.lease(&[1; 32]).unwrap(), first);
+    }
+
+    #[test]
+    fn reserved_addresses_are_skipped() {
+        let path = LeasePath::new("reserved");
+        let mut pool = open("10.0.0.0/29", &["10.0.0.1", "10.0.0.2"], &path);
+        assert_eq!(pool.lease(&[1; 32]).unwrap(), addr("10.0.0.3"));
+
+        // an address given statically after it was leased is leased again
+        let mut pool = open("10.0.0.0/29", &["10.0.0.3"], &path);
+        assert_eq!(pool.lease(&[1; 32]).unwrap(), addr("10.0.0.1"));
+    }
+
+    #[test]
+    fn network_and_broadcast_addresses_are_not_leased() {
+        let path = LeasePath::new("ends");
+        let mut pool = open("10.0.0.0/30", &[], &path);
+        assert_eq!(pool.lease(&[1; 32]).unwrap(), addr("10.0.0.1"));
+        assert_eq!(pool.lease(&[2; 32]).unwrap(), addr("10.0.0.2"));
+        assert!(matches!(pool.lease(&[3; 32]), Err(Error::PoolExhausted)));
+    }
+
+    #[test]
+    fn exhausted_pool_is_reported() {
+        let path = LeasePath::new("exhausted");
+        let mut pool = open("10.0.0.0/29", &["10.0.0.1", "10.0.0.2", "10.0.0.3"], &path);
+        for i in 0..3 {
+            pool.lease(&[i; 32]).unwrap();
+        }
+        assert!(matches!(pool.lease(&[9; 32]), Err(Error::PoolExhausted)));
+        // existing leases are still given
+        assert_eq!(pool.lease(&[0; 32]).unwrap(), addr("10.0.0.4"));
+    }
+}
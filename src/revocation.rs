@@ -0,0 +1,223 @@
+//! A list of revoked peer keys (e.g. of a stolen laptop), checked by the server.
+//!
+//! Each line revokes a key:
+//! ```text
+//! <fingerprint> <date> <reason>
+//! ```
+//! `<date>` is when the key was revoked (`YYYY-MM-DD`), and `<reason>` is the rest of the line.
+//! Empty lines and lines starting with `#` are ignored.
+//!
+//! The server re-reads the list whenever its modification time changes, so that a key is revoked
+//! by appending a line, without touching `[[peers]]` or restarting.
+
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+use std::time::SystemTime;
+
+use crate::crypto::Fingerprint;
+use crate::error::{Error, Result};
+
+/// A revoked key.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Revocation {
+    pub fingerprint: Fingerprint,
+    pub date: String,
+    pub reason: String,
+}
+
+impl std::fmt::Display for Revocation {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(f, "{} {} {}", self.fingerprint, self.date, self.reason)
+    }
+}
+
+impl std::str::FromStr for Revocation {
+    type Err = Error;
+    fn from_str(s: &str) -> Result<Self> {
+        let invalid = || Error::InvalidRevocationList { line: s.to_owned() };
+        let (fingerprint, rest) = s
+            .trim()
+            .split_once(char::is_whitespace)
+            .ok_or_else(invalid)?;
+        let rest = rest.trim_start();
+        let (date, reason) = rest.split_once(char::is_whitespace).unwrap_or((rest, ""));
+        if !is_date(date) {
+            return Err(invalid());
+        }
+        let fingerprint = fingerprint.parse()?;
+        let reason = reason.trim();
+        Ok(Self {
+            fingerprint,
+            date: date.to_owned(),
+            reason: reason.to_owned(),
+        })
+    }
+}
+
+/// Tells whether a string looks like `YYYY-MM-DD`.
+fn is_date(s: &str) -> bool {
+    let bytes = s.as_bytes();
+    bytes.len() == 10
+        && bytes.iter().enumerate().all(|(i, &b)| match i {
+            4 | 7 => b == b'-',
+            _ => b.is_ascii_digit(),
+        })
+}
+
+/// Reads all revocations in a file.
+pub fn read<P: AsRef<Path>>(path: P) -> Result<Vec<Revocation>> {
+    std::fs::read_to_string(path)?
+        .lines()
+        .map(str::trim)
+        .filter(|line| !line.is_empty() && !line.starts_with('#'))
+        .map(str::parse)
+        .collect()
+}
+
+/// A revocation list file, re-read when it is modified.
+#[derive(Debug)]
+pub struct RevocationList {
+    path: PathBuf,
+    /// The modification time of the file when it was read.
+    modified: Option<SystemTime>,
+    revoked: HashMap<Fingerprint, Revocation>,
+}
+
+impl RevocationList {
+    /// Reads the list. It fails if the file is missing or malformed.
+    pub fn open<P: AsRef<Path>>(path: P) -> Result<Self> {
+        let mut list = Self {
+            path: path.as_ref().to_owned(),
+            modified: None,
+            revoked: HashMap::new(),
+        };
+        list.refresh()?;
+        Ok(list)
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// Re-reads the file if it was modified. Returns whether it was re-read.
+    /// On failure (e.g. while the file is being edited), the revocations read before are kept.
+    pub fn refresh(&mut self) -> Result<bool> {
+        let modified = std::fs::metadata(&self.path)?.modified()?;
+        if self.modified == Some(modified) {
+            return Ok(false);
+        }
+        self.revoked = read(&self.path)?
+            .into_iter()
+            .map(|revocation| (revocation.fingerprint, revocation))
+            .collect();
+        self.modified = Some(modified);
+        log::info!(
+            "{} revoked keys in {}",
+            self.revoked.len(),
+            self.path.display()
+        );
+        Ok(true)
+    }
+
+    /// Returns the revocation of the key if it is revoked.
+    pub fn get(&self, fingerprint: &Fingerprint) -> Option<&Revocation> {
+        self.revoked.get(fingerprint)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+
+    const FINGERPRINT: &str = "2b3ea81d8d2813d2cfa2354f92c7802893e5bbeda910fcceba4be5d8369f8794";
+
+    fn temp_file(name: &str, content: &str) -> PathBuf {
+        let path = std::env::temp_dir().join(format!(
+            "poor-mans-vpn-revocation-{}-{}",
+            std::process::id(),
+            name
+        ));
+        std::fs::write(&path, content).unwrap();
+        path
+    }
+
+    #[test]
+    fn parse_line() {
+        let line = format!("{}  2024-05-01   laptop stolen ", FINGERPRINT);
+        let revocation: Revocation = line.parse().unwrap();
+        assert_eq!(revocation.fingerprint, FINGERPRINT.parse().unwrap());
+        assert_eq!(revocation.date, "2024-05-01");
+        assert_eq!(revocation.reason, "laptop stolen");
+        assert_eq!(
+            revocation.to_string(),
+            format!("{} 2024-05-01 laptop stolen", FINGERPRINT)
+        );
+    }
+
+    #[test]
+    fn parse_without_reason() {
+        let revocation: Revocation = format!("{} 2024-05-01", FINGERPRINT).parse().unwrap();
+        assert_eq!(revocation.date, "2024-05-01");
+        assert_eq!(revocation.reason, "");
+    }
+
+    #[test]
+    fn parse_rejects_malformed() {
+        assert!(matches!(
+            "abcd 2024-05-01 lost".parse::<Revocation>(),
+            Err(Error::InvalidFingerprint { .. })
+        ));
+        for line in [
+            FINGERPRINT.to_owned(),
+            format!("{} 2024/05/01 lost", FINGERPRINT),
+            format!("{} lost", FINGERPRINT),
+        ] {
+            assert!(matches!(
+                line.parse::<Revocation>(),
+                Err(Error::InvalidRevocationList { .. })
+            ));
+        }
+    }
+
+    #[test]
+    fn date_format() {
+        assert!(is_date("2024-05-01"));
+        assert!(!is_date("2024-5-01"));
+        assert!(!is_date("2024-05-01x"));
+        assert!(!is_date("2024_05_01"));
+        assert!(!is_date("２０２４-05-01"));
+    }
+
+    #[test]
+    fn read_skips_comments_and_accepts_crlf() {
+        let content = format!(
+            "# revoked keys\r\n\r\n  # indented comment\r\n{} 2024-05-01 lost\r\n",
+            FINGERPRINT
+        );
+        let path = temp_file("crlf", &content);
+        let revocations = read(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(revocations.len(), 1);
+        assert_eq!(revocations[0].reason, "lost");
+    }
+
+    #[test]
+    fn refresh_keeps_old_list_on_error() {
+        let path = temp_file("refresh", &format!("{} 2024-05-01 lost\n", FINGERPRINT));
+        let mut list = RevocationList::open(&path).unwrap();
+        let fingerprint = FINGERPRINT.parse().unwrap();
+        assert!(list.get(&fingerprint).is_some());
+        assert!(!list.refresh().unwrap());
+
+        std::fs::write(&path, "broken line\n").unwrap();
+        // the modification time may not change within the resolution of the file system
+        let file = std::fs::File::options().write(true).open(&path).unwrap();
+        file.set_modified(SystemTime::now() + Duration::from_secs(10))
+            .unwrap();
+        let result = list.refresh();
+        std::fs::remove_file(&path).unwrap();
+        assert!(matches!(result, Err(Error::InvalidRevocationList { .. })));
+        assert!(list.get(&fingerprint).is_some());
+    }
+}
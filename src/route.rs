@@ -0,0 +1,247 @@
+use serde::{Deserialize, Serialize};
+use std::net::Ipv4Addr;
+
+use crate::error::{Error, Result};
+
+/// An IPv4 network prefix in CIDR notation (e.g. "192.168.0.0/24").
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
+#[serde(try_from = "String", into = "String")]
+pub struct Ipv4Cidr {
+    addr: Ipv4Addr,
+    prefix_len: u8,
+}
+
+impl Ipv4Cidr {
+    /// Creates a prefix. Host bits of `addr` are cleared.
+    pub fn new(addr: Ipv4Addr, prefix_len: u8) -> Result<Self> {
+        if prefix_len > 32 {
+            return Err(Error::InvalidPrefix {
+                prefix: format!("{}/{}", addr, prefix_len),
+            });
+        }
+        let addr = Ipv4Addr::from(u32::from(addr) & Self::mask(prefix_len));
+        Ok(Self { addr, prefix_len })
+    }
+
+    /// Creates a prefix which covers only the given address.
+    pub fn host(addr: Ipv4Addr) -> Self {
+        Self {
+            addr,
+            prefix_len: 32,
+        }
+    }
+
+    pub fn network(&self) -> Ipv4Addr {
+        self.addr
+    }
+
+    pub fn prefix_len(&self) -> u8 {
+        self.prefix_len
+    }
+
+    /// Returns true if the given address belongs to this prefix.
+    pub fn contains(&self, addr: Ipv4Addr) -> bool {
+        u32::from(addr) & Self::mask(self.prefix_len) == u32::from(self.addr)
+    }
+
+    /// Returns true if the prefixes share any address, i.e. one contains the other.
+    pub fn overlaps(&self, other: &Ipv4Cidr) -> bool {
+        self.contains(other.addr) || other.contains(self.addr)
+    }
+
+    fn mask(prefix_len: u8) -> u32 {
+        u32::MAX.checked_shl(32 - prefix_len as u32).unwrap_or(0)
+    }
+}
+
+impl std::fmt::Display for Ipv4Cidr {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(f, "{}/{}", self.addr, self.prefix_len)
+    }
+}
+
+impl std::str::FromStr for Ipv4Cidr {
+    type Err = Error;
+    fn from_str(s: &str) -> Result<Self> {
+        let invalid = || Error::InvalidPrefix {
+            prefix: s.to_owned(),
+        };
+        match s.split_once('/') {
+            // a bare address is treated as a host prefix
+            None => Ok(Self::host(s.parse().map_err(|_| invalid())?)),
+            Some((addr, len)) => {
+                let addr = addr.parse().map_err(|_| invalid())?;
+                let len = len.parse().map_err(|_| invalid())?;
+                Self::new(addr, len)
+            }
+        }
+    }
+}
+
+impl TryFrom<String> for Ipv4Cidr {
+    type Error = Error;
+    fn try_from(s: String) -> Result<Self> {
+        s.parse()
+    }
+}
+
+impl From<Ipv4Cidr> for String {
+    fn from(cidr: Ipv4Cidr) -> String {
+        cidr.to_string()
+    }
+}
+
+/// A routing table which chooses an entry by the longest prefix match.
+#[derive(Debug)]
+pub struct RoutingTable<T> {
+    // sorted by the prefix length in descending order
+    entries: Vec<(Ipv4Cidr, T)>,
+}
+
+impl<T> Default for RoutingTable<T> {
+    fn default() -> Self {
+        Self {
+            entries: Vec::new(),
+        }
+    }
+}
+
+impl<T> RoutingTable<T> {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Adds a route. An existing route for the same prefix is replaced.
+    pub fn insert(&mut self, prefix: Ipv4Cidr, value: T) {
+        self.entries.retain(|(p, _)| *p != prefix);
+        let pos = self
+            .entries
+            .iter()
+            .position(|(p, _)| p.prefix_len() < prefix.prefix_len())
+            .unwrap_or(self.entries.len());
+        self.entries.insert(pos, (prefix, value));
+    }
+
+    /// Removes all routes whose value satisfies the predicate.
+    pub fn remove_if<F: FnMut(&T) -> bool>(&mut self, mut pred: F) {
+        self.entries.retain(|(_, v)| !pred(v));
+    }
+
+    /// Returns the value of the most specific route to the given address.
+    pub fn lookup(&self, addr: Ipv4Addr) -> Option<&T> {
+        self.entries
+            .iter()
+            .find(|(p, _)| p.contains(addr))
+            .map(|(_, v)| v)
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = &(Ipv4Cidr, T)> {
+        self.entries.iter()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn cidr(s: &str) -> Ipv4Cidr {
+        s.parse().unwrap()
+    }
+
+    fn addr(s: &str) -> Ipv4Addr {
+        s.parse().unwrap()
+    }
+
+    #[test]
+    fn parse_and_mask() {
+        let prefix = cidr("192.168.1.77/24");
+        assert_eq!(prefix.network(), addr("192.168.1.0"));
+        assert_eq!(prefix.prefix_len(), 24);
+        assert_eq!(prefix.to_string(), "192.168.1.0/24");
+
+        let default = cidr("10.1.2.3/0");
+        assert_eq!(default.network(), addr("0.0.0.0"));
+        assert!(default.contains(addr("255.255.255.255")));
+
+        let host = cidr("10.1.2.3/32");
+        assert_eq!(host, Ipv4Cidr::host(addr("10.1.2.3")));
+        assert!(host.contains(addr("10.1.2.3")));
+        assert!(!host.contains(addr("10.1.2.4")));
+    }
+
+    #[test]
+    fn parse_bare_address_as_host() {
+        assert_eq!(cidr("10.0.0.5"), cidr("10.0.0.5/32"));
+    }
+
+    #[test]
+    fn parse_rejects_invalid() {
+        for s in [
+            "10.0.0.0/33",
+            "10.0.0.0/",
+            "10.0.0/8",
+            "10.0.0.0/x",
+            "",
+            "/8",
+        ] {
+            assert!(
+                matches!(s.parse::<Ipv4Cidr>(), Err(Error::InvalidPrefix { .. })),
+                "{:?}",
+                s
+            );
+        }
+        assert!(Ipv4Cidr::new(addr("10.0.0.0"), 33).is_err());
+    }
+
+    #[test]
+    fn contains_respects_the_boundary() {
+        let prefix = cidr("10.0.0.0/23");
+        assert!(prefix.contains(addr("10.0.0.0")));
+        assert!(prefix.contains(addr("10.0.1.255")));
+        assert!(!prefix.contains(addr("10.0.2.0")));
+        assert!(!prefix.contains(addr("9.255.255.255")));
+    }
+
+    #[test]
+    fn overlaps() {
+        assert!(cidr("10.0.0.0/24").overlaps(&cidr("10.0.0.0/24")));
+        assert!(cidr("10.0.0.0/16").overlaps(&cidr("10.0.3.0/24")));
+        assert!(cidr("10.0.3.0/24").overlaps(&cidr("10.0.0.0/16")));
+        assert!(cidr("0.0.0.0/0").overlaps(&cidr("192.168.0.1/32")));
+        assert!(!cidr("10.0.0.0/24").overlaps(&cidr("10.0.1.0/24")));
+    }
+
+    #[test]
+    fn lookup_longest_prefix() {
+        let mut table = RoutingTable::new();
+        table.insert(cidr("0.0.0.0/0"), "default");
+        table.insert(cidr("10.0.0.0/8"), "site");
+        table.insert(cidr("10.1.2.3/32"), "host");
+        table.insert(cidr("10.1.0.0/16"), "subnet");
+
+        assert_eq!(table.lookup(addr("10.1.2.3")), Some(&"host"));
+        assert_eq!(table.lookup(addr("10.1.2.4")), Some(&"subnet"));
+        assert_eq!(table.lookup(addr("10.2.0.1")), Some(&"site"));
+        assert_eq!(table.lookup(addr("8.8.8.8")), Some(&"default"));
+    }
+
+    #[test]
+    fn lookup_without_match() {
+        let mut table = RoutingTable::new();
+        table.insert(cidr("10.0.0.0/8"), 1);
+        assert_eq!(table.lookup(addr("192.168.0.1")), None);
+    }
+
+    #[test]
+    fn insert_replaces_and_remove_if() {
+        let mut table = RoutingTable::new();
+        table.insert(cidr("10.0.0.0/8"), 1);
+        table.insert(cidr("10.0.0.0/8"), 2);
+        assert_eq!(table.iter().count(), 1);
+        assert_eq!(table.lookup(addr("10.0.0.1")), Some(&2));
+
+        table.insert(cidr("10.1.0.0/16"), 3);
+        table.remove_if(|&v| v == 3);
+        assert_eq!(table.lookup(addr("10.1.0.1")), Some(&2));
+    }
+}
@@ -1,13 +1,47 @@
+//! The server, which authenticates peers and forwards packets among them and the host.
+//!
+//! The `server` binary is a thin wrapper of `Server`, which can be embedded as well:
+//! ```no_run
+//! use poor_mans_vpn::server::{Config, Server};
+//!
+//! let config = Config::load("server-config.toml")?;
+//! let server = Server::builder(config)
+//!     .on_event(|event| println!("{:?}", event))
+//!     .start()?;
+//! server.wait()?;
+//! # Ok::<(), poor_mans_vpn::error::Error>(())
+//! ```
+
 use etherparse::Ipv4Header;
-use poor_mans_vpn::{crypto, error, setup_tun, Channel, Message, SealedPacket};
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet};
 use std::net::{Ipv4Addr, SocketAddr, UdpSocket};
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::mpsc::{self, Receiver, Sender};
 use std::sync::{Arc, Mutex};
+use std::thread::JoinHandle;
+use std::time::{Duration, Instant, SystemTime};
 
-use error::{Error, Result};
+use crate::capture::{self, Capture, Direction};
+use crate::cert::{self, Certificate};
+use crate::control::{PeerId, Request, Response};
+use crate::crypto::{self, Fingerprint, PublicKeySource, StaticKeyPair};
+use crate::error::{Error, Result};
+use crate::event::{Callback, Event, EventLog};
+use crate::hook::{self, Hooks};
+use crate::keylog::KeyLogWriter;
+use crate::metrics::{DropReason, Exposition, HandshakeFailure, Metrics};
+use crate::pool::AddressPool;
+use crate::revocation::{Revocation, RevocationList};
+use crate::route::{Ipv4Cidr, RoutingTable};
+use crate::{
+    add_route, del_route, is_timeout, run_command, setup_tun, Channel, Message, PeerSettings,
+    SealedPacket, Teardown, Traffic, VirtualInterface,
+};
 
-const CONFIG_FILE: &str = "server-config.toml";
+/// `session_timeout` must cover this number of heart beats, so that a few lost ones
+/// do not expire a healthy session.
+const MIN_SESSION_TIMEOUT_BEATS: u64 = 3;
 
 mod default_config {
     use std::net::Ipv4Addr;
@@ -29,6 +63,14 @@ mod default_config {
         1300
     }
 
+    pub fn keepalive() -> u64 {
+        5
+    }
+
+    pub fn session_timeout() -> u64 {
+        60
+    }
+
     pub fn server_address() -> Ipv4Addr {
         Ipv4Addr::new(10, 20, 30, 1)
     }
@@ -39,282 +81,1911 @@ mod default_config {
         p.push("privkey.der");
         p
     }
+
+    pub fn lease_file() -> PathBuf {
+        PathBuf::from("leases.toml")
+    }
+
+    pub fn control_socket() -> PathBuf {
+        PathBuf::from(crate::control::SERVER_SOCKET)
+    }
 }
 
-#[derive(Debug, serde::Deserialize)]
-struct Config {
-    server: ServerConfig,
-    peers: Vec<PeerConfig>,
+/// The configuration of the server (`server-config.toml`).
+#[derive(Debug, Clone, serde::Deserialize)]
+pub struct Config {
+    pub server: ServerConfig,
+    pub peers: Vec<PeerConfig>,
 }
 
-#[derive(Debug, serde::Deserialize)]
-struct ServerConfig {
+#[derive(Debug, Clone, serde::Deserialize)]
+pub struct ServerConfig {
     /// The binding address of the server.
     #[serde(default = "default_config::ipv4_addr_unspecified")]
-    bind_address: Ipv4Addr,
+    pub bind_address: Ipv4Addr,
 
     /// The binding port of the server.
     #[serde(default = "default_config::bind_port")]
-    port: u16,
+    pub port: u16,
 
     /// The name of a network interface to be used.
     #[serde(default = "default_config::ifname")]
-    ifname: String,
+    pub ifname: String,
 
     /// The address to be assigned to the VPN interface.
     #[serde(default = "default_config::server_address")]
-    address: Ipv4Addr,
+    pub address: Ipv4Addr,
 
     /// The MTU value of the VPN interface.
     #[serde(default = "default_config::max_transmission_unit")]
-    mtu: u16,
+    pub mtu: u16,
 
     /// A path to the private key of the server.
     #[serde(default = "default_config::private_key")]
-    private_key: PathBuf,
+    pub private_key: PathBuf,
+
+    /// The uplink interface through which traffic from peers to the outside is forwarded (NAT).
+    /// Forwarding is disabled if omitted.
+    pub nat_interface: Option<String>,
+
+    /// Prefixes pushed to every peer as routes through the VPN interface.
+    #[serde(default)]
+    pub routes: Vec<Ipv4Cidr>,
+
+    /// DNS servers pushed to every peer.
+    #[serde(default)]
+    pub dns: Vec<Ipv4Addr>,
+
+    /// The interval of heart beats (in seconds) pushed to every peer.
+    #[serde(default = "default_config::keepalive")]
+    pub keepalive: u64,
+
+    /// Sessions are expired if the peer sends nothing for this period (in seconds).
+    #[serde(default = "default_config::session_timeout")]
+    pub session_timeout: u64,
+
+    /// A prefix from which addresses are leased to peers without a static address.
+    pub address_pool: Option<Ipv4Cidr>,
+
+    /// A path to the file where the leased addresses are persisted.
+    #[serde(default = "default_config::lease_file")]
+    pub lease_file: PathBuf,
+
+    /// A path to the Unix domain socket to control the server (e.g. with `vpnctl`).
+    #[serde(default = "default_config::control_socket")]
+    pub control_socket: PathBuf,
+
+    /// An address to export metrics for Prometheus (e.g. `127.0.0.1:9586`). Not exported if omitted.
+    pub metrics_address: Option<SocketAddr>,
+
+    /// A path to the file where events (e.g. handshakes) are appended as JSON lines for auditing.
+    pub event_log: Option<PathBuf>,
+
+    /// A path to the pcapng file where decrypted packets are captured from startup.
+    /// The capture of the previous run is overwritten.
+    pub capture_file: Option<PathBuf>,
+
+    /// A path to the list of revoked keys, re-read whenever it is modified.
+    pub revocation_list: Option<PathBuf>,
+
+    /// The public key of the CA in base64, or a path to it.
+    /// Peers absent from `[[peers]]` are accepted with certificates signed by the CA.
+    pub ca_public_key: Option<PublicKeySource>,
+
+    /// A shell command run after the interface is set up.
+    pub on_up: Option<String>,
+
+    /// A shell command run on shutdown.
+    pub on_down: Option<String>,
+
+    /// A shell command run when a peer establishes a session.
+    pub on_peer_connect: Option<String>,
+
+    /// A shell command run when a session is dropped or expires.
+    pub on_peer_disconnect: Option<String>,
 }
 
-#[derive(Debug, serde::Deserialize)]
-struct PeerConfig {
-    /// A address of the peer.
-    address: Ipv4Addr,
+#[derive(Debug, Clone, serde::Deserialize)]
+pub struct PeerConfig {
+    /// A static address of the peer.
+    /// If omitted, an address is leased from the pool.
+    pub address: Option<Ipv4Addr>,
+
+    /// The public key of the peer in base64, or a path to it.
+    pub public_key: PublicKeySource,
 
-    /// A path to the public key of the peer.
-    public_key: PathBuf,
+    /// Prefixes routed to the peer (e.g. a LAN behind it), in addition to its own address.
+    #[serde(default)]
+    pub allowed_ips: Vec<Ipv4Cidr>,
+}
+
+impl Config {
+    /// Reads the configuration from a TOML file.
+    pub fn load<P: AsRef<Path>>(path: P) -> Result<Self> {
+        let config_toml = std::fs::read(path)?;
+        toml::from_slice(&config_toml).map_err(|err| Error::InvalidConfig {
+            msg: err.to_string(),
+        })
+    }
+
+    /// Rejects values which the server or the peers cannot work with.
+    fn validate(&self) -> Result<()> {
+        if self.server.keepalive == 0 {
+            return Err(Error::InvalidConfig {
+                msg: "keepalive must be at least 1 second".to_owned(),
+            });
+        }
+        let min_timeout = self
+            .server
+            .keepalive
+            .saturating_mul(MIN_SESSION_TIMEOUT_BEATS);
+        if self.server.session_timeout < min_timeout {
+            return Err(Error::InvalidConfig {
+                msg: format!(
+                    "session_timeout ({}s) must be at least {} times keepalive ({}s)",
+                    self.server.session_timeout, MIN_SESSION_TIMEOUT_BEATS, self.server.keepalive
+                ),
+            });
+        }
+        Ok(())
+    }
+}
+
+/// A peer registered in the configuration, or enrolled by a certificate.
+#[derive(Debug, Clone, PartialEq)]
+struct KnownPeer {
+    pubkey: Vec<u8>,
+    address: Option<Ipv4Addr>,
+    allowed_ips: Vec<Ipv4Cidr>,
+
+    /// When the certificate which enrolled the peer expires. `None` if registered in the configuration.
+    expires_at: Option<u64>,
+}
+
+/// Loads public keys of the peers and indexes them by the fingerprints.
+fn load_peers(config: &Config) -> Result<HashMap<Fingerprint, KnownPeer>> {
+    let mut known_peers = HashMap::new();
+    for (i, conf) in config.peers.iter().enumerate() {
+        let pubkey = conf.public_key.load().map_err(|err| Error::InvalidConfig {
+            msg: format!("public key of peers[{}] ({}): {}", i, conf.public_key, err),
+        })?;
+        let fingerprint = Fingerprint::of(&pubkey);
+        let peer = KnownPeer {
+            pubkey,
+            address: conf.address,
+            allowed_ips: conf.allowed_ips.clone(),
+            expires_at: None,
+        };
+        check_peer(config, &known_peers, fingerprint, &peer).map_err(|err| {
+            Error::InvalidConfig {
+                msg: format!("peers[{}]: {}", i, err),
+            }
+        })?;
+        if known_peers.insert(fingerprint, peer).is_some() {
+            return Err(Error::Setup {
+                msg: format!("duplicated public key of peers[{}]: {}", i, conf.public_key),
+            });
+        }
+    }
+    Ok(known_peers)
+}
+
+/// Checks that the address of a peer is in the VPN subnet, and that nothing the peer claims
+/// is given to the server, the pool or another peer.
+/// Otherwise the peer chosen for a packet would depend on the order of the peers.
+fn check_peer(
+    config: &Config,
+    known_peers: &HashMap<Fingerprint, KnownPeer>,
+    fingerprint: Fingerprint,
+    peer: &KnownPeer,
+) -> Result<()> {
+    let conflict = |reason: String| Err(Error::ConflictingPeer { reason });
+    if let Some(addr) = peer.address {
+        let server = &config.server;
+        let vpn_subnet = Ipv4Cidr::new(server.address, 24)?;
+        if !vpn_subnet.contains(addr) {
+            return conflict(format!(
+                "address {} of {} is outside the VPN subnet {}",
+                addr, fingerprint, vpn_subnet
+            ));
+        }
+        if addr == server.address {
+            return conflict(format!(
+                "address {} of {} is the address of the server",
+                addr, fingerprint
+            ));
+        }
+        if let Some(pool) = server.address_pool.filter(|pool| pool.contains(addr)) {
+            return conflict(format!(
+                "address {} of {} is in the address pool {}",
+                addr, fingerprint, pool
+            ));
+        }
+    }
+
+    let others = known_peers
+        .iter()
+        .filter(|(&other, _)| other != fingerprint);
+    for (other, other_peer) in others {
+        if let Some(addr) = peer
+            .address
+            .filter(|&addr| other_peer.address == Some(addr))
+        {
+            return conflict(format!(
+                "address {} of {} is the address of {}",
+                addr, fingerprint, other
+            ));
+        }
+        for prefix in peer.allowed_ips.iter() {
+            if let Some(claimed) = other_peer.allowed_ips.iter().find(|p| p.overlaps(prefix)) {
+                return conflict(format!(
+                    "allowed_ips {} of {} overlaps {} of {}",
+                    prefix, fingerprint, claimed, other
+                ));
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Returns prefixes routed to the peers, which need routes on the VPN interface.
+fn routed_prefixes(
+    config: &Config,
+    known_peers: &HashMap<Fingerprint, KnownPeer>,
+) -> Result<HashSet<Ipv4Cidr>> {
+    let vpn_subnet = Ipv4Cidr::new(config.server.address, 24)?;
+    let prefixes = known_peers
+        .values()
+        .flat_map(|peer| peer.allowed_ips.iter().copied())
+        .filter(|prefix| !vpn_subnet.contains(prefix.network()))
+        .collect();
+    Ok(prefixes)
+}
+
+/// Environment variables passed to the hooks of the interface.
+fn interface_env(config: &Config) -> hook::Env {
+    vec![
+        ("VPN_INTERFACE", config.server.ifname.clone()),
+        ("VPN_ADDRESS", config.server.address.to_string()),
+    ]
+}
+
+/// Environment variables passed to the hooks of a peer.
+fn peer_env(config: &Config, addr: Ipv4Addr, pubkey: &[u8], endpoint: SocketAddr) -> hook::Env {
+    let mut env = interface_env(config);
+    env.push(("VPN_PEER_ADDRESS", addr.to_string()));
+    env.push(("VPN_PEER_PUBLIC_KEY", crypto::to_hex(pubkey)));
+    env.push(("VPN_PEER_ENDPOINT", endpoint.to_string()));
+    env
+}
+
+/// Opens the address pool if configured.
+fn open_pool(
+    config: &Config,
+    known_peers: &HashMap<Fingerprint, KnownPeer>,
+) -> Result<Option<AddressPool>> {
+    match config.server.address_pool {
+        Some(prefix) => {
+            let mut reserved: HashSet<Ipv4Addr> = known_peers
+                .values()
+                .filter_map(|peer| peer.address)
+                .collect();
+            reserved.insert(config.server.address);
+            let pool = AddressPool::open(prefix, reserved, &config.server.lease_file)?;
+            Ok(Some(pool))
+        }
+        None => Ok(None),
+    }
 }
 
 struct Peer {
+    fingerprint: Fingerprint,
     sock_addr: SocketAddr,
     session_key: crypto::SessionKey,
+    handshake_at: SystemTime,
+
+    /// When the seed of the Hello message which established the session was generated.
+    /// Hellos with seeds not newer than it are copies or replays.
+    seed_created_at: u64,
+    traffic: Traffic,
+
+    /// When the last heart beat or packet arrived from the peer.
+    last_seen: Instant,
+}
+
+/// The state of the server shared among threads.
+struct State {
+    /// The latest configuration.
+    config: Arc<Config>,
+    known_peers: HashMap<Fingerprint, KnownPeer>,
+    pool: Option<AddressPool>,
+
+    /// Sessions with peers keyed by their addresses.
+    peers: HashMap<Ipv4Addr, Peer>,
+    routes: RoutingTable<Ipv4Addr>,
+
+    /// Traffic through all sessions since the server started.
+    traffic: Traffic,
+    started_at: Instant,
+
+    events: Arc<EventLog>,
+    hooks: Hooks,
+
+    /// The active capture of decrypted packets.
+    capture: Option<Capture>,
+
+    revocations: Option<RevocationList>,
+
+    /// The public key of the CA whose certificates enroll peers.
+    ca_public_key: Option<Vec<u8>>,
+
+    /// The socket used by the interface thread. It is taken on stop to release the port.
+    channel: Option<Channel>,
+
+    /// Whether routes of the host are managed (i.e. the interface is a tun device set up by the server).
+    manage_host: bool,
+}
+
+impl State {
+    /// Rebuilds the table to choose a peer by an inner address (a.k.a. cryptokey routing).
+    /// Routes to peers with leased addresses exist only while they have sessions.
+    fn rebuild_routes(&mut self) {
+        let mut routes = RoutingTable::new();
+        let static_peers = self
+            .known_peers
+            .values()
+            .filter_map(|peer| peer.address.map(|addr| (addr, peer)));
+        let session_peers = self.peers.iter().filter_map(|(&addr, peer)| {
+            let known_peer = self.known_peers.get(&peer.fingerprint)?;
+            Some((addr, known_peer))
+        });
+        for (addr, peer) in static_peers.chain(session_peers) {
+            routes.insert(Ipv4Cidr::host(addr), addr);
+            for &prefix in peer.allowed_ips.iter() {
+                routes.insert(prefix, addr);
+            }
+        }
+        log::debug!("routes: {:?}", routes);
+        self.routes = routes;
+    }
+
+    /// Drops sessions with peers silent longer than `session_timeout`.
+    fn expire_sessions(&mut self) {
+        let timeout = Duration::from_secs(self.config.server.session_timeout);
+        for (addr, peer) in self.remove_sessions(|_, peer| peer.last_seen.elapsed() > timeout) {
+            log::info!("session with {:?} expired", addr);
+            self.events.emit(Event::SessionExpired {
+                fingerprint: peer.fingerprint,
+                endpoint: peer.sock_addr,
+                address: addr,
+                idle_secs: peer.last_seen.elapsed().as_secs(),
+            });
+        }
+    }
+
+    /// Re-reads the revocation list if it was modified, and drops sessions with newly revoked keys.
+    fn check_revocations(&mut self) {
+        let list = match self.revocations.as_mut() {
+            Some(list) => list,
+            None => return,
+        };
+        match list.refresh() {
+            Ok(true) => self.revoke_sessions(),
+            Ok(false) => {}
+            Err(err) => print_error(format!("revocation list {}", list.path().display()), err),
+        }
+    }
+
+    /// Returns the revocation of the key if it is revoked.
+    fn revocation(&self, fingerprint: &Fingerprint) -> Option<&Revocation> {
+        self.revocations.as_ref()?.get(fingerprint)
+    }
+
+    /// Drops sessions with revoked keys.
+    fn revoke_sessions(&mut self) {
+        let revoked: HashSet<Fingerprint> = self
+            .peers
+            .values()
+            .map(|peer| peer.fingerprint)
+            .filter(|fingerprint| self.revocation(fingerprint).is_some())
+            .collect();
+        for (addr, peer) in self.remove_sessions(|_, peer| revoked.contains(&peer.fingerprint)) {
+            let reason = self
+                .revocation(&peer.fingerprint)
+                .map(|revocation| revocation.reason.clone())
+                .unwrap_or_default();
+            log::warn!("session with {:?} dropped: the key was revoked", addr);
+            self.events.emit(Event::SessionRevoked {
+                fingerprint: peer.fingerprint,
+                endpoint: peer.sock_addr,
+                address: addr,
+                reason,
+            });
+        }
+    }
+
+    /// Checks a certificate presented by a peer, and returns the peer it enrolls.
+    fn enroll(&self, fingerprint: Fingerprint, certificate: &Certificate) -> Result<KnownPeer> {
+        let ca_public_key = self.ca_public_key.as_ref().ok_or(Error::UnknownPeer)?;
+        let content = certificate.verify(ca_public_key, SystemTime::now())?;
+        if Fingerprint::of(&content.public_key) != fingerprint {
+            return Err(Error::InvalidCertificate {
+                reason: "issued for another key".to_owned(),
+            });
+        }
+        let peer = KnownPeer {
+            pubkey: content.public_key.to_vec(),
+            address: content.address,
+            allowed_ips: Vec::new(),
+            expires_at: Some(content.expires_at),
+        };
+        check_peer(&self.config, &self.known_peers, fingerprint, &peer).map_err(|err| {
+            Error::InvalidCertificate {
+                reason: err.to_string(),
+            }
+        })?;
+        Ok(peer)
+    }
+
+    /// Forgets peers enrolled by expired certificates, and drops their sessions.
+    fn expire_certificates(&mut self) {
+        let now = crate::unix_time(SystemTime::now());
+        let expired: HashSet<Fingerprint> = self
+            .known_peers
+            .iter()
+            .filter(|(_, peer)| peer.expires_at.is_some_and(|expires_at| expires_at <= now))
+            .map(|(&fingerprint, _)| fingerprint)
+            .collect();
+        if expired.is_empty() {
+            return;
+        }
+        for (addr, _) in self.remove_sessions(|_, peer| expired.contains(&peer.fingerprint)) {
+            log::info!("session with {:?} dropped: the certificate expired", addr);
+        }
+        for fingerprint in expired.iter() {
+            log::info!("certificate of {} expired", fingerprint);
+            self.known_peers.remove(fingerprint);
+        }
+        self.rebuild_routes();
+    }
+
+    /// Removes sessions matching the predicate, and runs `on_peer_disconnect` for each of them.
+    fn remove_sessions<F>(&mut self, pred: F) -> Vec<(Ipv4Addr, Peer)>
+    where
+        F: Fn(Ipv4Addr, &Peer) -> bool,
+    {
+        let addrs: Vec<Ipv4Addr> = self
+            .peers
+            .iter()
+            .filter(|(&addr, peer)| pred(addr, peer))
+            .map(|(&addr, _)| addr)
+            .collect();
+        let removed: Vec<(Ipv4Addr, Peer)> = addrs
+            .into_iter()
+            .filter_map(|addr| self.peers.remove(&addr).map(|peer| (addr, peer)))
+            .collect();
+
+        for (addr, peer) in removed.iter() {
+            if let Some(known_peer) = self.known_peers.get(&peer.fingerprint) {
+                let env = peer_env(&self.config, *addr, &known_peer.pubkey, peer.sock_addr);
+                let hook = self.config.server.on_peer_disconnect.as_deref();
+                self.hooks.spawn(hook, env);
+            }
+        }
+        if !removed.is_empty() {
+            self.rebuild_routes();
+        }
+        removed
+    }
+
+    /// Returns the fingerprint of a registered peer.
+    fn find_peer(&self, id: PeerId) -> Result<Fingerprint> {
+        let found = match id {
+            PeerId::Fingerprint(fingerprint) => Some(fingerprint),
+            PeerId::Address(addr) => {
+                self.peers
+                    .get(&addr)
+                    .map(|peer| peer.fingerprint)
+                    .or_else(|| {
+                        self.known_peers
+                            .iter()
+                            .find(|(_, peer)| peer.address == Some(addr))
+                            .map(|(&fingerprint, _)| fingerprint)
+                    })
+            }
+        };
+        found
+            .filter(|fingerprint| self.known_peers.contains_key(fingerprint))
+            .ok_or(Error::UnknownPeer)
+    }
+
+    /// Drops sessions with the given peer. Returns the number of dropped sessions.
+    fn drop_sessions(&mut self, fingerprint: Fingerprint) -> usize {
+        let dropped = self.remove_sessions(|_, peer| peer.fingerprint == fingerprint);
+        for (addr, _) in dropped.iter() {
+            log::info!("session with {:?} dropped", addr);
+        }
+        dropped.len()
+    }
+
+    fn peer_info(&self, fingerprint: Fingerprint) -> PeerInfo {
+        let known_peer = &self.known_peers[&fingerprint];
+        let session = self
+            .peers
+            .iter()
+            .find(|(_, peer)| peer.fingerprint == fingerprint);
+        PeerInfo {
+            fingerprint,
+            address: session.map(|(&addr, _)| addr).or(known_peer.address),
+            allowed_ips: known_peer.allowed_ips.clone(),
+            connected: session.is_some(),
+            endpoint: session.map(|(_, peer)| peer.sock_addr),
+            handshake_at: session.map(|(_, peer)| crate::unix_time(peer.handshake_at)),
+            traffic: session.map(|(_, peer)| peer.traffic),
+            certificate_expires_at: known_peer.expires_at,
+        }
+    }
+}
+
+/// Details of a peer reported to the control socket.
+#[derive(Debug, serde::Serialize)]
+struct PeerInfo {
+    fingerprint: Fingerprint,
+    address: Option<Ipv4Addr>,
+    allowed_ips: Vec<Ipv4Cidr>,
+    connected: bool,
+    endpoint: Option<SocketAddr>,
+    /// When the session was established (seconds since the UNIX epoch).
+    handshake_at: Option<u64>,
+    traffic: Option<Traffic>,
+    /// When the certificate which enrolled the peer expires (seconds since the UNIX epoch).
+    certificate_expires_at: Option<u64>,
+}
+
+/// Statistics reported to the control socket.
+#[derive(Debug, serde::Serialize)]
+struct Stats {
+    uptime_secs: u64,
+    known_peers: usize,
+    sessions: usize,
+    traffic: Traffic,
+}
+
+/// Serves a request from the control socket.
+/// `Reload` is served by `Server::handle_request`, which knows the configuration file.
+fn handle_request(state: &Mutex<State>, request: Request) -> Result<Response> {
+    let mut state = state.lock().expect("poisoned");
+    match request {
+        Request::ListPeers => {
+            let peers: Vec<PeerInfo> = state
+                .known_peers
+                .keys()
+                .map(|&fingerprint| state.peer_info(fingerprint))
+                .collect();
+            Ok(Response::ok(peers))
+        }
+
+        Request::ShowPeer { peer } => {
+            let fingerprint = state.find_peer(peer)?;
+            Ok(Response::ok(state.peer_info(fingerprint)))
+        }
+
+        Request::KickPeer { peer } => {
+            let fingerprint = state.find_peer(peer)?;
+            if state.drop_sessions(fingerprint) == 0 {
+                return Err(Error::UnknownPeer);
+            }
+            log::info!("control: peer {} kicked", fingerprint);
+            Ok(Response::ok(()))
+        }
+
+        Request::AddPeer {
+            public_key,
+            address,
+            allowed_ips,
+        } => {
+            let pubkey = crypto::from_hex(&public_key)
+                .filter(|key| key.len() == crypto::PUBLIC_KEY_LEN)
+                .ok_or(Error::InvalidPublicKey)?;
+            let fingerprint = Fingerprint::of(&pubkey);
+            if state.known_peers.contains_key(&fingerprint) {
+                return Err(Error::Setup {
+                    msg: format!("peer {} already exists", fingerprint),
+                });
+            }
+
+            let peer = KnownPeer {
+                pubkey,
+                address,
+                allowed_ips,
+                expires_at: None,
+            };
+            check_peer(&state.config, &state.known_peers, fingerprint, &peer)?;
+
+            let old_prefixes = routed_prefixes(&state.config, &state.known_peers)?;
+            state.known_peers.insert(fingerprint, peer);
+            let new_prefixes = routed_prefixes(&state.config, &state.known_peers)?;
+            if state.manage_host {
+                for &prefix in new_prefixes.difference(&old_prefixes) {
+                    add_route(&state.config.server.ifname, prefix)?;
+                }
+            }
+
+            state.pool = open_pool(&state.config, &state.known_peers)?;
+            state.rebuild_routes();
+            log::info!("control: peer {} added", fingerprint);
+            Ok(Response::ok(state.peer_info(fingerprint)))
+        }
+
+        Request::RemovePeer { peer } => {
+            let fingerprint = state.find_peer(peer)?;
+            state.drop_sessions(fingerprint);
+
+            let old_prefixes = routed_prefixes(&state.config, &state.known_peers)?;
+            state.known_peers.remove(&fingerprint);
+            let new_prefixes = routed_prefixes(&state.config, &state.known_peers)?;
+            if state.manage_host {
+                for &prefix in old_prefixes.difference(&new_prefixes) {
+                    del_route(&state.config.server.ifname, prefix)?;
+                }
+            }
+
+            state.pool = open_pool(&state.config, &state.known_peers)?;
+            state.rebuild_routes();
+            log::info!("control: peer {} removed", fingerprint);
+            Ok(Response::ok(()))
+        }
+
+        Request::Stats => {
+            let stats = Stats {
+                uptime_secs: state.started_at.elapsed().as_secs(),
+                known_peers: state.known_peers.len(),
+                sessions: state.peers.len(),
+                traffic: state.traffic,
+            };
+            Ok(Response::ok(stats))
+        }
+
+        // needs the path of the configuration file, which is handled by `Server::handle_request`
+        Request::Reload => Ok(Response::error("reload is not available here")),
+
+        Request::CaptureStart { path } => {
+            state.capture = Some(Capture::create(&path, &state.config.server.ifname)?);
+            Ok(Response::ok(path))
+        }
+
+        Request::CaptureStop => match state.capture.take() {
+            Some(capture) => {
+                log::info!("capture stopped: {:?}", capture.path());
+                Ok(Response::ok(capture.path()))
+            }
+            None => Ok(Response::error("not capturing")),
+        },
+
+        Request::Status | Request::Reconnect | Request::Shutdown => {
+            Ok(Response::error("unsupported command"))
+        }
+    }
+}
+
+/// Applies changes of the peers in a new configuration.
+/// Sessions with removed or modified peers are dropped, and the others are kept intact.
+fn reload(state: &Mutex<State>, config: Config) -> Result<()> {
+    let mut known_peers = load_peers(&config)?;
+    let revocations = open_revocations(&config)?;
+    let ca_public_key = load_ca_public_key(&config)?;
+
+    let mut state = state.lock().expect("poisoned");
+    // peers enrolled by certificates are kept as long as the CA is
+    if ca_public_key.is_some() && ca_public_key == state.ca_public_key {
+        for (&fingerprint, peer) in state.known_peers.iter() {
+            if peer.expires_at.is_some() {
+                known_peers
+                    .entry(fingerprint)
+                    .or_insert_with(|| peer.clone());
+            }
+        }
+    }
+    let old = &state.config.server;
+    let new = &config.server;
+    if (
+        old.bind_address,
+        old.port,
+        &old.ifname,
+        old.address,
+        old.mtu,
+    ) != (
+        new.bind_address,
+        new.port,
+        &new.ifname,
+        new.address,
+        new.mtu,
+    ) || (&old.private_key, &old.nat_interface) != (&new.private_key, &new.nat_interface)
+    {
+        log::warn!("reload: changes of the interface or the socket require restart");
+    }
+
+    let (mut added, mut removed, mut modified) = (0, 0, 0);
+    for (fingerprint, old_peer) in state.known_peers.iter() {
+        match known_peers.get(fingerprint) {
+            Some(new_peer) if new_peer == old_peer => {}
+            Some(_) => {
+                log::info!("reload: peer {} modified", fingerprint);
+                modified += 1;
+            }
+            None => {
+                removed += 1;
+                let rekeyed = old_peer.address.is_some()
+                    && known_peers.iter().any(|(fp, new_peer)| {
+                        new_peer.address == old_peer.address && !state.known_peers.contains_key(fp)
+                    });
+                if rekeyed {
+                    let addr = old_peer.address.expect("static address");
+                    log::info!("reload: peer {} ({:?}) re-keyed", fingerprint, addr);
+                } else {
+                    log::info!("reload: peer {} removed", fingerprint);
+                }
+            }
+        }
+    }
+    for fingerprint in known_peers.keys() {
+        if !state.known_peers.contains_key(fingerprint) {
+            log::info!("reload: peer {} added", fingerprint);
+            added += 1;
+        }
+    }
+
+    let old_prefixes = routed_prefixes(&state.config, &state.known_peers)?;
+    let new_prefixes = routed_prefixes(&config, &known_peers)?;
+    let ifname = &state.config.server.ifname;
+    if state.manage_host {
+        for &prefix in old_prefixes.difference(&new_prefixes) {
+            if let Err(err) = del_route(ifname, prefix) {
+                print_error("reload", err);
+            }
+        }
+        for &prefix in new_prefixes.difference(&old_prefixes) {
+            if let Err(err) = add_route(ifname, prefix) {
+                print_error("reload", err);
+            }
+        }
+    }
+
+    let stale: HashSet<Ipv4Addr> = state
+        .peers
+        .iter()
+        .filter(|(_, peer)| {
+            let old_peer = state.known_peers.get(&peer.fingerprint);
+            old_peer.is_none() || old_peer != known_peers.get(&peer.fingerprint)
+        })
+        .map(|(&addr, _)| addr)
+        .collect();
+    for (addr, _) in state.remove_sessions(|addr, _| stale.contains(&addr)) {
+        log::info!("reload: session with {:?} dropped", addr);
+    }
+
+    state.pool = open_pool(&config, &known_peers)?;
+    state.known_peers = known_peers;
+    state.config = Arc::new(config);
+    state.revocations = revocations;
+    state.ca_public_key = ca_public_key;
+    state.revoke_sessions();
+    state.rebuild_routes();
+    log::info!("reload: done ({} peers)", state.known_peers.len());
+    state.events.emit(Event::ConfigReloaded {
+        peers: state.known_peers.len(),
+        added,
+        removed,
+        modified,
+    });
+    Ok(())
+}
+
+/// Reads the revocation list if configured.
+fn open_revocations(config: &Config) -> Result<Option<RevocationList>> {
+    let path = match &config.server.revocation_list {
+        Some(path) => path,
+        None => return Ok(None),
+    };
+    RevocationList::open(path)
+        .map(Some)
+        .map_err(|err| Error::InvalidConfig {
+            msg: format!("revocation list {}: {}", path.display(), err),
+        })
+}
+
+/// Reads the public key of the CA if configured.
+fn load_ca_public_key(config: &Config) -> Result<Option<Vec<u8>>> {
+    let source = match &config.server.ca_public_key {
+        Some(source) => source,
+        None => return Ok(None),
+    };
+    source.load().map(Some).map_err(|err| Error::InvalidConfig {
+        msg: format!("public key of the CA ({}): {}", source, err),
+    })
 }
 
 fn print_error<D: std::fmt::Display>(ctx: D, err: Error) {
     log::error!("{}: {}", ctx, err);
 }
 
-fn main() -> Result<()> {
-    env_logger::init();
+/// Makes the settings pushed to the peer with the given address.
+fn peer_settings(
+    config: &Config,
+    known_peers: &HashMap<Fingerprint, KnownPeer>,
+    peer: &KnownPeer,
+    addr: Ipv4Addr,
+) -> Result<PeerSettings> {
+    let vpn_subnet = Ipv4Cidr::new(config.server.address, 24)?;
 
-    let config: Config = {
-        let config_toml = std::fs::read(CONFIG_FILE)?;
-        match toml::from_slice(&config_toml) {
-            Ok(conf) => conf,
-            Err(_) => {
-                log::error!("failed to parse {}", CONFIG_FILE);
-                return Ok(());
+    // the subnets behind the other peers are reachable as well
+    let mut routes = config.server.routes.clone();
+    for other in known_peers
+        .values()
+        .filter(|&other| !std::ptr::eq(other, peer))
+    {
+        for &prefix in other.allowed_ips.iter() {
+            if !vpn_subnet.contains(prefix.network()) && !routes.contains(&prefix) {
+                routes.push(prefix);
             }
         }
-    };
-    log::debug!("config: {:#?}", config);
+    }
 
-    let static_key_pair = crypto::StaticKeyPair::from_pkcs8(&config.server.private_key)?;
+    Ok(PeerSettings {
+        address: addr,
+        prefix_len: vpn_subnet.prefix_len(),
+        mtu: config.server.mtu,
+        routes,
+        dns: config.server.dns.clone(),
+        keepalive: config.server.keepalive,
+    })
+}
 
-    let iface = setup_tun(
-        &config.server.ifname,
-        config.server.address,
-        24,
-        config.server.mtu,
-    )?;
-    let iface = Arc::new(iface);
+/// Configures and starts a `Server`.
+pub struct ServerBuilder {
+    config: Config,
+    config_file: Option<PathBuf>,
+    key_pair: Option<StaticKeyPair>,
+    iface: Option<Arc<dyn VirtualInterface>>,
+    teardown: Option<Arc<Mutex<Teardown>>>,
+    callbacks: Vec<Callback>,
+}
 
-    let sock = UdpSocket::bind((config.server.bind_address, config.server.port))?;
-    let mut sock = Channel::new(sock);
+impl ServerBuilder {
+    /// Sets the file which `Server::reload` re-reads.
+    pub fn config_file<P: AsRef<Path>>(mut self, path: P) -> Self {
+        self.config_file = Some(path.as_ref().to_owned());
+        self
+    }
 
-    let peers: HashMap<Ipv4Addr, Peer> = HashMap::new();
-    let peers = Arc::new(Mutex::new(peers));
+    /// Uses the key pair instead of reading `private_key` of the configuration.
+    pub fn key_pair(mut self, key_pair: StaticKeyPair) -> Self {
+        self.key_pair = Some(key_pair);
+        self
+    }
 
-    std::thread::spawn({
-        let iface = iface.clone();
-        let mut sock = sock.clone();
-        let peers = peers.clone();
-        move || -> std::io::Result<()> {
-            loop {
-                let (msg, src_addr) = match sock.recv_from() {
-                    Err(err) => {
-                        print_error("receive", err);
+    /// Uses the interface instead of setting up a tun device.
+    /// The host is left untouched: neither routes nor NAT are configured.
+    pub fn interface(mut self, iface: Arc<dyn VirtualInterface>) -> Self {
+        self.iface = Some(iface);
+        self
+    }
+
+    /// Registers the commands reverting changes to the host to the given teardown
+    /// (e.g. one run on signals by `exit_on_signal`) instead of a new one.
+    pub fn teardown(mut self, teardown: Arc<Mutex<Teardown>>) -> Self {
+        self.teardown = Some(teardown);
+        self
+    }
+
+    /// Registers a function called with every event. It is called on the threads of the server,
+    /// possibly with its state locked, so it must not call back into the server.
+    pub fn on_event<F>(mut self, callback: F) -> Self
+    where
+        F: Fn(&Event) + Send + Sync + 'static,
+    {
+        self.callbacks.push(Box::new(callback));
+        self
+    }
+
+    /// Sets up the interface and the host, and starts serving peers in background threads.
+    /// Changes made to the host are reverted if it fails.
+    pub fn start(self) -> Result<Server> {
+        let teardown = self.teardown.clone().unwrap_or_default();
+        let result = self.start_with(teardown.clone());
+        if result.is_err() {
+            teardown.lock().expect("poisoned").run();
+        }
+        result
+    }
+
+    fn start_with(self, teardown: Arc<Mutex<Teardown>>) -> Result<Server> {
+        self.config.validate()?;
+        let config = Arc::new(self.config);
+        let static_key_pair = match self.key_pair {
+            Some(key_pair) => key_pair,
+            None => StaticKeyPair::from_pkcs8(&config.server.private_key)?,
+        };
+        let known_peers = load_peers(&config)?;
+        let revocations = open_revocations(&config)?;
+        let ca_public_key = load_ca_public_key(&config)?;
+
+        let manage_host = self.iface.is_none();
+        let iface = match self.iface {
+            Some(iface) => iface,
+            None => Arc::new(setup_tun(
+                &config.server.ifname,
+                config.server.address,
+                24,
+                config.server.mtu,
+            )?),
+        };
+
+        if manage_host {
+            setup_host(
+                &config,
+                &known_peers,
+                &mut teardown.lock().expect("poisoned"),
+            )?;
+        }
+
+        let env = interface_env(&config);
+        if let Some(script) = &config.server.on_up {
+            hook::run_hook(script, &env)?;
+        }
+        if let Some(script) = &config.server.on_down {
+            let envs: Vec<(&str, &str)> = env.iter().map(|(k, v)| (*k, v.as_str())).collect();
+            let mut teardown = teardown.lock().expect("poisoned");
+            teardown.push_with_env("sh", &["-c", script], &envs);
+        }
+
+        let sock = UdpSocket::bind((config.server.bind_address, config.server.port))?;
+        let local_addr = sock.local_addr()?;
+        let sock = Channel::new(sock);
+        sock.set_read_timeout(Some(crate::POLL_INTERVAL))?;
+
+        let mut events = EventLog::open(config.server.event_log.as_ref())?;
+        for callback in self.callbacks {
+            events.subscribe(callback);
+        }
+        let events = Arc::new(events);
+
+        let mut state = State {
+            config: config.clone(),
+            pool: open_pool(&config, &known_peers)?,
+            known_peers,
+            peers: HashMap::new(),
+            routes: RoutingTable::new(),
+            traffic: Traffic::default(),
+            started_at: Instant::now(),
+            events: events.clone(),
+            hooks: Hooks::new(),
+            capture: match &config.server.capture_file {
+                Some(path) => Some(Capture::create(path, &config.server.ifname)?),
+                None => None,
+            },
+            channel: Some(sock.clone()),
+            manage_host,
+            revocations,
+            ca_public_key,
+        };
+        state.rebuild_routes();
+
+        let (done, finished) = mpsc::channel();
+        let server = Server {
+            state: Arc::new(Mutex::new(state)),
+            metrics: Arc::new(Metrics::new()),
+            local_addr,
+            config_file: self.config_file,
+            stopped: Arc::new(AtomicBool::new(false)),
+            teardown,
+            receiver: Mutex::new(None),
+            done,
+            finished: Mutex::new(finished),
+        };
+        server.spawn_expiry();
+        let receiver = server.spawn_receiver(sock, iface.clone(), static_key_pair)?;
+        *server.receiver.lock().expect("poisoned") = Some(receiver);
+        server.spawn_sender(iface);
+        log::info!("server started on {}", local_addr);
+        Ok(server)
+    }
+}
+
+/// Enables NAT if configured, and adds routes to the prefixes behind the peers.
+fn setup_host(
+    config: &Config,
+    known_peers: &HashMap<Fingerprint, KnownPeer>,
+    teardown: &mut Teardown,
+) -> Result<()> {
+    let vpn_subnet = Ipv4Cidr::new(config.server.address, 24)?;
+    if let Some(uplink) = &config.server.nat_interface {
+        let ip_forward = std::fs::read_to_string("/proc/sys/net/ipv4/ip_forward")?;
+        let ip_forward = format!("net.ipv4.ip_forward={}", ip_forward.trim());
+        run_command("sysctl", &["-w", "net.ipv4.ip_forward=1"])?;
+        teardown.push("sysctl", &["-w", &ip_forward]);
+
+        let ifname = config.server.ifname.as_str();
+        let rules = [
+            format!(
+                "-t nat POSTROUTING -s {} -o {} -j MASQUERADE",
+                vpn_subnet, uplink
+            ),
+            format!("-t filter FORWARD -i {} -o {} -j ACCEPT", ifname, uplink),
+            format!(
+                "-t filter FORWARD -i {} -o {} -m state --state RELATED,ESTABLISHED -j ACCEPT",
+                uplink, ifname,
+            ),
+        ];
+        for rule in rules.iter() {
+            // rule: [table option, table, chain, rule specification...]
+            let rule: Vec<&str> = rule.split_whitespace().collect();
+            let (table, spec) = rule.split_at(2);
+            let append = [table, &["-A"], spec].concat();
+            let delete = [table, &["-D"], spec].concat();
+            run_command("iptables", &append)?;
+            teardown.push("iptables", &delete);
+        }
+    }
+
+    for prefix in routed_prefixes(config, known_peers)? {
+        add_route(&config.server.ifname, prefix)?;
+    }
+    Ok(())
+}
+
+/// A running server.
+///
+/// It is stopped by `stop` or when dropped. The tun device is closed when the process exits.
+pub struct Server {
+    state: Arc<Mutex<State>>,
+    metrics: Arc<Metrics>,
+    local_addr: SocketAddr,
+    config_file: Option<PathBuf>,
+    stopped: Arc<AtomicBool>,
+    teardown: Arc<Mutex<Teardown>>,
+
+    /// The thread receiving messages from peers, joined on stop.
+    receiver: Mutex<Option<JoinHandle<()>>>,
+
+    /// Tells `wait` that the server stopped, with the error which stopped it if any.
+    done: Sender<Result<()>>,
+    finished: Mutex<Receiver<Result<()>>>,
+}
+
+impl Server {
+    /// Makes a builder of a server with the configuration.
+    pub fn builder(config: Config) -> ServerBuilder {
+        ServerBuilder {
+            config,
+            config_file: None,
+            key_pair: None,
+            iface: None,
+            teardown: None,
+            callbacks: Vec::new(),
+        }
+    }
+
+    /// Returns the address of the UDP socket (e.g. to know the port chosen for port 0).
+    pub fn local_addr(&self) -> SocketAddr {
+        self.local_addr
+    }
+
+    /// Re-reads the configuration file given by `ServerBuilder::config_file` and applies it.
+    pub fn reload(&self) -> Result<()> {
+        let path = self
+            .config_file
+            .as_ref()
+            .ok_or_else(|| Error::InvalidConfig {
+                msg: "no configuration file to reload".to_owned(),
+            })?;
+        self.update_config(Config::load(path)?)
+    }
+
+    /// Applies changes of the peers in a new configuration.
+    /// Changes of the interface or the socket are ignored until restart.
+    pub fn update_config(&self, config: Config) -> Result<()> {
+        config.validate()?;
+        reload(&self.state, config)
+    }
+
+    /// Serves a request from the control socket.
+    pub fn handle_request(&self, request: Request) -> Response {
+        let result = match request {
+            Request::Reload => self.reload().map(|_| Response::ok(())),
+            request => handle_request(&self.state, request),
+        };
+        result.unwrap_or_else(Response::error)
+    }
+
+    /// Renders the metrics in the Prometheus text format.
+    pub fn metrics(&self) -> String {
+        let mut out = Exposition::new();
+        self.metrics.write_to(&mut out);
+
+        let state = self.state.lock().expect("poisoned");
+        out.family("active_sessions", "gauge", "Peers with a session.");
+        out.sample("active_sessions", &[], state.peers.len());
+        let peers: Vec<(String, Traffic)> = state
+            .peers
+            .iter()
+            .map(|(addr, peer)| (addr.to_string(), peer.traffic))
+            .collect();
+        out.peer_traffic(&peers);
+        out.into_string()
+    }
+
+    /// Stops serving peers, releases the socket, and reverts changes made to the host.
+    pub fn stop(&self) {
+        if self.stopped.swap(true, Ordering::SeqCst) {
+            return;
+        }
+        self.state.lock().expect("poisoned").channel = None;
+        if let Some(receiver) = self.receiver.lock().expect("poisoned").take() {
+            let _ = receiver.join();
+        }
+        self.teardown.lock().expect("poisoned").run();
+        let _ = self.done.send(Ok(()));
+        log::info!("server stopped");
+    }
+
+    /// Blocks until the server is stopped, either by `stop` or by an error of the interface.
+    pub fn wait(&self) -> Result<()> {
+        let result = self
+            .finished
+            .lock()
+            .expect("poisoned")
+            .recv()
+            .unwrap_or(Ok(()));
+        self.stop();
+        result
+    }
+
+    /// Spawns a thread which runs `f` until it fails, and then lets `wait` return.
+    fn spawn_worker<F>(&self, f: F) -> JoinHandle<()>
+    where
+        F: FnOnce() -> Result<()> + Send + 'static,
+    {
+        let done = self.done.clone();
+        std::thread::spawn(move || {
+            if let Err(err) = f() {
+                log::error!("server: {}", err);
+                let _ = done.send(Err(err));
+            }
+        })
+    }
+
+    /// Spawns a thread which expires sessions with silent peers.
+    fn spawn_expiry(&self) {
+        let state = self.state.clone();
+        let stopped = self.stopped.clone();
+        std::thread::spawn(move || loop {
+            let interval = state.lock().expect("poisoned").config.server.keepalive;
+            std::thread::sleep(Duration::from_secs(interval.max(1)));
+            if stopped.load(Ordering::SeqCst) {
+                return;
+            }
+            let mut state = state.lock().expect("poisoned");
+            state.expire_sessions();
+            state.expire_certificates();
+            state.check_revocations();
+        });
+    }
+
+    /// Spawns a thread which handles messages from peers.
+    fn spawn_receiver(
+        &self,
+        mut sock: Channel,
+        iface: Arc<dyn VirtualInterface>,
+        static_key_pair: StaticKeyPair,
+    ) -> Result<JoinHandle<()>> {
+        let state = self.state.clone();
+        let (config, events) = {
+            let state = state.lock().expect("poisoned");
+            (state.config.clone(), state.events.clone())
+        };
+        let metrics = self.metrics.clone();
+        let stopped = self.stopped.clone();
+        let keylog = KeyLogWriter::from_env()?;
+        Ok(self.spawn_worker(move || loop {
+            if stopped.load(Ordering::SeqCst) {
+                return Ok(());
+            }
+            let (msg, src_addr) = match sock.recv_from() {
+                Err(Error::Io(err)) if is_timeout(&err) => continue,
+                Err(err) => {
+                    // not a handshake yet: anyone can send a datagram
+                    if let Error::BrokenMessage = err {
+                        metrics.malformed_datagram();
+                    }
+                    print_error("receive", err);
+                    continue;
+                }
+                Ok(pair) => pair,
+            };
+
+            match msg {
+                Message::Hello {
+                    key_hint,
+                    seed: client_seed,
+                    certificate,
+                } => {
+                    log::debug!("Hello message received from: {:?}", src_addr);
+
+                    let revocation = {
+                        // the list is refreshed by the expiry thread, not by anyone sending a Hello
+                        let state = state.lock().expect("poisoned");
+                        state.revocation(&key_hint).cloned()
+                    };
+                    if let Some(revocation) = revocation {
+                        log::warn!("revoked peer: {}", key_hint);
+                        metrics.handshake_failed(HandshakeFailure::Revoked);
+                        let err = Error::Revoked {
+                            date: revocation.date,
+                            reason: revocation.reason,
+                        };
+                        events.emit(Event::HandshakeRejected {
+                            fingerprint: key_hint,
+                            endpoint: src_addr,
+                            reason: err.to_string(),
+                        });
                         continue;
                     }
-                    Ok(pair) => pair,
-                };
 
-                match msg {
-                    Message::Hello {
-                        addr,
-                        seed: client_seed,
-                    } => {
-                        log::debug!("Hello message received from: {:?}", addr);
-
-                        let peer_conf = config.peers.iter().find(|conf| conf.address == addr);
-                        let pubkey = match peer_conf {
-                            None => {
-                                log::warn!("unknown peer: {:?}", addr);
-                                continue;
+                    // The hint only tells which key to try.
+                    // The peer is authenticated by the signature.
+                    let found = {
+                        let state = state.lock().expect("poisoned");
+                        match (state.known_peers.get(&key_hint), &certificate) {
+                            // a certificate does not override the configuration
+                            (Some(peer), _) if peer.expires_at.is_none() => {
+                                Ok((peer.pubkey.clone(), None))
                             }
-                            Some(conf) => std::fs::read(&conf.public_key)?,
-                        };
+                            (_, Some(certificate)) => state
+                                .enroll(key_hint, certificate)
+                                .map(|peer| (peer.pubkey.clone(), Some(peer))),
+                            (Some(peer), None) => Ok((peer.pubkey.clone(), None)),
+                            (None, None) => Err(Error::UnknownPeer),
+                        }
+                    };
+                    let (pubkey, enrolled) = match found {
+                        Err(err) => {
+                            log::warn!("peer {} rejected: {}", key_hint, err);
+                            if let Some(reason) = HandshakeFailure::of(&err) {
+                                metrics.handshake_failed(reason);
+                            }
+                            events.emit(Event::HandshakeRejected {
+                                fingerprint: key_hint,
+                                endpoint: src_addr,
+                                reason: err.to_string(),
+                            });
+                            continue;
+                        }
+                        Ok(found) => found,
+                    };
 
-                        let client_seed = match client_seed.open(&pubkey) {
+                    let client_seed = match client_seed.open(&pubkey) {
+                        Err(err) => {
+                            // the seed may be broken even if the signature is valid
+                            if let Some(reason) = HandshakeFailure::of(&err) {
+                                metrics.handshake_failed(reason);
+                            }
+                            events.emit(Event::HandshakeRejected {
+                                fingerprint: key_hint,
+                                endpoint: src_addr,
+                                reason: err.to_string(),
+                            });
+                            print_error("unseal", err);
+                            continue;
+                        }
+                        Ok(seed) => seed,
+                    };
+
+                    // A copy of the Hello (e.g. duplicated by the network) or a replayed old one
+                    // would replace the session the peer has just accepted with one it never hears of.
+                    let seed_created_at = client_seed.created_at();
+                    let stale = state
+                        .lock()
+                        .expect("poisoned")
+                        .peers
+                        .values()
+                        .any(|peer| {
+                            peer.fingerprint == key_hint && peer.seed_created_at >= seed_created_at
+                        });
+                    if stale {
+                        log::debug!("ignored a Hello from {} not newer than the session", key_hint);
+                        continue;
+                    }
+
+                    let (priv_seed, pub_seed) = crypto::generate_seed_pair();
+                    let mut session_key =
+                        match crypto::SessionKey::server_derive(priv_seed, client_seed) {
                             Err(err) => {
-                                print_error("unseal", err);
+                                metrics.handshake_failed(HandshakeFailure::BrokenMessage);
+                                events.emit(Event::HandshakeRejected {
+                                    fingerprint: key_hint,
+                                    endpoint: src_addr,
+                                    reason: err.to_string(),
+                                });
+                                print_error("derive", err);
                                 continue;
                             }
-                            Ok(seed) => seed,
+                            Ok(key) => key,
                         };
-                        let (priv_seed, pub_seed) = crypto::generate_seed_pair();
-                        let session_key = crypto::SessionKey::server_derive(priv_seed, client_seed);
+                    keylog.write(key_hint, &session_key);
 
-                        let mut peers = peers.lock().expect("poisoned");
-                        peers.insert(
-                            addr,
-                            Peer {
-                                sock_addr: src_addr,
-                                session_key,
-                            },
-                        );
+                    let mut state = state.lock().expect("poisoned");
+                    if let Some(peer) = enrolled {
+                        let configured = state
+                            .known_peers
+                            .get(&key_hint)
+                            .is_some_and(|peer| peer.expires_at.is_none());
+                        if !configured {
+                            let moved = state
+                                .known_peers
+                                .get(&key_hint)
+                                .is_some_and(|known| known.address != peer.address);
+                            if moved {
+                                // the old address would stay routed to the peer until expiry
+                                let removed = state
+                                    .remove_sessions(|_, session| session.fingerprint == key_hint);
+                                for (addr, _) in removed {
+                                    log::info!(
+                                        "session with {:?} dropped: the certificate assigns another address",
+                                        addr
+                                    );
+                                }
+                            }
+                            let expires_at = peer.expires_at.unwrap_or_default();
+                            if state.known_peers.insert(key_hint, peer).is_none() {
+                                log::info!(
+                                    "peer {} enrolled by a certificate until {}",
+                                    key_hint,
+                                    cert::format_date(expires_at)
+                                );
+                            }
+                            state.rebuild_routes();
+                        }
+                    }
+                    let State {
+                        config,
+                        known_peers,
+                        pool,
+                        peers,
+                        routes,
+                        hooks,
+                        ..
+                    } = &mut *state;
 
-                        let signed_seed = static_key_pair.sign(&pub_seed);
-                        let reply = Message::HelloReply { seed: signed_seed };
-                        if let Err(err) = sock.send_to(&reply, src_addr) {
-                            print_error("send", err);
+                    // the peer may have been removed by a reload in the meantime
+                    let known_peer = match known_peers.get(&key_hint) {
+                        None => {
+                            log::warn!("unknown peer: {}", key_hint);
+                            metrics.handshake_failed(HandshakeFailure::UnknownPeer);
+                            events.emit(Event::HandshakeRejected {
+                                fingerprint: key_hint,
+                                endpoint: src_addr,
+                                reason: Error::UnknownPeer.to_string(),
+                            });
                             continue;
                         }
-                        log::info!("new connection with {:?} (socket: {:?})", addr, src_addr);
-                    }
+                        Some(peer) => peer,
+                    };
 
-                    Message::HeartBeat => {
-                        log::trace!("HeartBeat from {:?}", src_addr);
-                        if let Err(err) = sock.send_to(&Message::HeartBeat, src_addr) {
-                            print_error("send", err);
+                    let addr = match (known_peer.address, pool.as_mut()) {
+                        (Some(addr), _) => addr,
+                        (None, Some(pool)) => match pool.lease(&known_peer.pubkey) {
+                            Err(err) => {
+                                events.emit(Event::HandshakeRejected {
+                                    fingerprint: key_hint,
+                                    endpoint: src_addr,
+                                    reason: err.to_string(),
+                                });
+                                print_error("lease", err);
+                                continue;
+                            }
+                            Ok(addr) => addr,
+                        },
+                        (None, None) => {
+                            log::warn!("no address for {}", key_hint);
+                            events.emit(Event::HandshakeRejected {
+                                fingerprint: key_hint,
+                                endpoint: src_addr,
+                                reason: "no address".to_owned(),
+                            });
+                            continue;
+                        }
+                    };
+
+                    let settings = match peer_settings(config, known_peers, known_peer, addr) {
+                        Err(err) => {
+                            print_error("settings", err);
                             continue;
                         }
+                        Ok(settings) => settings,
+                    };
+                    log::debug!("settings for {:?}: {:?}", addr, settings);
+                    let settings = session_key
+                        .seal(PeerSettings::AAD, settings)
+                        .expect("Failed to encrypt");
+
+                    // never expected after the checks of the peers, but a session must not be taken over
+                    if let Some(other) = peers.get(&addr).filter(|p| p.fingerprint != key_hint) {
+                        log::error!(
+                            "address {:?} of {} is used by the session with {}",
+                            addr,
+                            key_hint,
+                            other.fingerprint
+                        );
+                        events.emit(Event::HandshakeRejected {
+                            fingerprint: key_hint,
+                            endpoint: src_addr,
+                            reason: format!("address {} is in use", addr),
+                        });
+                        continue;
                     }
 
-                    Message::Packet(mut sealed_packet) => {
-                        let mut peers = peers.lock().expect("poisoned");
-                        let packet: Vec<u8> = {
-                            let src = sealed_packet.source;
-                            let session_key = if let Some(peer) = peers.get(&src) {
-                                &peer.session_key
-                            } else {
-                                log::warn!("unknown peer");
-                                continue;
-                            };
+                    routes.insert(Ipv4Cidr::host(addr), addr);
+                    for &prefix in known_peer.allowed_ips.iter() {
+                        routes.insert(prefix, addr);
+                    }
+                    let old_session = peers.insert(
+                        addr,
+                        Peer {
+                            fingerprint: key_hint,
+                            sock_addr: src_addr,
+                            session_key,
+                            handshake_at: SystemTime::now(),
+                            seed_created_at,
+                            traffic: Traffic::default(),
+                            last_seen: Instant::now(),
+                        },
+                    );
+                    let rekey = old_session.is_some();
 
-                            let aad = sealed_packet.addresses_as_bytes();
-                            match session_key.unseal(&aad, &mut sealed_packet.content) {
-                                Ok(p) => p,
-                                Err(_) => {
-                                    log::error!("failed to unseal a packet");
-                                    continue;
-                                }
+                    let reply_seed = crypto::ReplySeed::new(pub_seed, seed_created_at, &settings);
+                    let signed_seed = static_key_pair.sign(&reply_seed);
+                    let reply = Message::HelloReply {
+                        seed: signed_seed,
+                        settings,
+                    };
+                    if let Err(err) = sock.send_to(&reply, src_addr) {
+                        print_error("send", err);
+                        continue;
+                    }
+                    metrics.handshake_completed();
+                    if rekey {
+                        log::info!("rekeyed with {:?} (socket: {:?})", addr, src_addr);
+                        events.emit(Event::Rekey {
+                            fingerprint: key_hint,
+                            endpoint: src_addr,
+                            address: addr,
+                        });
+                    } else {
+                        log::info!("new connection with {:?} (socket: {:?})", addr, src_addr);
+                        events.emit(Event::HandshakeAccepted {
+                            fingerprint: key_hint,
+                            endpoint: src_addr,
+                            address: addr,
+                        });
+                        let env = peer_env(config, addr, &known_peer.pubkey, src_addr);
+                        hooks.spawn(config.server.on_peer_connect.as_deref(), env);
+                    }
+                }
+
+                Message::HeartBeat => {
+                    log::trace!("HeartBeat from {:?}", src_addr);
+                    // no reply without a session so that the peer notices it has to reconnect
+                    let known = {
+                        let mut state = state.lock().expect("poisoned");
+                        let peer = state
+                            .peers
+                            .values_mut()
+                            .find(|peer| peer.sock_addr == src_addr);
+                        match peer {
+                            Some(peer) => {
+                                peer.last_seen = Instant::now();
+                                true
                             }
+                            None => false,
+                        }
+                    };
+                    if !known {
+                        log::debug!("HeartBeat from an unknown endpoint {:?}", src_addr);
+                        continue;
+                    }
+                    if let Err(err) = sock.send_to(&Message::HeartBeat, src_addr) {
+                        print_error("send", err);
+                        continue;
+                    }
+                }
+
+                Message::Packet(mut sealed_packet) => {
+                    let mut state = state.lock().expect("poisoned");
+                    let State {
+                        peers,
+                        routes,
+                        traffic,
+                        capture,
+                        ..
+                    } = &mut *state;
+                    let sender = match routes.lookup(sealed_packet.source) {
+                        Some(&addr) => addr,
+                        None => {
+                            log::warn!("no route to the sender: {:?}", sealed_packet.source);
+                            metrics.dropped(DropReason::NoRoute);
+                            continue;
+                        }
+                    };
+                    let packet: Vec<u8> = {
+                        let session_key = if let Some(peer) = peers.get_mut(&sender) {
+                            &mut peer.session_key
+                        } else {
+                            log::warn!("unknown peer");
+                            metrics.dropped(DropReason::NoSession);
+                            continue;
                         };
 
-                        let (ip_hdr, _payload) = match Ipv4Header::from_slice(&packet) {
-                            Ok(hdr_payload) => hdr_payload,
-                            Err(err) => {
-                                log::debug!("ignored uninteresting packet: {}", err);
+                        let aad = sealed_packet.addresses_as_bytes();
+                        match session_key.unseal(aad, &mut sealed_packet.content) {
+                            Ok(p) => p,
+                            Err(Error::Replayed) => {
+                                log::debug!("dropped a replayed packet from {:?}", sender);
+                                metrics.dropped(DropReason::Replayed);
                                 continue;
                             }
-                        };
+                            Err(_) => {
+                                log::error!("failed to unseal a packet");
+                                metrics.dropped(DropReason::Unseal);
+                                continue;
+                            }
+                        }
+                    };
 
-                        let source = Ipv4Addr::from(ip_hdr.source);
-                        let destination = Ipv4Addr::from(ip_hdr.destination);
+                    let (ip_hdr, _payload) = match Ipv4Header::from_slice(&packet) {
+                        Ok(hdr_payload) => hdr_payload,
+                        Err(err) => {
+                            log::debug!("ignored uninteresting packet: {}", err);
+                            continue;
+                        }
+                    };
 
-                        if destination == config.server.address {
+                    let source = Ipv4Addr::from(ip_hdr.source);
+                    let destination = Ipv4Addr::from(ip_hdr.destination);
+
+                    // The peer is only allowed to send packets from its own prefixes.
+                    if routes.lookup(source) != Some(&sender) {
+                        log::warn!("{:?} is not allowed to send from {:?}", sender, source);
+                        metrics.dropped(DropReason::ForbiddenSource);
+                        continue;
+                    }
+                    if let Some(peer) = peers.get_mut(&sender) {
+                        peer.traffic.rx(packet.len());
+                        peer.last_seen = Instant::now();
+                        // the packet is authentic, so the peer is at the new endpoint
+                        if peer.sock_addr != src_addr {
+                            log::info!(
+                                "{:?} roamed from {:?} to {:?}",
+                                sender,
+                                peer.sock_addr,
+                                src_addr
+                            );
+                            events.emit(Event::EndpointRoamed {
+                                fingerprint: peer.fingerprint,
+                                address: sender,
+                                from: peer.sock_addr,
+                                to: src_addr,
+                            });
+                            peer.sock_addr = src_addr;
+                        }
+                    }
+                    traffic.rx(packet.len());
+
+                    if destination == config.server.address {
+                        log::debug!(
+                            "receive {} bytes: {:?} --> {:?}",
+                            packet.len(),
+                            source,
+                            destination,
+                        );
+
+                        capture::record(capture, Direction::PeerToTun, sender, &packet);
+                        iface.send(&packet)?;
+                    } else {
+                        let peer = routes
+                            .lookup(destination)
+                            .and_then(|&addr| peers.get_mut(&addr).map(|peer| (addr, peer)));
+                        if let Some((receiver, peer)) = peer {
+                            log::debug!(
+                                "forward {} bytes: {:?} --> {:?} ({:?})",
+                                packet.len(),
+                                source,
+                                destination,
+                                peer.sock_addr,
+                            );
+                            let mut sealed_packet = SealedPacket {
+                                source,
+                                destination,
+                                content: Vec::new(),
+                            };
+                            let aad = sealed_packet.addresses_as_bytes();
+                            sealed_packet.content = peer
+                                .session_key
+                                .seal(aad, packet.to_vec())
+                                .expect("Failed to encrypt");
+
+                            let label = format!("{} -> {}", sender, receiver);
+                            capture::record(capture, Direction::PeerToPeer, label, &packet);
+                            let len = packet.len();
+                            let packet = Message::Packet(sealed_packet);
+                            if let Err(err) = sock.send_to(&packet, peer.sock_addr) {
+                                print_error("send", err);
+                                continue;
+                            }
+                            peer.traffic.tx(len);
+                            traffic.tx(len);
+                        } else if config.server.nat_interface.is_some() {
+                            // leave it to the kernel to forward the packet to the outside
                             log::debug!(
-                                "receive {} bytes: {:?} --> {:?}",
+                                "receive {} bytes: {:?} --> {:?} (forwarding)",
                                 packet.len(),
                                 source,
                                 destination,
                             );
 
+                            capture::record(capture, Direction::PeerToTun, sender, &packet);
                             iface.send(&packet)?;
                         } else {
-                            if let Some(peer) = peers.get_mut(&destination) {
-                                log::debug!(
-                                    "forward {} bytes: {:?} --> {:?} ({:?})",
-                                    packet.len(),
-                                    source,
-                                    destination,
-                                    peer.sock_addr,
-                                );
-                                let mut sealed_packet = SealedPacket {
-                                    source,
-                                    destination,
-                                    content: Vec::new(),
-                                };
-                                let aad = sealed_packet.addresses_as_bytes();
-                                sealed_packet.content = peer
-                                    .session_key
-                                    .seal(&aad, packet.to_vec())
-                                    .expect("Failed to encrypt");
-
-                                let packet = Message::Packet(sealed_packet);
-                                if let Err(err) = sock.send_to(&packet, peer.sock_addr) {
-                                    print_error("send", err);
-                                    continue;
-                                }
-                            } else {
-                                // TODO: handle broadcast packets
-                                log::warn!("unknown peer");
-                            }
+                            // TODO: handle broadcast packets
+                            log::warn!("unknown peer");
+                            metrics.dropped(DropReason::NoRoute);
                         }
                     }
-
-                    _ => log::error!("unexpected packet"),
                 }
-            }
-        }
-    });
-
-    let mut buf = [0; 4096];
-    loop {
-        let nb = iface.recv(&mut buf[..])?;
-        let packet = &buf[..nb];
 
-        let (ip_hdr, _payload) = match Ipv4Header::from_slice(packet) {
-            Ok(hdr_payload) => hdr_payload,
-            Err(err) => {
-                log::debug!("ignored uninteresting packet: {}", err);
-                continue;
+                _ => log::error!("unexpected packet"),
             }
-        };
+        }))
+    }
 
-        let source = Ipv4Addr::from(ip_hdr.source);
-        let destination = Ipv4Addr::from(ip_hdr.destination);
-        log::debug!(
-            "send    {} bytes: {:?} --> {:?}",
-            packet.len(),
-            source,
-            destination,
-        );
+    /// Spawns a thread which sends packets from the interface to peers.
+    fn spawn_sender(&self, iface: Arc<dyn VirtualInterface>) {
+        let state = self.state.clone();
+        let config = state.lock().expect("poisoned").config.clone();
+        let metrics = self.metrics.clone();
+        let stopped = self.stopped.clone();
+        self.spawn_worker(move || {
+            let mut buf = [0; 4096];
+            loop {
+                let nb = iface.recv(&mut buf[..])?;
+                if stopped.load(Ordering::SeqCst) {
+                    return Ok(());
+                }
+                let packet = &buf[..nb];
+
+                let (ip_hdr, _payload) = match Ipv4Header::from_slice(packet) {
+                    Ok(hdr_payload) => hdr_payload,
+                    Err(err) => {
+                        log::debug!("ignored uninteresting packet: {}", err);
+                        continue;
+                    }
+                };
 
-        if destination == config.server.address {
-            // the packet is for the server host.
-            continue;
-        } else {
-            let mut peers = peers.lock().expect("poisoned");
-            if let Some(peer) = peers.get_mut(&destination) {
-                let mut sealed_packet = SealedPacket {
+                let source = Ipv4Addr::from(ip_hdr.source);
+                let destination = Ipv4Addr::from(ip_hdr.destination);
+                log::debug!(
+                    "send    {} bytes: {:?} --> {:?}",
+                    packet.len(),
                     source,
                     destination,
-                    content: Vec::new(),
-                };
-                let aad = sealed_packet.addresses_as_bytes();
-                sealed_packet.content = peer
-                    .session_key
-                    .seal(&aad, packet.to_vec())
-                    .expect("Failed to encrypt");
-
-                let packet = Message::Packet(sealed_packet);
-                if let Err(err) = sock.send_to(&packet, peer.sock_addr) {
-                    print_error("send", err);
+                );
+
+                if destination == config.server.address {
+                    // the packet is for the server host.
                     continue;
+                } else {
+                    let mut state = state.lock().expect("poisoned");
+                    let State {
+                        peers,
+                        routes,
+                        traffic,
+                        capture,
+                        channel,
+                        ..
+                    } = &mut *state;
+                    let sock = match channel {
+                        Some(channel) => channel,
+                        None => return Ok(()),
+                    };
+                    let peer = routes
+                        .lookup(destination)
+                        .and_then(|&addr| peers.get_mut(&addr).map(|peer| (addr, peer)));
+                    if let Some((receiver, peer)) = peer {
+                        let mut sealed_packet = SealedPacket {
+                            source,
+                            destination,
+                            content: Vec::new(),
+                        };
+                        let aad = sealed_packet.addresses_as_bytes();
+                        sealed_packet.content = peer
+                            .session_key
+                            .seal(aad, packet.to_vec())
+                            .expect("Failed to encrypt");
+
+                        capture::record(capture, Direction::TunToPeer, receiver, packet);
+                        let len = packet.len();
+                        let packet = Message::Packet(sealed_packet);
+                        if let Err(err) = sock.send_to(&packet, peer.sock_addr) {
+                            print_error("send", err);
+                            continue;
+                        }
+                        peer.traffic.tx(len);
+                        traffic.tx(len);
+                    } else {
+                        log::warn!("unknown peer");
+                        metrics.dropped(DropReason::NoRoute);
+                    }
                 }
-            } else {
-                log::warn!("unknown peer");
             }
-        }
+        });
+    }
+}
+
+impl Drop for Server {
+    fn drop(&mut self) {
+        self.stop();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn key(n: u8) -> String {
+        crypto::to_base64(&[n; crypto::PUBLIC_KEY_LEN])
+    }
+
+    fn config(toml: &str) -> Config {
+        toml::from_str(&format!("[server]\n{}", toml)).unwrap()
+    }
+
+    #[test]
+    fn peers_claiming_the_same_prefix_are_rejected() {
+        let config = config(&format!(
+            r#"
+            [[peers]]
+            address = "10.20.30.2"
+            public_key = "{}"
+            allowed_ips = ["192.168.1.0/24"]
+
+            [[peers]]
+            address = "10.20.30.3"
+            public_key = "{}"
+            allowed_ips = ["192.168.1.0/24"]
+            "#,
+            key(1),
+            key(2)
+        ));
+        let err = load_peers(&config).unwrap_err().to_string();
+        assert!(
+            err.contains(&Fingerprint::of(&[1; 32]).to_string()),
+            "{}",
+            err
+        );
+        assert!(
+            err.contains(&Fingerprint::of(&[2; 32]).to_string()),
+            "{}",
+            err
+        );
+    }
+
+    #[test]
+    fn peers_claiming_overlapping_prefixes_are_rejected() {
+        let config = config(&format!(
+            r#"
+            [[peers]]
+            address = "10.20.30.2"
+            public_key = "{}"
+            allowed_ips = ["192.168.0.0/16"]
+
+            [[peers]]
+            address = "10.20.30.3"
+            public_key = "{}"
+            allowed_ips = ["192.168.1.0/24"]
+            "#,
+            key(1),
+            key(2)
+        ));
+        assert!(matches!(
+            load_peers(&config),
+            Err(Error::InvalidConfig { .. })
+        ));
+    }
+
+    #[test]
+    fn peers_with_disjoint_prefixes_are_loaded() {
+        let config = config(&format!(
+            r#"
+            [[peers]]
+            address = "10.20.30.2"
+            public_key = "{}"
+            allowed_ips = ["192.168.1.0/24"]
+
+            [[peers]]
+            address = "10.20.30.3"
+            public_key = "{}"
+            allowed_ips = ["192.168.2.0/24"]
+            "#,
+            key(1),
+            key(2)
+        ));
+        assert_eq!(load_peers(&config).unwrap().len(), 2);
+    }
+
+    fn load_error(server: &str, addresses: [&str; 2]) -> String {
+        let config = config(&format!(
+            r#"
+            {}
+            [[peers]]
+            address = "{}"
+            public_key = "{}"
+
+            [[peers]]
+            address = "{}"
+            public_key = "{}"
+            "#,
+            server,
+            addresses[0],
+            key(1),
+            addresses[1],
+            key(2)
+        ));
+        load_peers(&config).unwrap_err().to_string()
+    }
+
+    #[test]
+    fn peers_with_the_same_address_are_rejected() {
+        let err = load_error("", ["10.20.30.2", "10.20.30.2"]);
+        assert!(err.contains("address 10.20.30.2"), "{}", err);
+        assert!(
+            err.contains(&Fingerprint::of(&[1; 32]).to_string()),
+            "{}",
+            err
+        );
+    }
+
+    #[test]
+    fn peer_with_the_server_address_is_rejected() {
+        let err = load_error("", ["10.20.30.2", "10.20.30.1"]);
+        assert!(err.contains("address of the server"), "{}", err);
+    }
+
+    #[test]
+    fn peer_with_an_address_in_the_pool_is_rejected() {
+        let err = load_error(
+            r#"address_pool = "10.20.30.128/25""#,
+            ["10.20.30.2", "10.20.30.130"],
+        );
+        assert!(err.contains("address pool"), "{}", err);
+    }
+
+    /// Makes a configuration without peers.
+    fn server_config(toml: &str) -> Config {
+        toml::from_str(&format!("peers = []\n[server]\n{}", toml)).unwrap()
+    }
+
+    #[test]
+    fn session_timeout_shorter_than_heart_beats_is_rejected() {
+        let err = server_config("keepalive = 10\nsession_timeout = 29")
+            .validate()
+            .unwrap_err();
+        assert!(
+            err.to_string().contains("at least 3 times keepalive"),
+            "{}",
+            err
+        );
+        let config = server_config("keepalive = 10\nsession_timeout = 30");
+        assert!(config.validate().is_ok());
+        assert!(server_config("keepalive = 0").validate().is_err());
     }
 }
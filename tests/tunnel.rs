@@ -0,0 +1,652 @@
+//! End-to-end tests running a server and clients in-process over loopback UDP,
+//! with in-memory interfaces in place of tun devices.
+//! Some clients reach the server through `impair::Proxy` simulating a poor network.
+
+use poor_mans_vpn::cert::{Certificate, CertificateContent};
+use poor_mans_vpn::client::{self, Client, ConnectionState};
+use poor_mans_vpn::control::{self, Request, Response};
+use poor_mans_vpn::crypto::{self, to_base64, to_hex, Fingerprint, PublicKeySource, StaticKeyPair};
+use poor_mans_vpn::event::Event;
+use poor_mans_vpn::impair::{Direction, Impairment, Proxy};
+use poor_mans_vpn::memory::{memory_interface, MemoryHost};
+use poor_mans_vpn::server::{self, Server};
+use poor_mans_vpn::{Channel, Message};
+use std::net::{Ipv4Addr, SocketAddr, UdpSocket};
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::mpsc::{self, Receiver};
+use std::sync::Arc;
+use std::time::{Duration, Instant, SystemTime};
+
+const SERVER_ADDRESS: Ipv4Addr = Ipv4Addr::new(10, 20, 30, 1);
+const PEER1_ADDRESS: Ipv4Addr = Ipv4Addr::new(10, 20, 30, 2);
+const PEER2_ADDRESS: Ipv4Addr = Ipv4Addr::new(10, 20, 30, 3);
+
+/// Handshakes are slow in debug builds, so the timeout is generous.
+const TIMEOUT: Duration = Duration::from_secs(30);
+
+/// A directory for key files, removed on drop.
+struct TempDir(PathBuf);
+
+impl TempDir {
+    fn new() -> Self {
+        static COUNT: AtomicUsize = AtomicUsize::new(0);
+        let name = format!(
+            "poor-mans-vpn-test-{}-{}",
+            std::process::id(),
+            COUNT.fetch_add(1, Ordering::SeqCst)
+        );
+        let path = std::env::temp_dir().join(name);
+        std::fs::create_dir_all(&path).unwrap();
+        Self(path)
+    }
+
+    /// Writes a file and returns its path.
+    fn write(&self, name: &str, content: &[u8]) -> PathBuf {
+        let path = self.0.join(name);
+        std::fs::write(&path, content).unwrap();
+        path
+    }
+}
+
+impl Drop for TempDir {
+    fn drop(&mut self) {
+        let _ = std::fs::remove_dir_all(&self.0);
+    }
+}
+
+/// A peer registered on the server.
+struct Peer {
+    private_key: Vec<u8>,
+    address: Ipv4Addr,
+}
+
+impl Peer {
+    fn new(address: Ipv4Addr) -> Self {
+        Self {
+            private_key: StaticKeyPair::generate_pkcs8(),
+            address,
+        }
+    }
+
+    fn fingerprint(&self) -> Fingerprint {
+        fingerprint_of(&self.private_key)
+    }
+}
+
+fn key_pair(private_key: &[u8]) -> StaticKeyPair {
+    StaticKeyPair::from_pkcs8_bytes(private_key).unwrap()
+}
+
+fn fingerprint_of(private_key: &[u8]) -> Fingerprint {
+    Fingerprint::of(&key_pair(private_key).public_key())
+}
+
+/// A server with the host side of its interface and the events it emitted.
+struct TestServer {
+    server: Server,
+    host: MemoryHost,
+    events: Receiver<Event>,
+}
+
+impl TestServer {
+    fn port(&self) -> u16 {
+        self.server.local_addr().port()
+    }
+
+    /// Waits for an event matching the predicate, skipping the others.
+    fn wait_for_event<F: Fn(&Event) -> bool>(&self, pred: F) -> Option<Event> {
+        let deadline = Instant::now() + TIMEOUT;
+        loop {
+            let timeout = deadline.checked_duration_since(Instant::now())?;
+            match self.events.recv_timeout(timeout) {
+                Ok(event) if pred(&event) => return Some(event),
+                Ok(_) => continue,
+                Err(_) => return None,
+            }
+        }
+    }
+
+    /// Waits until all the peers establish sessions.
+    fn wait_for_handshakes(&self, peers: &[Peer]) -> bool {
+        let mut waiting: Vec<Fingerprint> = peers.iter().map(Peer::fingerprint).collect();
+        while !waiting.is_empty() {
+            match self.wait_for_event(|event| matches!(event, Event::HandshakeAccepted { .. })) {
+                Some(Event::HandshakeAccepted { fingerprint, .. }) => {
+                    waiting.retain(|&fp| fp != fingerprint)
+                }
+                _ => return false,
+            }
+        }
+        true
+    }
+}
+
+/// The keys of a server and its peers.
+struct Network {
+    dir: TempDir,
+    server_key: Vec<u8>,
+    peers: Vec<Peer>,
+}
+
+impl Network {
+    fn new(peers: Vec<Peer>) -> Self {
+        Self {
+            dir: TempDir::new(),
+            server_key: StaticKeyPair::generate_pkcs8(),
+            peers,
+        }
+    }
+
+    /// Starts a server knowing all peers on the given port (0 to choose one).
+    fn start_server(&self, port: u16) -> TestServer {
+        self.start_server_with(port, "")
+    }
+
+    /// Starts a server with additional lines in the `[server]` section.
+    fn start_server_with(&self, port: u16, server_config: &str) -> TestServer {
+        let private_key = self.dir.write("server.der", &self.server_key);
+        let config = format!(
+            "peers = []\n\
+             [server]\n\
+             bind_address = \"127.0.0.1\"\n\
+             port = {}\n\
+             address = \"{}\"\n\
+             keepalive = 1\n\
+             private_key = {:?}\n\
+             {}\n",
+            port, SERVER_ADDRESS, private_key, server_config
+        );
+        let mut config: server::Config = toml::from_str(&config).unwrap();
+        for (i, peer) in self.peers.iter().enumerate() {
+            let public_key = key_pair(&peer.private_key).public_key();
+            config.peers.push(server::PeerConfig {
+                address: Some(peer.address),
+                public_key: PublicKeySource::File(
+                    self.dir.write(&format!("peer{}.pub", i), &public_key),
+                ),
+                allowed_ips: Vec::new(),
+            });
+        }
+
+        let (iface, host) = memory_interface("server0");
+        let (sender, events) = mpsc::channel();
+        let server = Server::builder(config)
+            .interface(Arc::new(iface))
+            .on_event(move |event| {
+                let _ = sender.send(event.clone());
+            })
+            .start()
+            .unwrap();
+        TestServer {
+            server,
+            host,
+            events,
+        }
+    }
+
+    /// Starts a client with the private key, connecting to the server on the port.
+    fn start_client(&self, port: u16, private_key: &[u8]) -> (Client, MemoryHost) {
+        self.start_client_with(port, private_key, None)
+    }
+
+    /// Starts a client presenting the certificate if any.
+    fn start_client_with(
+        &self,
+        port: u16,
+        private_key: &[u8],
+        certificate: Option<Certificate>,
+    ) -> (Client, MemoryHost) {
+        let config = format!(
+            "[peer]\n\
+             bind_address = \"127.0.0.1\"\n\
+             [server]\n\
+             bind_address = \"127.0.0.1\"\n\
+             port = {}\n\
+             public_key = \"{}\"\n",
+            port,
+            to_base64(&key_pair(&self.server_key).public_key())
+        );
+        let config: client::Config = toml::from_str(&config).unwrap();
+
+        let (iface, host) = memory_interface("client0");
+        let mut builder = Client::builder(config)
+            .key_pair(key_pair(private_key))
+            .interface(Arc::new(iface));
+        if let Some(certificate) = certificate {
+            builder = builder.certificate(certificate);
+        }
+        let client = builder.start().unwrap();
+        (client, host)
+    }
+}
+
+/// Starts a proxy to the server applying the impairment to both directions.
+fn start_proxy(server: &TestServer, impairment: Impairment) -> Proxy {
+    let upstream = SocketAddr::from((Ipv4Addr::LOCALHOST, server.port()));
+    Proxy::builder(upstream)
+        .impairment(Direction::Upstream, impairment.clone())
+        .impairment(Direction::Downstream, impairment)
+        .seed(31415)
+        .start()
+        .unwrap()
+}
+
+/// Polls the condition until it holds or the timeout passes.
+fn wait_until<F: Fn() -> bool>(cond: F) -> bool {
+    let deadline = Instant::now() + TIMEOUT;
+    while Instant::now() < deadline {
+        if cond() {
+            return true;
+        }
+        std::thread::sleep(Duration::from_millis(50));
+    }
+    false
+}
+
+fn is_connected(client: &Client) -> bool {
+    client.status().state == ConnectionState::Connected
+}
+
+/// Makes a UDP packet from `source` to `destination`.
+fn udp_packet(source: Ipv4Addr, destination: Ipv4Addr, payload: &[u8]) -> Vec<u8> {
+    let mut packet = Vec::new();
+    etherparse::PacketBuilder::ipv4(source.octets(), destination.octets(), 64)
+        .udp(40000, 50000)
+        .write(&mut packet, payload)
+        .unwrap();
+    packet
+}
+
+/// Sends numbered packets from the host of `from`, and checks that each arrives at the host `to`
+/// exactly once, in any order.
+fn assert_delivered_once(
+    from: &MemoryHost,
+    to: &MemoryHost,
+    source: Ipv4Addr,
+    destination: Ipv4Addr,
+    count: usize,
+) {
+    let mut packets: Vec<Vec<u8>> = (0..count)
+        .map(|i| udp_packet(source, destination, format!("packet {}", i).as_bytes()))
+        .collect();
+    for packet in packets.iter() {
+        from.inject(packet).unwrap();
+    }
+
+    let mut received: Vec<Vec<u8>> = (0..count)
+        .map(|_| to.recv_timeout(TIMEOUT).expect("a packet was lost"))
+        .collect();
+    assert_eq!(to.recv_timeout(Duration::from_millis(500)), None);
+    packets.sort();
+    received.sort();
+    assert_eq!(received, packets);
+}
+
+/// Sends a packet from the host of `from` and checks that it arrives at the host `to` intact.
+fn assert_delivered(from: &MemoryHost, to: &MemoryHost, source: Ipv4Addr, destination: Ipv4Addr) {
+    let packet = udp_packet(source, destination, b"poor man's payload");
+    from.inject(&packet).unwrap();
+    assert_eq!(to.recv_timeout(TIMEOUT), Some(packet));
+}
+
+#[test]
+fn peers_complete_handshakes() {
+    let network = Network::new(vec![Peer::new(PEER1_ADDRESS), Peer::new(PEER2_ADDRESS)]);
+    let server = network.start_server(0);
+    let clients: Vec<_> = network
+        .peers
+        .iter()
+        .map(|peer| network.start_client(server.port(), &peer.private_key))
+        .collect();
+
+    assert!(server.wait_for_handshakes(&network.peers));
+    for ((client, _), peer) in clients.iter().zip(network.peers.iter()) {
+        assert!(wait_until(|| is_connected(client)));
+        let status = client.status();
+        assert_eq!(status.address, Some(peer.address));
+        assert_eq!(status.fingerprint, peer.fingerprint());
+    }
+}
+
+#[test]
+fn packets_are_forwarded_between_peers() {
+    let network = Network::new(vec![Peer::new(PEER1_ADDRESS), Peer::new(PEER2_ADDRESS)]);
+    let server = network.start_server(0);
+    let (client1, host1) = network.start_client(server.port(), &network.peers[0].private_key);
+    let (client2, host2) = network.start_client(server.port(), &network.peers[1].private_key);
+    assert!(wait_until(
+        || is_connected(&client1) && is_connected(&client2)
+    ));
+
+    assert_delivered(&host1, &host2, PEER1_ADDRESS, PEER2_ADDRESS);
+    assert_delivered(&host2, &host1, PEER2_ADDRESS, PEER1_ADDRESS);
+
+    // between a peer and the server host
+    assert_delivered(&host1, &server.host, PEER1_ADDRESS, SERVER_ADDRESS);
+    assert_delivered(&server.host, &host2, SERVER_ADDRESS, PEER2_ADDRESS);
+
+    assert_eq!(client1.status().traffic.tx_packets, 2);
+    assert_eq!(client2.status().traffic.rx_packets, 2);
+}
+
+#[test]
+fn unknown_key_is_rejected() {
+    let network = Network::new(vec![Peer::new(PEER1_ADDRESS)]);
+    let server = network.start_server(0);
+
+    let stranger = Peer::new(PEER2_ADDRESS);
+    let (client, _host) = network.start_client(server.port(), &stranger.private_key);
+
+    let fingerprint = stranger.fingerprint();
+    let rejected = server.wait_for_event(|event| {
+        matches!(event, Event::HandshakeRejected { fingerprint: fp, .. } if *fp == fingerprint)
+    });
+    assert!(rejected.is_some());
+    assert!(!is_connected(&client));
+    assert_eq!(client.status().address, None);
+}
+
+#[test]
+fn clients_reconnect_after_server_restart() {
+    let network = Network::new(vec![Peer::new(PEER1_ADDRESS), Peer::new(PEER2_ADDRESS)]);
+    let server = network.start_server(0);
+    let port = server.port();
+    let (client1, host1) = network.start_client(port, &network.peers[0].private_key);
+    let (client2, host2) = network.start_client(port, &network.peers[1].private_key);
+    assert!(wait_until(
+        || is_connected(&client1) && is_connected(&client2)
+    ));
+    assert_delivered(&host1, &host2, PEER1_ADDRESS, PEER2_ADDRESS);
+
+    // the new server knows no sessions, and the clients notice it by the lack of heart beats
+    drop(server);
+    let server = network.start_server(port);
+    assert!(server.wait_for_handshakes(&network.peers));
+    assert!(wait_until(
+        || is_connected(&client1) && is_connected(&client2)
+    ));
+
+    assert_delivered(&host1, &host2, PEER1_ADDRESS, PEER2_ADDRESS);
+    assert_delivered(&host2, &host1, PEER2_ADDRESS, PEER1_ADDRESS);
+}
+
+#[test]
+fn handshake_is_retried_after_hello_is_lost() {
+    let network = Network::new(vec![Peer::new(PEER1_ADDRESS)]);
+    let server = network.start_server(0);
+    let lossy = Impairment {
+        loss: 1.0,
+        ..Impairment::default()
+    };
+    let proxy = start_proxy(&server, lossy);
+    let (client, _host) =
+        network.start_client(proxy.local_addr().port(), &network.peers[0].private_key);
+
+    // the first Hello is lost, and the network recovers before the retry
+    assert!(wait_until(|| proxy.stats(Direction::Upstream).lost >= 1));
+    proxy.set_impairment(Direction::Upstream, Impairment::default());
+    proxy.set_impairment(Direction::Downstream, Impairment::default());
+
+    assert!(server.wait_for_handshakes(&network.peers));
+    assert!(wait_until(|| is_connected(&client)));
+}
+
+#[test]
+fn duplicated_packets_are_delivered_once() {
+    let network = Network::new(vec![Peer::new(PEER1_ADDRESS), Peer::new(PEER2_ADDRESS)]);
+    let server = network.start_server(0);
+    let duplicating = Impairment {
+        duplicate: 1.0,
+        ..Impairment::default()
+    };
+    let proxy = start_proxy(&server, duplicating);
+    let (client1, host1) =
+        network.start_client(proxy.local_addr().port(), &network.peers[0].private_key);
+    let (client2, host2) = network.start_client(server.port(), &network.peers[1].private_key);
+    // even the Hello is duplicated, which must not replace the session just established
+    assert!(wait_until(
+        || is_connected(&client1) && is_connected(&client2)
+    ));
+
+    assert_delivered_once(&host1, &host2, PEER1_ADDRESS, PEER2_ADDRESS, 10);
+    assert_delivered_once(&host2, &host1, PEER2_ADDRESS, PEER1_ADDRESS, 10);
+    assert!(proxy.stats(Direction::Upstream).duplicated >= 10);
+    assert!(server
+        .server
+        .metrics()
+        .contains("dropped_packets_total{reason=\"replayed\"} 10\n"));
+}
+
+#[test]
+fn reordered_packets_are_delivered() {
+    let network = Network::new(vec![Peer::new(PEER1_ADDRESS), Peer::new(PEER2_ADDRESS)]);
+    let server = network.start_server(0);
+    let bumpy = Impairment {
+        latency: Duration::from_millis(5),
+        jitter: Duration::from_millis(20),
+        reorder: 0.3,
+        ..Impairment::default()
+    };
+    let proxy = start_proxy(&server, bumpy);
+    let (client1, host1) =
+        network.start_client(proxy.local_addr().port(), &network.peers[0].private_key);
+    let (client2, host2) = network.start_client(server.port(), &network.peers[1].private_key);
+    assert!(wait_until(
+        || is_connected(&client1) && is_connected(&client2)
+    ));
+
+    assert_delivered_once(&host1, &host2, PEER1_ADDRESS, PEER2_ADDRESS, 20);
+    assert_delivered_once(&host2, &host1, PEER2_ADDRESS, PEER1_ADDRESS, 20);
+    assert!(proxy.stats(Direction::Upstream).reordered > 0);
+}
+
+#[test]
+fn invalid_public_key_is_reported() {
+    let network = Network::new(vec![Peer::new(PEER1_ADDRESS)]);
+    let private_key = network.dir.write("server.der", &network.server_key);
+    let config = format!(
+        "[server]\n\
+         bind_address = \"127.0.0.1\"\n\
+         port = 0\n\
+         address = \"{}\"\n\
+         private_key = {:?}\n\
+         [[peers]]\n\
+         public_key = \"{}\"\n\
+         [[peers]]\n\
+         public_key = {:?}\n",
+        SERVER_ADDRESS,
+        private_key,
+        to_base64(&key_pair(&network.peers[0].private_key).public_key()),
+        network.dir.write("truncated.pub", &[0; 31]),
+    );
+    let config: server::Config = toml::from_str(&config).unwrap();
+    assert_eq!(
+        config.peers[0].public_key,
+        PublicKeySource::Inline(key_pair(&network.peers[0].private_key).public_key())
+    );
+
+    let (iface, _host) = memory_interface("server0");
+    let err = Server::builder(config)
+        .interface(Arc::new(iface))
+        .start()
+        .err()
+        .expect("the server started with a broken key");
+    assert!(err.to_string().contains("peers[1]"), "{}", err);
+}
+
+#[test]
+fn revoked_key_loses_its_session() {
+    let network = Network::new(vec![Peer::new(PEER1_ADDRESS), Peer::new(PEER2_ADDRESS)]);
+    let revocation_list = network.dir.write("revoked.txt", b"# no keys revoked\n");
+    let server = network.start_server_with(0, &format!("revocation_list = {:?}", revocation_list));
+    let (client1, _host1) = network.start_client(server.port(), &network.peers[0].private_key);
+    let (client2, host2) = network.start_client(server.port(), &network.peers[1].private_key);
+    assert!(wait_until(
+        || is_connected(&client1) && is_connected(&client2)
+    ));
+
+    // revoked without reloading the configuration
+    let fingerprint = network.peers[0].fingerprint();
+    let line = format!("{} 2026-10-18 laptop stolen\n", fingerprint);
+    network.dir.write("revoked.txt", line.as_bytes());
+    let revoked = server.wait_for_event(|event| {
+        matches!(event, Event::SessionRevoked { fingerprint: fp, .. } if *fp == fingerprint)
+    });
+    assert!(revoked.is_some());
+
+    // the peer notices the lost session, and its handshakes are refused from now on
+    let rejected = server.wait_for_event(|event| {
+        matches!(event, Event::HandshakeRejected { fingerprint: fp, reason, .. }
+            if *fp == fingerprint && reason.contains("laptop stolen"))
+    });
+    assert!(rejected.is_some());
+    assert!(!is_connected(&client1));
+
+    assert_delivered(&host2, &server.host, PEER2_ADDRESS, SERVER_ADDRESS);
+}
+
+#[test]
+fn peers_are_enrolled_by_certificates() {
+    let network = Network::new(vec![Peer::new(PEER1_ADDRESS)]);
+    let ca_key = StaticKeyPair::generate_pkcs8();
+    let ca_public_key = to_base64(&key_pair(&ca_key).public_key());
+    let server = network.start_server_with(0, &format!("ca_public_key = {:?}", ca_public_key));
+
+    let issue = |ca_key: &[u8], peer: &Peer| {
+        let expires_at = SystemTime::now() + Duration::from_secs(24 * 60 * 60);
+        let content = CertificateContent {
+            public_key: key_pair(&peer.private_key).public_key().try_into().unwrap(),
+            address: Some(peer.address),
+            expires_at: poor_mans_vpn::unix_time(expires_at),
+        };
+        Certificate::issue(&key_pair(ca_key), &content)
+    };
+
+    // a peer absent from the configuration, with the address in its certificate
+    let enrolled = Peer::new(PEER2_ADDRESS);
+    let certificate = issue(&ca_key, &enrolled);
+    let certificate: Certificate = certificate.to_string().parse().unwrap();
+    let (client, host) =
+        network.start_client_with(server.port(), &enrolled.private_key, Some(certificate));
+    assert!(server.wait_for_handshakes(std::slice::from_ref(&enrolled)));
+    assert!(wait_until(|| is_connected(&client)));
+    assert_eq!(client.status().address, Some(PEER2_ADDRESS));
+    assert_delivered(&host, &server.host, PEER2_ADDRESS, SERVER_ADDRESS);
+
+    // a certificate signed by another CA
+    let stranger = Peer::new(Ipv4Addr::new(10, 20, 30, 4));
+    let certificate = issue(&StaticKeyPair::generate_pkcs8(), &stranger);
+    let (stranger_client, _host) =
+        network.start_client_with(server.port(), &stranger.private_key, Some(certificate));
+    let fingerprint = stranger.fingerprint();
+    let rejected = server.wait_for_event(|event| {
+        matches!(event, Event::HandshakeRejected { fingerprint: fp, reason, .. }
+            if *fp == fingerprint && reason.contains("not signed by the CA"))
+    });
+    assert!(rejected.is_some());
+    assert!(!is_connected(&stranger_client));
+}
+
+#[test]
+fn conflicting_peers_are_not_added() {
+    let network = Network::new(vec![Peer::new(PEER1_ADDRESS)]);
+    let server = Arc::new(
+        network
+            .start_server_with(0, "address_pool = \"10.20.30.128/25\"")
+            .server,
+    );
+    let socket = network.dir.0.join("control.sock");
+    control::serve(&socket, {
+        let server = server.clone();
+        move |request| server.handle_request(request)
+    })
+    .unwrap();
+
+    let add_peer = |address: &str, allowed_ips: &[&str]| {
+        let request = Request::AddPeer {
+            public_key: to_hex(&key_pair(&StaticKeyPair::generate_pkcs8()).public_key()),
+            address: Some(address.parse().unwrap()),
+            allowed_ips: allowed_ips.iter().map(|p| p.parse().unwrap()).collect(),
+        };
+        control::request(&socket, &request).unwrap()
+    };
+    let assert_rejected = |response: Response, reason: &str| match response {
+        Response::Error { message } => assert!(message.contains(reason), "{}", message),
+        Response::Ok { result } => panic!("added: {}", result),
+    };
+
+    let response = add_peer("10.20.30.4", &["192.168.1.0/24"]);
+    assert!(matches!(response, Response::Ok { .. }), "{:?}", response);
+
+    let taken = format!("the address of {}", network.peers[0].fingerprint());
+    assert_rejected(add_peer("10.20.30.2", &[]), &taken);
+    assert_rejected(add_peer("10.20.30.1", &[]), "the address of the server");
+    assert_rejected(add_peer("10.20.30.130", &[]), "in the address pool");
+    assert_rejected(add_peer("10.99.0.2", &[]), "outside the VPN subnet");
+    assert_rejected(
+        add_peer("10.20.30.5", &["192.168.1.128/25"]),
+        "overlaps 192.168.1.0/24",
+    );
+}
+
+#[test]
+fn control_socket_replaces_only_a_stale_socket() {
+    let dir = TempDir::new();
+    let handler = |_| Response::Ok {
+        result: serde_json::Value::Null,
+    };
+
+    // a socket left by the previous run
+    let socket = dir.0.join("control.sock");
+    drop(std::os::unix::net::UnixListener::bind(&socket).unwrap());
+    control::serve(&socket, handler).unwrap();
+    assert!(matches!(
+        control::request(&socket, &Request::Stats),
+        Ok(Response::Ok { .. })
+    ));
+
+    let file = dir.write("control.conf", b"not a socket");
+    let err = control::serve(&file, handler).unwrap_err();
+    assert!(err.to_string().contains("control.conf"), "{}", err);
+    assert_eq!(std::fs::read(&file).unwrap(), b"not a socket");
+}
+
+#[test]
+fn replayed_hello_does_not_replace_the_session() {
+    let network = Network::new(vec![Peer::new(PEER1_ADDRESS)]);
+    let server = network.start_server(0);
+    let peer = &network.peers[0];
+    let hello = || {
+        let (_, seed) = crypto::generate_seed_pair();
+        Message::Hello {
+            key_hint: peer.fingerprint(),
+            seed: key_pair(&peer.private_key).sign(&seed),
+            certificate: None,
+        }
+    };
+    // e.g. captured from an earlier handshake
+    let old_hello = hello();
+    std::thread::sleep(Duration::from_millis(10));
+    let new_hello = hello();
+
+    let server_addr = SocketAddr::from((Ipv4Addr::LOCALHOST, server.port()));
+    let mut channel = Channel::new(UdpSocket::bind("127.0.0.1:0").unwrap());
+    channel.send_to(&new_hello, server_addr).unwrap();
+    assert!(server
+        .wait_for_event(|event| matches!(event, Event::HandshakeAccepted { .. }))
+        .is_some());
+
+    channel.send_to(&old_hello, server_addr).unwrap();
+    channel.send_to(&new_hello, server_addr).unwrap();
+    let deadline = Instant::now() + Duration::from_secs(2);
+    while let Some(timeout) = deadline.checked_duration_since(Instant::now()) {
+        if let Ok(event) = server.events.recv_timeout(timeout) {
+            assert!(
+                !matches!(event, Event::Rekey { .. } | Event::HandshakeAccepted { .. }),
+                "{:?}",
+                event
+            );
+        }
+    }
+}
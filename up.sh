@@ -102,4 +102,3 @@ docker exec 'net2-host' sh -c 'cp shared/server_pubkey.der keys/'
 docker exec 'server' bash -c 'cp shared/peer{1,2}_pubkey.der keys/'
 docker exec 'server' sh -c 'rm shared/*.der'
 
-docker exec 'net2-host' sh -c "sed -i 's/10.20.30.2/10.20.30.3/' client-config.toml"
@@ -0,0 +1,96 @@
+-- A Wireshark dissector of the outer UDP protocol of Poor Man's VPN.
+--
+-- Install: copy this file to the personal plugins directory
+-- (e.g. ~/.local/lib/wireshark/plugins/) and restart Wireshark.
+-- Messages are `Message` of src/lib.rs serialized with bincode:
+-- little-endian integers, a u32 variant index for enums and a u64 length before byte vectors.
+-- Signatures are 64 bytes without a length, and an `Option` is a u8 tag (1 if present).
+-- Packets are not decrypted. Use `vpn-dissect --keylog` for that.
+
+local proto = Proto("pmvpn", "Poor Man's VPN")
+
+local message_types = {
+    [0] = "Hello",
+    [1] = "HelloReply",
+    [2] = "HeartBeat",
+    [3] = "Packet",
+}
+
+local f = proto.fields
+f.msg_type = ProtoField.uint32("pmvpn.type", "Type", base.DEC, message_types)
+f.key_hint = ProtoField.bytes("pmvpn.key_hint", "Key hint (fingerprint)")
+f.seed = ProtoField.bytes("pmvpn.seed", "Seed (signed data)")
+f.signature = ProtoField.bytes("pmvpn.signature", "Signature")
+f.certificate = ProtoField.bytes("pmvpn.certificate", "Certificate (signed data)")
+f.cert_public_key = ProtoField.bytes("pmvpn.certificate.public_key", "Public key")
+f.cert_address = ProtoField.ipv4("pmvpn.certificate.address", "Address")
+f.cert_expires_at = ProtoField.absolute_time("pmvpn.certificate.expires_at", "Expires at", base.UTC)
+f.settings = ProtoField.bytes("pmvpn.settings", "Settings (sealed)")
+f.source = ProtoField.ipv4("pmvpn.source", "Source")
+f.destination = ProtoField.ipv4("pmvpn.destination", "Destination")
+f.content = ProtoField.bytes("pmvpn.content", "Content (sealed)")
+f.nonce = ProtoField.bytes("pmvpn.nonce", "Nonce")
+
+-- Adds a length-prefixed byte vector at `offset` to `tree`, and returns the next offset.
+local function add_vec(tree, field, buf, offset)
+    local len = buf(offset, 8):le_uint64():tonumber()
+    tree:add(field, buf(offset + 8, len))
+    return offset + 8 + len
+end
+
+local function add_signed(tree, field, buf, offset)
+    offset = add_vec(tree, field, buf, offset)
+    tree:add(f.signature, buf(offset, 64))
+    return offset + 64
+end
+
+-- Adds the optional certificate of a Hello at `offset` to `tree`.
+local function add_certificate(tree, buf, offset)
+    if buf:len() <= offset or buf(offset, 1):uint() == 0 then
+        return
+    end
+    local subtree = tree:add(f.certificate, buf(offset + 1, buf:len() - offset - 1))
+    -- the signed data: public key, address (optional) and expiry
+    local data = offset + 1 + 8
+    subtree:add(f.cert_public_key, buf(data, 32))
+    data = data + 32
+    if buf(data, 1):uint() == 1 then
+        subtree:add(f.cert_address, buf(data + 1, 4))
+        data = data + 4
+    end
+    local secs = buf(data + 1, 8):le_uint64():tonumber()
+    subtree:add(f.cert_expires_at, buf(data + 1, 8), NSTime.new(secs, 0))
+    add_signed(subtree, f.certificate, buf, offset + 1)
+end
+
+function proto.dissector(buf, pinfo, root)
+    if buf:len() < 4 then
+        return 0
+    end
+    pinfo.cols.protocol = proto.name
+    local tree = root:add(proto, buf())
+    local msg_type = buf(0, 4):le_uint()
+    tree:add_le(f.msg_type, buf(0, 4))
+    pinfo.cols.info = message_types[msg_type] or "Unknown"
+
+    local offset = 4
+    if msg_type == 0 then
+        tree:add(f.key_hint, buf(offset, 32))
+        offset = add_signed(tree, f.seed, buf, offset + 32)
+        add_certificate(tree, buf, offset)
+    elseif msg_type == 1 then
+        offset = add_signed(tree, f.seed, buf, offset)
+        add_vec(tree, f.settings, buf, offset)
+    elseif msg_type == 3 then
+        tree:add(f.source, buf(offset, 4))
+        tree:add(f.destination, buf(offset + 4, 4))
+        local len = buf(offset + 8, 8):le_uint64():tonumber()
+        tree:add(f.content, buf(offset + 16, len))
+        if len >= 12 then
+            tree:add(f.nonce, buf(offset + 16 + len - 12, 12))
+        end
+    end
+    return buf:len()
+end
+
+DissectorTable.get("udp.port"):add(31415, proto)